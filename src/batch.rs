@@ -1,49 +1,64 @@
 use std::{
     collections::HashMap,
+    ops::Deref,
     sync::{atomic::Ordering, Arc},
 };
 
-use bytes::{Bytes, BytesMut};
-use log::error;
+use bytes::Bytes;
 use parking_lot::Mutex;
-use prost::{decode_length_delimiter, encode_length_delimiter};
 
 use crate::{
-    data::log_record::{LogRecord, LogRecordKey, LogRecordType},
-    db::Engine,
+    data::log_record::{LogRecord, LogRecordType},
+    db::{Db, Engine, NON_BATCH_COMMIT_ID, NON_BATCH_ID},
     error::{Errors, Result},
     options::WriteBatchOptions,
 };
 
-const TXN_FIN_PREFIX: &[u8] = "txn_fin_prefix".as_bytes();
-pub(crate) const NON_TXN_PREFIX: &[u8] = "non_txn".as_bytes();
+/// batch borrowing an engine
+pub type WriteBatch<'a> = GenericWriteBatch<&'a Engine>;
+/// batch owning a shared engine, it is `'static` and can be moved to other threads
+pub type OwnedWriteBatch = GenericWriteBatch<Arc<Engine>>;
 
-pub struct WriteBatch<'a> {
-    engine: &'a Engine,
+pub struct GenericWriteBatch<E: Deref<Target = Engine>> {
+    engine: E,
     options: WriteBatchOptions,
     pending_batch: Arc<Mutex<HashMap<Vec<u8>, LogRecord>>>,
 }
 
 impl Engine {
-    pub fn write_batch(&self, options: &WriteBatchOptions) -> Result<WriteBatch> {
-        Ok(WriteBatch {
-            engine: self,
+    pub fn write_batch(&self, options: &WriteBatchOptions) -> Result<WriteBatch<'_>> {
+        GenericWriteBatch::new(self, options)
+    }
+}
+
+impl Db {
+    pub fn write_batch(&self, options: &WriteBatchOptions) -> Result<OwnedWriteBatch> {
+        GenericWriteBatch::new(self.engine(), options)
+    }
+}
+
+impl<E: Deref<Target = Engine>> GenericWriteBatch<E> {
+    fn new(engine: E, options: &WriteBatchOptions) -> Result<Self> {
+        engine.check_writable()?;
+        Ok(Self {
+            engine,
             options: options.clone(),
             pending_batch: Arc::new(Mutex::new(HashMap::new())),
         })
     }
-}
 
-impl WriteBatch<'_> {
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         if key.is_empty() {
             return Err(Errors::EmptyKey);
         }
+        self.engine.check_size(key, value)?;
 
         let record = LogRecord {
             key: key.into(),
             value: value.into(),
             record_type: LogRecordType::Normal,
+            batch_id: NON_BATCH_ID,
+            seq_id: NON_BATCH_COMMIT_ID,
         };
 
         let mut lock_guard = self.pending_batch.lock();
@@ -56,6 +71,7 @@ impl WriteBatch<'_> {
         if key.is_empty() {
             return Err(Errors::EmptyKey);
         }
+        self.engine.check_size(key, &[])?;
 
         let has_key = match self.engine.get(Bytes::copy_from_slice(key)) {
             Ok(_) => Ok(true),
@@ -75,6 +91,8 @@ impl WriteBatch<'_> {
                 key: key.into(),
                 value: Default::default(),
                 record_type: LogRecordType::Deleted,
+                batch_id: NON_BATCH_ID,
+                seq_id: NON_BATCH_COMMIT_ID,
             })
             .record_type
             == LogRecordType::Normal
@@ -87,6 +105,8 @@ impl WriteBatch<'_> {
                         key: key.into(),
                         value: Default::default(),
                         record_type: LogRecordType::Deleted,
+                        batch_id: NON_BATCH_ID,
+                        seq_id: NON_BATCH_COMMIT_ID,
                     },
                 );
             }
@@ -105,42 +125,65 @@ impl WriteBatch<'_> {
         }
 
         let seq_id = self.engine.batch_commit_id.fetch_add(1, Ordering::SeqCst);
-        let prefix = &self.engine.batch_prefix;
+        let batch_id = self.engine.batch_id;
         let _commit_lock = self.engine.batch_commit_lock.lock();
+        let _key_locks = self
+            .engine
+            .lock_keys(batch.keys().map(|key| key.as_slice()));
+        let _index_lock = self.engine.index_lock.read();
 
         let record_pos = batch
             .values()
             .try_fold(HashMap::new(), |mut prev, record| {
                 let original_key = &record.key;
                 let record = LogRecord {
-                    key: log_record_key_with_sequence(&record.key, prefix, seq_id)?,
+                    key: record.key.clone(),
                     value: record.value.clone(),
                     record_type: record.record_type,
+                    batch_id,
+                    seq_id,
                 };
+                let record = self.engine.separate_value(original_key, record)?;
                 let pos = self.engine.append_log_record(&record)?;
-                prev.insert(pos, original_key);
+                prev.insert(pos, (original_key, record.record_type));
                 Ok(prev)
             })?;
 
         self.engine.append_log_record(&LogRecord {
-            key: log_record_key_with_sequence(TXN_FIN_PREFIX, prefix, seq_id)?,
+            key: Default::default(),
             value: Default::default(),
             record_type: LogRecordType::BatchCommit,
+            batch_id,
+            seq_id,
         })?;
 
+        // the batch is in files once its commit record is written, a failed sync only
+        // makes its durability unknown, so index still follows files before it returns
+        let synced = match self.options.sync_on_write {
+            true => self.engine.sync(),
+            false => Ok(()),
+        };
+
         // update index
 
         record_pos
             .into_iter()
-            .try_for_each(|(pos, key)| -> Result<()> {
-                match self.engine.indexer.put(key.clone(), pos) {
-                    true => Ok(()),
-                    false => Err(Errors::FailToUpdateIndex),
+            .try_for_each(|(pos, (key, record_type))| -> Result<()> {
+                match record_type {
+                    LogRecordType::Deleted => {
+                        // the key may have been deleted by other non batch actions
+                        self.engine.indexer.delete(key.clone());
+                        Ok(())
+                    }
+                    _ => match self.engine.indexer.put(key.clone(), pos) {
+                        true => Ok(()),
+                        false => Err(Errors::FailToUpdateIndex),
+                    },
                 }
             })?;
 
         batch.clear();
-        Ok(())
+        synced
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
@@ -149,7 +192,9 @@ impl WriteBatch<'_> {
             match not_commit.record_type {
                 LogRecordType::Normal => Ok(not_commit.value.clone()),
                 LogRecordType::Deleted => Err(Errors::KeyNotFound),
-                LogRecordType::BatchCommit => unreachable!(),
+                LogRecordType::BatchCommit
+                | LogRecordType::BlobPointer
+                | LogRecordType::ChunkList => unreachable!(),
             }
         } else {
             self.engine
@@ -159,46 +204,13 @@ impl WriteBatch<'_> {
     }
 }
 
-pub(crate) fn log_record_key_with_sequence(
-    key: &[u8],
-    prefix: &[u8],
-    seq_id: usize,
-) -> Result<Vec<u8>> {
-    let mut buffer = BytesMut::new();
-    encode_length_delimiter(prefix.len(), &mut buffer).map_err(|e| {
-        error!("encode batch record failed: {}", e);
-        Errors::EncodingError
-    })?;
-    buffer.extend_from_slice(prefix);
-    encode_length_delimiter(seq_id, &mut buffer).map_err(|e| {
-        error!("encode batch record failed: {}", e);
-        Errors::EncodingError
-    })?;
-    buffer.extend_from_slice(key);
-    Ok(buffer.into())
-}
-
-pub(crate) fn log_record_key_parse(key: &[u8]) -> Result<LogRecordKey> {
-    let mut buffer: BytesMut = key.into();
-    let pos = decode_length_delimiter(&mut buffer).map_err(|e| {
-        error!("decode log record with commit id failed: {}", e);
-        Errors::DecodingError
-    })?;
-    let prefix = buffer.split_to(pos);
-
-    let seq_id = decode_length_delimiter(&mut buffer).map_err(|e| {
-        error!("decode log record with commit id failed: {}", e);
-        Errors::DecodingError
-    })?;
-
-    Ok(LogRecordKey {
-        prefix: prefix.into(),
-        seq_id,
-        key: buffer.into(),
-    })
-}
-
 #[cfg(test)]
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::needless_return,
+    clippy::unnecessary_to_owned,
+    clippy::useless_conversion
+)]
 mod tests {
 
     use tempfile::Builder;
@@ -211,14 +223,16 @@ mod tests {
     use super::*;
 
     fn new_engine() -> (Engine, Options) {
-        let mut opts = Options::default();
-        opts.dir_path = Builder::new()
-            .prefix("bitcast-rs")
-            .tempdir()
-            .unwrap()
-            .path()
-            .to_path_buf();
-        opts.datafile_size = 64 * 1024 * 1024;
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
 
         return (
             Engine::open(opts.clone()).expect("failed to open engine"),
@@ -305,6 +319,8 @@ mod tests {
                             key: get_test_key(x).into(),
                             value: get_test_value(x).into(),
                             record_type: LogRecordType::Normal,
+                            batch_id: NON_BATCH_ID,
+                            seq_id: NON_BATCH_COMMIT_ID,
                         },
                     )
                 })
@@ -372,6 +388,8 @@ mod tests {
                             key: get_test_key(x).into(),
                             value: get_test_value(x).into(),
                             record_type: LogRecordType::Normal,
+                            batch_id: NON_BATCH_ID,
+                            seq_id: NON_BATCH_COMMIT_ID,
                         },
                     )
                 })
@@ -484,6 +502,8 @@ mod tests {
                             key: get_test_key(x).into(),
                             value: get_test_value(x).into(),
                             record_type: LogRecordType::Normal,
+                            batch_id: NON_BATCH_ID,
+                            seq_id: NON_BATCH_COMMIT_ID,
                         },
                     )
                 })
@@ -619,6 +639,8 @@ mod tests {
                             key: get_test_key(x).into(),
                             value: get_test_value(x).into(),
                             record_type: LogRecordType::Normal,
+                            batch_id: NON_BATCH_ID,
+                            seq_id: NON_BATCH_COMMIT_ID,
                         },
                     )
                 })
@@ -903,23 +925,4 @@ mod tests {
             Err(Errors::KeyNotFound)
         );
     }
-
-    #[test]
-    fn test_log_record_key_with_sequence() {
-        let serialized_key = log_record_key_with_sequence(
-            &get_test_key(101).to_vec(),
-            &get_test_key(201).to_vec(),
-            89,
-        )
-        .expect("serialization failed");
-
-        assert_eq!(
-            log_record_key_parse(&serialized_key),
-            Ok(LogRecordKey {
-                prefix: get_test_key(201).into(),
-                seq_id: 89,
-                key: get_test_key(101).into(),
-            })
-        );
-    }
 }
@@ -0,0 +1,451 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::Path,
+};
+
+use bytes::{Bytes, BytesMut};
+use log::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    data::{
+        data_file::{generate_blobfile_name, DataFile},
+        file_header::FILE_HEADER_SIZE,
+        log_record::{
+            decode_chunks, encode_chunks, log_record_max_size, BlobChunk, BlobPos, LogRecord,
+            LogRecordPos, LogRecordType,
+        },
+    },
+    db::{check_file_header, Engine, NON_BATCH_COMMIT_ID, NON_BATCH_ID},
+    error::{Errors, Result},
+    fio::io_manager::sync_dir,
+    manifest::{ManifestEdit, ManifestState},
+    options::{Options, ReadOptions},
+};
+
+/// readers which may follow blob pointers, a merged blob file is removed
+/// only when no reader is left, as a reader may hold a pointer to it
+#[derive(Default)]
+pub(crate) struct BlobReaders {
+    count: usize,     // readers in progress, including live iterators and value readers
+    merged: Vec<u32>, // merged blob files waiting for readers to finish
+}
+
+/// BlobPin keeps merged blob files on disk until it is dropped
+pub(crate) struct BlobPin<'a> {
+    engine: &'a Engine,
+}
+
+impl<'a> BlobPin<'a> {
+    pub(crate) fn new(engine: &'a Engine) -> Self {
+        engine.pin_blobs();
+        Self { engine }
+    }
+}
+
+impl Drop for BlobPin<'_> {
+    fn drop(&mut self) {
+        self.engine.unpin_blobs();
+    }
+}
+
+/// open blob files in @directory_path live in manifest @state, all of them
+/// are sealed, values written after open go to a new blob file
+pub(crate) fn load_blob_files(
+    directory_path: &Path,
+    state: &ManifestState,
+    options: &Options,
+    db_id: &mut Option<Uuid>,
+) -> Result<HashMap<u32, DataFile>> {
+    state
+        .blob_files
+        .keys()
+        .map(|&fid| {
+            if !Path::new(&generate_blobfile_name(directory_path, fid)).exists() {
+                error!("blob file {} in manifest is missing", fid);
+                return Err(Errors::DataFileNotFound);
+            }
+            let mut blob_file = DataFile::new_blob(directory_path, fid, &options.io_type)?;
+            blob_file.set_record_options(options);
+            // a blob file without header is empty, it is removed by next merge
+            check_file_header(&mut blob_file, db_id)?;
+            Ok((fid, blob_file))
+        })
+        .collect()
+}
+
+impl Engine {
+    /// write value of @record to a blob file when it reaches `min_blob_size`,
+    /// or when the record can't fit in a datafile, the returned record keeps
+    /// a pointer to the blob instead of the value
+    pub(crate) fn separate_value(&self, key: &[u8], record: LogRecord) -> Result<LogRecord> {
+        let record_size = (FILE_HEADER_SIZE + log_record_max_size()) as u64
+            + (record.key.len() + record.value.len()) as u64;
+        let oversize = record_size > self.options.datafile_size;
+        if record.record_type != LogRecordType::Normal
+            || !oversize
+                && (self.options.min_blob_size == 0
+                    || record.value.len() < self.options.min_blob_size)
+        {
+            return Ok(record);
+        }
+
+        let blob_pos = self.append_blob(key, &record.value)?;
+        Ok(LogRecord {
+            key: record.key,
+            value: blob_pos.encode(),
+            record_type: LogRecordType::BlobPointer,
+            batch_id: record.batch_id,
+            seq_id: record.seq_id,
+        })
+    }
+
+    /// value of a record read from a datafile, returns `None` for a tombstone
+    pub(crate) fn resolve_value(
+        &self,
+        record: LogRecord,
+        options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
+        match record.record_type {
+            LogRecordType::Deleted => Ok(None),
+            LogRecordType::BlobPointer => {
+                let blob_pos =
+                    BlobPos::decode(&record.value).ok_or(Errors::DatabaseFileCorrupted)?;
+                self.read_blob(&blob_pos, options).map(Some)
+            }
+            LogRecordType::ChunkList => {
+                let chunks = decode_chunks(&record.value).ok_or(Errors::DatabaseFileCorrupted)?;
+                let mut value = BytesMut::new();
+                for chunk in chunks {
+                    value.extend_from_slice(&self.read_blob(&chunk.pos, options)?);
+                }
+                Ok(Some(value.freeze()))
+            }
+            _ => Ok(Some(record.value.into())),
+        }
+    }
+
+    /// value of a blob pointer or chunk list @record in range [@offset, @offset + @len)
+    pub(crate) fn resolve_range(&self, record: LogRecord, offset: u64, len: u64) -> Result<Bytes> {
+        match record.record_type {
+            LogRecordType::BlobPointer => {
+                let blob_pos =
+                    BlobPos::decode(&record.value).ok_or(Errors::DatabaseFileCorrupted)?;
+                self.read_blob_range(&blob_pos, offset, len)
+                    .map(Bytes::from)
+            }
+            LogRecordType::ChunkList => {
+                let chunks = decode_chunks(&record.value).ok_or(Errors::DatabaseFileCorrupted)?;
+                let end = offset.saturating_add(len);
+                let mut value = BytesMut::new();
+                let mut chunk_start = 0;
+                for chunk in chunks {
+                    let chunk_end = chunk_start + chunk.size;
+                    if chunk_end > offset && chunk_start < end {
+                        let from = offset.max(chunk_start) - chunk_start;
+                        let to = end.min(chunk_end) - chunk_start;
+                        value.extend_from_slice(&self.read_blob_range(
+                            &chunk.pos,
+                            from,
+                            to - from,
+                        )?);
+                    }
+                    chunk_start = chunk_end;
+                }
+                Ok(value.freeze())
+            }
+            _ => Err(Errors::DatabaseFileCorrupted),
+        }
+    }
+
+    pub(crate) fn read_blob(&self, pos: &BlobPos, options: &ReadOptions) -> Result<Bytes> {
+        self.with_blob_file(pos.file_id, |blob_file| {
+            Ok(blob_file
+                .read_log_record_with(pos.offset, options)?
+                .record
+                .value
+                .into())
+        })
+    }
+
+    fn read_blob_range(&self, pos: &BlobPos, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.with_blob_file(pos.file_id, |blob_file| {
+            Ok(blob_file.read_value_range(pos.offset, offset, len)?.1)
+        })
+    }
+
+    /// register a reader of blob pointers, it must be done before the index is read,
+    /// every `pin_blobs` is paired with an `unpin_blobs`
+    pub(crate) fn pin_blobs(&self) {
+        self.blob_readers.lock().count += 1;
+    }
+
+    /// unregister a reader, merged blob files are removed when it is the last one
+    pub(crate) fn unpin_blobs(&self) {
+        let merged = {
+            let mut readers = self.blob_readers.lock();
+            readers.count -= 1;
+            if readers.count > 0 {
+                return;
+            }
+            // dropped with the lock held, so a merge never picks them again
+            let merged = std::mem::take(&mut readers.merged);
+            let mut old_blob_files = self.old_blob_files.write();
+            merged.iter().for_each(|fid| {
+                old_blob_files.remove(fid);
+            });
+            merged
+        };
+        // a file left by a closed engine is an orphan, it is removed on open
+        if self.check_open().is_err() {
+            return;
+        }
+        for fid in merged {
+            if let Err(e) = self.remove_blob_file(fid) {
+                error!("remove merged blob file {} failed: {}", fid, e);
+            }
+        }
+    }
+
+    /// delete blob file of @fid from disk, it is no longer in sealed blob files
+    fn remove_blob_file(&self, fid: u32) -> Result<()> {
+        let blob_file_name = generate_blobfile_name(self.options.dir_path.as_path(), fid);
+        fs::remove_file(&blob_file_name).map_err(|e| {
+            error!("remove blob file {} failed: {:?}", blob_file_name, e);
+            Errors::FailToRemoveDataFile(e.into())
+        })?;
+        sync_dir(self.options.dir_path.as_path(), &self.options.io_type)?;
+        info!("blob file {} merged", blob_file_name);
+        Ok(())
+    }
+
+    /// run @f with active or sealed blob file of @file_id
+    fn with_blob_file<T>(&self, file_id: u32, f: impl FnOnce(&DataFile) -> Result<T>) -> Result<T> {
+        if let Some(blob_file) = self
+            .active_blob_file
+            .read()
+            .as_ref()
+            .filter(|blob_file| blob_file.file_id() == file_id)
+        {
+            return f(blob_file);
+        }
+
+        let old_blob_files = self.old_blob_files.read();
+        f(old_blob_files
+            .get(&file_id)
+            .ok_or(Errors::DataFileNotFound)?)
+    }
+
+    /// append @value to active blob file, a new blob file is created when there is none
+    /// or the current one reaches `datafile_size`
+    pub(crate) fn append_blob(&self, key: &[u8], value: &[u8]) -> Result<BlobPos> {
+        let encode_log = LogRecord {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            record_type: LogRecordType::Normal,
+            batch_id: NON_BATCH_ID,
+            seq_id: NON_BATCH_COMMIT_ID,
+        }
+        .encode_with(
+            self.options.compression.codec(),
+            self.options.encryption.as_deref(),
+            &self.db_id,
+            self.options.checksum,
+        )?;
+
+        let mut active_blob_file = self.active_blob_file.write();
+        self.check_writable()?;
+        // a blob larger than a file takes a new file of its own
+        let is_full = active_blob_file.as_ref().is_none_or(|f| {
+            f.get_offset() > f.records_offset()
+                && f.get_offset() + encode_log.len() as u64 > self.options.datafile_size
+        });
+        if is_full {
+            if let Some(blob_file) = active_blob_file.as_mut() {
+                self.poison_on_sync_failure(blob_file.sync())?;
+            }
+            let mut old_blob_files = self.old_blob_files.write();
+            let fid = old_blob_files
+                .keys()
+                .copied()
+                .chain(active_blob_file.as_ref().map(|f| f.file_id()))
+                .max()
+                .map_or(0, |fid| fid + 1);
+            let blob_file = self.poison_on_sync_failure(DataFile::new_active_blob(
+                self.options.dir_path.as_path(),
+                fid,
+                &self.options,
+                self.db_id,
+            ))?;
+            let mut edits = Vec::new();
+            if let Some(prev_blob_file) = active_blob_file.as_ref() {
+                edits.push(ManifestEdit::SealBlob(prev_blob_file.file_id()));
+            }
+            edits.push(ManifestEdit::NewBlob(fid));
+            self.poison_on_sync_failure(self.manifest.lock().log(&edits))?;
+            if let Some(prev_blob_file) = active_blob_file.replace(blob_file) {
+                old_blob_files.insert(prev_blob_file.file_id(), prev_blob_file);
+            }
+        }
+
+        let blob_file = active_blob_file.as_mut().expect("active blob file exists");
+        let offset = blob_file.get_offset();
+        if self.options.sync_in_write {
+            self.poison_on_sync_failure(blob_file.write_and_sync(&encode_log))?;
+        } else {
+            blob_file.write(&encode_log)?;
+        }
+
+        Ok(BlobPos {
+            file_id: blob_file.file_id(),
+            offset,
+        })
+    }
+
+    /// sync active blob file
+    pub(crate) fn sync_blob(&self) -> Result<()> {
+        match self.active_blob_file.write().as_mut() {
+            Some(blob_file) => self.poison_on_sync_failure(blob_file.sync()),
+            None => Ok(()),
+        }
+    }
+
+    /// reclaim space of blob values which are overwritten or deleted,
+    /// live values of sealed blob files are copied to the active blob file,
+    /// then the sealed files are removed
+    pub fn merge_blobs(&self) -> Result<()> {
+        let _merge_lock = self.blob_merge_lock.lock();
+        self.check_writable()?;
+
+        // merged files waiting for readers have no live blob
+        let readers = self.blob_readers.lock();
+        let mut fids = self
+            .old_blob_files
+            .read()
+            .keys()
+            .copied()
+            .filter(|fid| !readers.merged.contains(fid))
+            .collect::<Vec<_>>();
+        drop(readers);
+        fids.sort();
+        for fid in fids {
+            // moved blobs grouped by key, with the record referring them
+            let mut moved: HashMap<Vec<u8>, (LogRecordPos, LogRecord, HashMap<BlobPos, BlobPos>)> =
+                HashMap::new();
+            let mut offset = self
+                .old_blob_files
+                .read()
+                .get(&fid)
+                .ok_or(Errors::DataFileNotFound)?
+                .records_offset();
+            loop {
+                let blob = {
+                    let old_blob_files = self.old_blob_files.read();
+                    let blob_file = old_blob_files.get(&fid).ok_or(Errors::DataFileNotFound)?;
+                    match blob_file.read_log_record(offset) {
+                        Ok(blob) => blob,
+                        Err(Errors::ReadEOF) => break,
+                        Err(e) => return Err(e),
+                    }
+                };
+                let blob_pos = BlobPos {
+                    file_id: fid,
+                    offset,
+                };
+                offset += blob.size;
+
+                let key = blob.record.key;
+                let (_, record, moves) = match moved.entry(key.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match self.indexer.get(key.clone()) {
+                        Some(record_pos) => {
+                            let record = self.read_record_at(&record_pos)?;
+                            entry.insert((record_pos, record, HashMap::new()))
+                        }
+                        None => continue,
+                    },
+                };
+                if blob_refs(record)?.contains(&blob_pos) {
+                    moves.insert(blob_pos, self.append_blob(&key, &blob.record.value)?);
+                }
+            }
+
+            // no write to a key lands between the check and the update, so a moved
+            // record is appended only when it is still the latest one of its key
+            let index_lock = self.index_lock.write();
+            for (key, (record_pos, record, moves)) in moved {
+                if moves.is_empty() || self.indexer.get(key.clone()) != Some(record_pos) {
+                    continue;
+                }
+                let record = LogRecord {
+                    key: key.to_vec(),
+                    value: relocate_blobs(&record, &moves)?,
+                    record_type: record.record_type,
+                    batch_id: NON_BATCH_ID,
+                    seq_id: NON_BATCH_COMMIT_ID,
+                };
+                let new_pos = self.append_log_record(&record)?;
+                if !self.indexer.put(key, new_pos) {
+                    return Err(Errors::FailToUpdateIndex);
+                }
+            }
+            drop(index_lock);
+
+            // pointers to moved values must be durable before the old blob file is gone
+            self.sync()?;
+            // a crash before the file is removed leaves an orphan, it is removed on open
+            self.poison_on_sync_failure(
+                self.manifest.lock().log(&[ManifestEdit::RemoveBlob(fid)]),
+            )?;
+            // a reader which read the index before the update may still follow
+            // a pointer to the file, the last reader removes it then
+            let mut readers = self.blob_readers.lock();
+            if readers.count > 0 {
+                readers.merged.push(fid);
+                continue;
+            }
+            self.old_blob_files.write().remove(&fid);
+            drop(readers);
+            self.remove_blob_file(fid)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// blobs referred by a datafile record
+fn blob_refs(record: &LogRecord) -> Result<Vec<BlobPos>> {
+    match record.record_type {
+        LogRecordType::BlobPointer => BlobPos::decode(&record.value)
+            .map(|pos| vec![pos])
+            .ok_or(Errors::DatabaseFileCorrupted),
+        LogRecordType::ChunkList => decode_chunks(&record.value)
+            .map(|chunks| chunks.into_iter().map(|chunk| chunk.pos).collect())
+            .ok_or(Errors::DatabaseFileCorrupted),
+        _ => Ok(vec![]),
+    }
+}
+
+/// value of @record with blob positions replaced according to @moves
+fn relocate_blobs(record: &LogRecord, moves: &HashMap<BlobPos, BlobPos>) -> Result<Vec<u8>> {
+    let relocate = |pos: BlobPos| moves.get(&pos).copied().unwrap_or(pos);
+    match record.record_type {
+        LogRecordType::BlobPointer => BlobPos::decode(&record.value)
+            .map(|pos| relocate(pos).encode())
+            .ok_or(Errors::DatabaseFileCorrupted),
+        LogRecordType::ChunkList => decode_chunks(&record.value)
+            .map(|chunks| {
+                let chunks = chunks
+                    .into_iter()
+                    .map(|chunk| BlobChunk {
+                        pos: relocate(chunk.pos),
+                        size: chunk.size,
+                    })
+                    .collect::<Vec<_>>();
+                encode_chunks(&chunks)
+            })
+            .ok_or(Errors::DatabaseFileCorrupted),
+        _ => Ok(record.value.clone()),
+    }
+}
@@ -0,0 +1,445 @@
+use std::{collections::BTreeMap, fs, path::Path, sync::Arc};
+
+use bytes::Bytes;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tempfile::Builder;
+
+use crate::{
+    db::Engine,
+    error::{Errors, Result},
+    fio::{
+        fault_io::{FaultIO, FaultInjector},
+        IOManager,
+    },
+    options::{IOType, Options, WriteBatchOptions},
+    utils::rand_kv::get_test_key,
+};
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+const KEY_SPACE: usize = 64;
+const OPS_PER_ROUND: usize = 400;
+
+enum Fault {
+    None,
+    FailWrite(usize),
+    ShortWrite(usize),
+    FailSync(usize),
+}
+
+fn random_value(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(0..200);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn engine_state(engine: &Engine) -> Model {
+    engine
+        .list_keys()
+        .expect("keys must be listed")
+        .into_iter()
+        .map(|key| {
+            let value = engine
+                .get(key.clone())
+                .expect("listed key must be readable");
+            (key.to_vec(), value.to_vec())
+        })
+        .collect()
+}
+
+/// run a random put/delete/batch workload against a fault injecting engine,
+/// crash it and check the recovered state against the acknowledged model
+fn crash_round(seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+
+    let fault = match rng.gen_range(0..4) {
+        0 => Fault::None,
+        1 => Fault::FailWrite(rng.gen_range(1..OPS_PER_ROUND)),
+        2 => Fault::ShortWrite(rng.gen_range(1..OPS_PER_ROUND)),
+        _ => Fault::FailSync(rng.gen_range(1..OPS_PER_ROUND / 4)),
+    };
+    match fault {
+        Fault::None => {}
+        Fault::FailWrite(n) => injector.fail_write_at(n),
+        Fault::ShortWrite(n) => injector.short_write_at(n),
+        Fault::FailSync(n) => injector.fail_sync_at(n),
+    }
+
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 4 * 1024,
+        max_key_size: 1024,
+        sync_in_write: rng.gen_bool(0.2),
+        io_type: IOType::FaultInjection(injector.clone()),
+        write_buffer_size: [0, 256, 1024][rng.gen_range(0..3)],
+        min_blob_size: [0, 100][rng.gen_range(0..2)],
+        ..Default::default()
+    };
+
+    // history of every state of engine, recovery must end in one of the
+    // states at or after the last acknowledged sync
+    let mut history: Vec<Model> = vec![Model::new()];
+    let mut synced = 0;
+    let mut poisoned = None;
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for _ in 0..OPS_PER_ROUND {
+        let mut model = history.last().unwrap().clone();
+        let mut durable = opts.sync_in_write;
+
+        let res: Result<()> = match rng.gen_range(0..20) {
+            0..=9 => {
+                let key = get_test_key(rng.gen_range(0..KEY_SPACE)).to_vec();
+                let value = random_value(&mut rng);
+                model.insert(key.clone(), value.clone());
+                engine.put(key.into(), value.into())
+            }
+            10..=13 => {
+                let key = get_test_key(rng.gen_range(0..KEY_SPACE)).to_vec();
+                model.remove(&key);
+                engine.delete(key.into())
+            }
+            14..=16 => {
+                let batch_options = WriteBatchOptions {
+                    sync_on_write: rng.gen_bool(0.5),
+                    ..Default::default()
+                };
+                durable |= batch_options.sync_on_write;
+                engine.write_batch(&batch_options).and_then(|mut batch| {
+                    (0..rng.gen_range(1..8))
+                        .try_for_each(|_| {
+                            let key = get_test_key(rng.gen_range(0..KEY_SPACE)).to_vec();
+                            if rng.gen_bool(0.7) {
+                                let value = random_value(&mut rng);
+                                model.insert(key.clone(), value.clone());
+                                batch.put(&key, &value)
+                            } else {
+                                model.remove(&key);
+                                batch.delete(&key)
+                            }
+                        })
+                        .and_then(|_| batch.commit())
+                })
+            }
+            17..=18 => {
+                durable = true;
+                engine.sync()
+            }
+            _ => engine.merge_blobs(),
+        };
+
+        // a failed sync poisons the engine, every write and sync after it fails
+        if let Some(poisoned) = &poisoned {
+            assert_eq!(res.as_ref().err(), Some(poisoned), "seed {}", seed);
+        }
+        if let Err(e @ Errors::FailToSyncDataFile(_)) = &res {
+            poisoned.get_or_insert_with(|| e.clone());
+        }
+
+        // workload goes on after a failure, the failed operation is either
+        // applied entirely or not at all, and it is never acknowledged as durable
+        if res.is_err() {
+            let live = engine_state(&engine);
+            assert!(
+                &live == history.last().unwrap() || live == model,
+                "seed {}: failed operation is partially applied",
+                seed
+            );
+            history.push(live);
+            continue;
+        }
+
+        history.push(model);
+        if durable {
+            synced = history.len() - 1;
+        }
+    }
+
+    assert_eq!(
+        &engine_state(&engine),
+        history.last().unwrap(),
+        "seed {}: live engine diverges from model",
+        seed
+    );
+
+    injector.crash();
+    drop(engine);
+
+    let opts = Options {
+        io_type: IOType::StandardFIO,
+        ..opts
+    };
+    let engine = Engine::open(opts).expect("failed to reopen engine after crash");
+    let recovered = engine_state(&engine);
+    assert!(
+        history[synced..].contains(&recovered),
+        "seed {}: recovered state is not a state acknowledged after last sync (synced {}, history {})",
+        seed,
+        synced,
+        history.len()
+    );
+}
+
+#[test]
+fn test_crash_recovery_random_workload() {
+    (0..64).for_each(crash_round);
+}
+
+#[test]
+fn test_crash_drops_unsynced_data() {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        io_type: IOType::FaultInjection(injector.clone()),
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.put("synced".into(), "value".into()), Ok(()));
+    assert_eq!(engine.sync(), Ok(()));
+    assert_eq!(engine.put("unsynced".into(), "value".into()), Ok(()));
+    injector.crash();
+    assert!(engine.put("crashed".into(), "value".into()).is_err());
+    // failed close is returned and database is left dirty
+    assert!(engine.close().is_err());
+
+    let engine = Engine::open(Options {
+        io_type: IOType::StandardFIO,
+        ..opts
+    })
+    .expect("failed to reopen engine after crash");
+    assert!(!engine.last_shutdown_clean());
+    assert_eq!(engine.get("synced".into()), Ok(Bytes::from("value")));
+    assert_eq!(engine.get("unsynced".into()), Err(Errors::KeyNotFound));
+    assert_eq!(engine.get("crashed".into()), Err(Errors::KeyNotFound));
+}
+
+#[test]
+fn test_crash_keeps_rotated_datafiles() {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 4 * 1024,
+        max_key_size: 1024,
+        sync_in_write: true,
+        io_type: IOType::FaultInjection(injector.clone()),
+        ..Default::default()
+    };
+
+    // synced records of files created by rotation survive because directory is synced
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..200 {
+        assert_eq!(engine.put(get_test_key(i), get_test_key(i)), Ok(()));
+    }
+    injector.crash();
+    drop(engine);
+
+    let engine = Engine::open(Options {
+        io_type: IOType::StandardFIO,
+        ..opts
+    })
+    .expect("failed to reopen engine after crash");
+    for i in 0..200 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_key(i)));
+    }
+}
+
+#[test]
+fn test_crash_drops_file_without_dir_sync() {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+    let synced = dir.path().join("synced");
+    let unsynced = dir.path().join("unsynced");
+
+    let mut file = FaultIO::new(synced.clone(), injector.clone()).unwrap();
+    assert_eq!(file.write_and_sync(b"value"), Ok(5));
+    assert_eq!(injector.sync_dir(dir.path()), Ok(()));
+    let mut file = FaultIO::new(unsynced.clone(), injector.clone()).unwrap();
+    assert_eq!(file.write_and_sync(b"value"), Ok(5));
+    injector.crash();
+
+    assert_eq!(fs::read(synced).unwrap(), b"value");
+    assert!(!unsynced.exists());
+}
+
+#[test]
+fn test_crash_injected_failures() {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        io_type: IOType::FaultInjection(injector.clone()),
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    injector.fail_write_at(injector.writes() + 1);
+    let err = engine.put("key".into(), "value".into()).unwrap_err();
+    assert!(matches!(
+        &err,
+        Errors::FailToWriteToDataFile(e) if e.file_id == Some(0) && e.offset.is_some()
+    ));
+    assert_eq!(err.io_kind(), Some(std::io::ErrorKind::Other));
+    assert_eq!(engine.get("key".into()), Err(Errors::KeyNotFound));
+    assert_eq!(engine.put("key".into(), "value".into()), Ok(()));
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from("value")));
+
+    // a sync which succeeds after a failed one proves nothing, so every write
+    // and sync fails until reopen, reads go on
+    injector.fail_sync_at(injector.syncs() + 1);
+    let err = engine.sync().unwrap_err();
+    assert!(err.is_fatal() && !err.is_retryable());
+    assert_eq!(engine.sync(), Err(err.clone()));
+    assert_eq!(engine.put("key".into(), "other".into()), Err(err.clone()));
+    assert_eq!(engine.delete("key".into()), Err(err.clone()));
+    assert_eq!(engine.merge_blobs(), Err(err.clone()));
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from("value")));
+    // a poisoned database is left dirty
+    assert_eq!(engine.close(), Err(err));
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(!engine.last_shutdown_clean());
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from("value")));
+
+    injector.short_write_at(injector.writes() + 1);
+    assert!(engine.put("short".into(), "value".into()).is_err());
+    assert_eq!(engine.get("short".into()), Err(Errors::KeyNotFound));
+
+    // records after a short write overwrite its bytes, so they are readable
+    // before and after reopen
+    for i in 0..10 {
+        assert_eq!(engine.put(get_test_key(i), get_test_key(i)), Ok(()));
+    }
+    for i in 0..10 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_key(i)));
+    }
+    injector.short_write_at(injector.writes() + 1);
+    assert!(engine
+        .put("short".into(), Bytes::from(vec![1u8; 4096]))
+        .is_err());
+    assert_eq!(engine.put("last".into(), "value".into()), Ok(()));
+    assert_eq!(engine.close(), Ok(()));
+
+    let engine = Engine::open(Options {
+        io_type: IOType::StandardFIO,
+        ..opts
+    })
+    .expect("failed to reopen engine");
+    assert!(engine.last_shutdown_clean());
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from("value")));
+    assert_eq!(engine.get("short".into()), Err(Errors::KeyNotFound));
+    assert_eq!(engine.get("last".into()), Ok(Bytes::from("value")));
+    for i in 0..10 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_key(i)));
+    }
+}
+
+#[test]
+fn test_crash_manifest_and_marker_failures() {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let injector = Arc::new(FaultInjector::new());
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 4 * 1024,
+        max_key_size: 64,
+        max_value_size: 3000,
+        io_type: IOType::FaultInjection(injector.clone()),
+        ..Default::default()
+    };
+
+    // rotation syncs the sealed file, header of the new one, then the manifest
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        engine.put("key".into(), Bytes::from(vec![1u8; 3000])),
+        Ok(())
+    );
+    injector.fail_sync_at(injector.syncs() + 3);
+    let err = engine
+        .put("other".into(), Bytes::from(vec![2u8; 3000]))
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        Errors::FailToSyncDataFile(e) if e.file_id.is_none()
+    ));
+    assert_eq!(engine.sync(), Err(err));
+    injector.crash();
+    drop(engine);
+
+    // the new file never reaches the manifest, it is removed as an orphan
+    let injector = Arc::new(FaultInjector::new());
+    let opts = Options {
+        io_type: IOType::FaultInjection(injector.clone()),
+        ..opts
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine after crash");
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from(vec![1u8; 3000])));
+    assert_eq!(engine.get("other".into()), Err(Errors::KeyNotFound));
+    assert!(!dir.path().join("000000001.bcdata").exists());
+
+    // a marker whose sync fails is lost by a crash, the database is dirty
+    injector.fail_sync_at(injector.syncs() + 2);
+    assert!(matches!(engine.close(), Err(Errors::FailToSyncDataFile(_))));
+    injector.crash();
+    let engine = Engine::open(Options {
+        io_type: IOType::StandardFIO,
+        ..opts
+    })
+    .expect("failed to reopen engine after crash");
+    assert!(!engine.last_shutdown_clean());
+    assert_eq!(engine.get("key".into()), Ok(Bytes::from(vec![1u8; 3000])));
+}
+
+fn corrupt_and_reopen(flip_offset: impl Fn(&[u8]) -> u64) {
+    let dir = Builder::new().prefix("bitcast-rs-crash").tempdir().unwrap();
+    let opts = Options {
+        dir_path: dir.path().to_path_buf(),
+        datafile_size: 4 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..100 {
+        assert_eq!(
+            engine.put(
+                get_test_key(i),
+                Bytes::from(format!("crash-value-{:09}", i))
+            ),
+            Ok(())
+        );
+    }
+    drop(engine);
+
+    let path = Path::new(&opts.dir_path).join("000000000.bcdata");
+    let content = fs::read(&path).unwrap();
+    FaultInjector::flip_bit(&path, flip_offset(&content), 0x10);
+
+    assert!(Engine::open(opts).is_err_and(|e| e.is_corruption()));
+}
+
+#[test]
+fn test_crash_bit_flip_in_value_detected() {
+    corrupt_and_reopen(|content| {
+        let needle = b"crash-value-000000003";
+        content
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .expect("value not found in datafile") as u64
+            + 3
+    });
+}
+
+#[test]
+fn test_crash_bit_flip_in_crc_detected() {
+    // the last non-zero byte of a datafile belongs to the last record
+    corrupt_and_reopen(|content| {
+        content
+            .iter()
+            .rposition(|b| *b != 0)
+            .expect("datafile is empty") as u64
+    });
+}
@@ -0,0 +1,81 @@
+use xxhash_rust::xxh3::Xxh3;
+
+/// ChecksumType algorithm of record checksums, it is recorded in the header
+/// of each file, so files written with different algorithms stay readable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumType {
+    // crc32 (IEEE), the only algorithm of files before format version 3
+    #[default]
+    Crc32,
+    // crc32c (Castagnoli), hardware accelerated on most cpus
+    Crc32c,
+    // low 32 bits of xxh3 64
+    Xxh3,
+}
+
+impl ChecksumType {
+    /// id stored in file header
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            ChecksumType::Crc32 => 0,
+            ChecksumType::Crc32c => 1,
+            ChecksumType::Xxh3 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ChecksumType::Crc32),
+            1 => Some(ChecksumType::Crc32c),
+            2 => Some(ChecksumType::Xxh3),
+            _ => None,
+        }
+    }
+
+    /// checksum of @parts as if they are concatenated
+    pub(crate) fn checksum(&self, parts: &[&[u8]]) -> u32 {
+        match self {
+            ChecksumType::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finalize()
+            }
+            ChecksumType::Crc32c => parts
+                .iter()
+                .fold(0, |crc, part| crc32c::crc32c_append(crc, part)),
+            ChecksumType::Xxh3 => {
+                let mut hasher = Xxh3::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.digest() as u32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(ChecksumType::Crc32.checksum(&[b"123456789"]), 0xcbf43926);
+        assert_eq!(ChecksumType::Crc32c.checksum(&[b"123456789"]), 0xe3069283);
+        assert_eq!(
+            ChecksumType::Xxh3.checksum(&[b"123456789"]),
+            xxhash_rust::xxh3::xxh3_64(b"123456789") as u32
+        );
+
+        for checksum in [
+            ChecksumType::Crc32,
+            ChecksumType::Crc32c,
+            ChecksumType::Xxh3,
+        ] {
+            assert_eq!(ChecksumType::from_id(checksum.id()), Some(checksum));
+            assert_eq!(
+                checksum.checksum(&[b"1234", b"", b"56789"]),
+                checksum.checksum(&[b"123456789"])
+            );
+        }
+        assert_eq!(ChecksumType::from_id(3), None);
+    }
+}
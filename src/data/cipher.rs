@@ -0,0 +1,95 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use log::error;
+
+use crate::error::{Errors, Result};
+
+/// size of nonce stored in front of each sealed message, it is large enough
+/// for random nonces never to repeat under one key
+const NONCE_SIZE: usize = 24;
+
+/// Cipher encrypts and authenticates keys and values of records
+pub trait Cipher: Send + Sync {
+    /// seal @plaintext, @aad is authenticated but not encrypted
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+
+    /// open a message sealed by `encrypt`, fails when it is modified
+    /// or sealed with another key
+    fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// XChaCha20-Poly1305 cipher, each message is sealed with a random 192-bit nonce
+pub struct XChaCha20Poly1305Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl XChaCha20Poly1305Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        XChaCha20Poly1305Cipher {
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+}
+
+impl Cipher for XChaCha20Poly1305Cipher {
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|e| {
+                error!("encrypt record failed: {:?}", e);
+                Errors::EncryptionFailed
+            })?;
+        let mut message = nonce.to_vec();
+        message.extend_from_slice(&sealed);
+        Ok(message)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < NONCE_SIZE {
+            return Err(Errors::DecryptionFailed);
+        }
+        let (nonce, sealed) = ciphertext.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .map_err(|_| Errors::DecryptionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xchacha20_poly1305_cipher() {
+        let cipher = XChaCha20Poly1305Cipher::new(&[7; 32]);
+        let sealed = cipher.encrypt(b"secret", b"aad").unwrap();
+        assert_ne!(&sealed[NONCE_SIZE..NONCE_SIZE + 6], b"secret");
+        assert_eq!(cipher.decrypt(&sealed, b"aad"), Ok(b"secret".to_vec()));
+        // nonce is random, same plaintext is sealed differently
+        assert_ne!(cipher.encrypt(b"secret", b"aad").unwrap(), sealed);
+
+        assert_eq!(
+            cipher.decrypt(&sealed, b"other"),
+            Err(Errors::DecryptionFailed)
+        );
+        let other = XChaCha20Poly1305Cipher::new(&[8; 32]);
+        assert_eq!(
+            other.decrypt(&sealed, b"aad"),
+            Err(Errors::DecryptionFailed)
+        );
+        assert_eq!(
+            cipher.decrypt(&sealed[..4], b"aad"),
+            Err(Errors::DecryptionFailed)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use log::error;
+
+use crate::error::{Errors, Result};
+
+/// id of values stored without compression
+pub(crate) const NO_CODEC_ID: u8 = 0;
+/// id of the built-in snappy codec
+pub(crate) const SNAPPY_CODEC_ID: u8 = 1;
+/// max codec id which fits in a record type flag
+pub const MAX_CODEC_ID: u8 = 7;
+
+/// Codec compresses values of records, its id is stored in each record,
+/// so files written with different codecs stay readable
+pub trait Codec: Send + Sync {
+    /// id stored in records, 1 is reserved by the built-in snappy codec,
+    /// custom codecs use 2 to `MAX_CODEC_ID`
+    fn id(&self) -> u8;
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// size of @data once decompressed, if it is known without decompressing,
+    /// it is checked against max value size before buffer is allocated
+    fn decompressed_len(&self, _data: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+/// pure rust snappy codec
+pub struct SnappyCodec;
+
+pub(crate) static SNAPPY_CODEC: SnappyCodec = SnappyCodec;
+
+impl Codec for SnappyCodec {
+    fn id(&self) -> u8 {
+        SNAPPY_CODEC_ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        snap::raw::Encoder::new().compress_vec(data).map_err(|e| {
+            error!("snappy compress failed: {:?}", e);
+            Errors::CodecFailure(e.to_string())
+        })
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        snap::raw::Decoder::new().decompress_vec(data).map_err(|e| {
+            error!("snappy decompress failed: {:?}", e);
+            Errors::CodecFailure(e.to_string())
+        })
+    }
+
+    fn decompressed_len(&self, data: &[u8]) -> Option<usize> {
+        snap::raw::decompress_len(data).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snappy_codec() {
+        let data = "{\"name\":\"bitcask\",\"tags\":[\"kv\",\"kv\",\"kv\"]}".repeat(20);
+        let compressed = SNAPPY_CODEC.compress(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(SNAPPY_CODEC.decompress(&compressed), Ok(data.into_bytes()));
+        assert!(matches!(
+            SNAPPY_CODEC.decompress(&[0xff, 0xff, 0xff]),
+            Err(Errors::CodecFailure(_))
+        ));
+    }
+}
@@ -4,13 +4,24 @@ use std::sync::Arc;
 use bytes::{Buf, BytesMut};
 use log::error;
 use parking_lot::RwLock;
-use prost::{decode_length_delimiter, length_delimiter_len, DecodeError};
+use prost::{decode_length_delimiter, encoding::decode_varint, DecodeError};
+use uuid::Uuid;
 
-use crate::data::log_record::{LogRecord, LogRecordType, LOG_CRC_SIZE, LOG_TYPE_FLAG_SIZE};
-use crate::fio::io_manager::new_io_manager;
+use crate::data::checksum::ChecksumType;
+use crate::data::cipher::Cipher;
+use crate::data::codec::NO_CODEC_ID;
+use crate::data::file_header::{FileHeader, FILE_HEADER_SIZE, FORMAT_VERSION};
+use crate::data::log_record::{
+    decode_v1_key, key_aad, value_aad, LogRecord, LogRecordType, LOG_CRC_SIZE, RECORD_CODEC_MASK,
+    RECORD_CODEC_SHIFT, RECORD_ENCRYPTED_FLAG, RECORD_TYPE_MASK,
+};
+use crate::fio::io_manager::{new_io_manager, sync_dir};
 use crate::fio::{self};
 
 use crate::error::{Errors, Result};
+use crate::options::{
+    Compression, IOType, Options, ReadOptions, DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_VALUE_SIZE,
+};
 
 use super::log_record::{log_record_max_size, ReadLogRecord};
 
@@ -18,26 +29,181 @@ pub const DATAFILE_SEPARATOR: &str = ".";
 
 pub const DATAFILE_NAME_SUFFIX: &str = ".bcdata";
 
+pub const BLOBFILE_NAME_SUFFIX: &str = ".bcblob";
+
+/// bytes read at a time when searching for next valid record
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// stored key and value may exceed size limits by overhead of cipher
+const SEALED_SIZE_SLACK: usize = 1024;
+
 /// datafile for each bitcast file
 pub(crate) struct DataFile {
     /// current file id
     file_id: Arc<RwLock<u32>>,
-    /// current write cursor offset
+    /// current write cursor offset, include bytes still in write buffer
     write_offset: Arc<RwLock<u64>>,
     /// io manager for file manuplation
     io_manager: Box<dyn fio::IOManager>,
+    /// records written but not flushed to io manager yet
+    write_buffer: Vec<u8>,
+    /// max size of write buffer, 0 means writes go to io manager directly
+    write_buffer_size: usize,
+    /// a write failed, part of it may be left after write offset, write cursor of
+    /// io manager is moved back before next write and the bytes are erased by sync
+    torn: bool,
+    /// provides codecs to decompress values
+    compression: Compression,
+    /// decrypts keys and values of encrypted records
+    cipher: Option<Arc<dyn Cipher>>,
+    /// database the file belongs to, from file header, encrypted records are bound to it
+    db_id: Uuid,
+    /// format version of records, from file header
+    format_version: u32,
+    /// algorithm of record checksums, from file header
+    checksum: ChecksumType,
+    /// whether it is a blob file, keys of v1 blob files have no batch envelope
+    is_blob: bool,
+    /// records with larger keys or values are treated as corrupted
+    max_key_size: usize,
+    max_value_size: usize,
 }
 
 impl DataFile {
-    pub fn new(file_dir: &Path, fid: u32) -> Result<Self> {
-        let io_manager = new_io_manager(PathBuf::from(generate_datafile_name(file_dir, fid)))?;
+    pub fn new(file_dir: &Path, fid: u32, io_type: &IOType) -> Result<Self> {
+        DataFile::open(generate_datafile_name(file_dir, fid), fid, io_type)
+    }
+
+    /// open a blob file, it has the same record format as a datafile,
+    /// but only keeps values separated from datafiles
+    pub fn new_blob(file_dir: &Path, fid: u32, io_type: &IOType) -> Result<Self> {
+        let mut blob_file = DataFile::open(generate_blobfile_name(file_dir, fid), fid, io_type)?;
+        blob_file.is_blob = true;
+        Ok(blob_file)
+    }
+
+    /// create a new blob file of database @db_id which accepts writes
+    pub fn new_active_blob(
+        file_dir: &Path,
+        fid: u32,
+        options: &Options,
+        db_id: Uuid,
+    ) -> Result<Self> {
+        let mut data_file = DataFile::new_blob(file_dir, fid, &options.io_type)?;
+        data_file.write_header(&FileHeader::new(db_id, options.checksum))?;
+        data_file.sync()?;
+        sync_dir(file_dir, &options.io_type)?;
+        data_file.set_record_options(options);
+        Ok(data_file)
+    }
+
+    fn open(file_name: String, fid: u32, io_type: &IOType) -> Result<Self> {
+        let io_manager =
+            new_io_manager(PathBuf::from(file_name), io_type).map_err(|e| e.at(fid, None))?;
         Ok(DataFile {
             file_id: Arc::new(RwLock::new(fid)),
             write_offset: Arc::new(RwLock::new(0)),
             io_manager,
+            write_buffer: Vec::new(),
+            write_buffer_size: 0,
+            torn: false,
+            compression: Compression::None,
+            cipher: None,
+            db_id: Uuid::nil(),
+            format_version: FORMAT_VERSION,
+            checksum: ChecksumType::default(),
+            is_blob: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
         })
     }
 
+    /// create a datafile of database @db_id which accepts writes, with `datafile_size`
+    /// bytes preallocated and a file header, a file left by a failed creation is never
+    /// live, so its header is written again
+    pub fn new_active(file_dir: &Path, fid: u32, options: &Options, db_id: Uuid) -> Result<Self> {
+        let mut data_file = DataFile::new(file_dir, fid, &options.io_type)?;
+        if options.preallocate_datafile {
+            data_file
+                .io_manager
+                .preallocate(options.datafile_size)
+                .map_err(|e| e.at(fid, None))?;
+        }
+        data_file.write_header(&FileHeader::new(db_id, options.checksum))?;
+        data_file.sync()?;
+        // new file is lost on power loss until its directory entry is synced
+        sync_dir(file_dir, &options.io_type)?;
+        data_file.set_write_buffer_size(options.write_buffer_size);
+        data_file.set_record_options(options);
+        Ok(data_file)
+    }
+
+    /// write header to an empty file, records are appended after it
+    pub fn write_header(&mut self, header: &FileHeader) -> Result<()> {
+        self.set_offset(0)?;
+        self.write(&header.encode())?;
+        self.set_header(header);
+        Ok(())
+    }
+
+    /// read header of file, returns `None` when it is never written
+    pub fn read_header(&self) -> Result<Option<FileHeader>> {
+        let mut buf = [0u8; FILE_HEADER_SIZE];
+        self.read_at(&mut buf, 0)?;
+        FileHeader::decode(&buf)
+    }
+
+    /// records are decoded in format version and checksum algorithm of @header
+    pub fn set_header(&mut self, header: &FileHeader) {
+        self.db_id = header.db_id;
+        self.format_version = header.version;
+        self.checksum = header.checksum;
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    pub fn checksum(&self) -> ChecksumType {
+        self.checksum
+    }
+
+    /// offset of the first record, right after file header
+    pub fn records_offset(&self) -> u64 {
+        FileHeader::size(self.format_version) as u64
+    }
+
+    /// buffer writes up to @size bytes before handing them to io manager
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.write_buffer_size = size;
+        self.write_buffer.reserve(size);
+    }
+
+    /// values compressed by a custom codec are readable only when it is set here
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// encrypted records are readable only with the cipher which sealed them
+    pub fn set_cipher(&mut self, cipher: Option<Arc<dyn Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    /// set compression, cipher and size limits of records from @options
+    pub fn set_record_options(&mut self, options: &Options) {
+        self.set_compression(options.compression.clone());
+        self.set_cipher(options.encryption.clone());
+        self.max_key_size = options.max_key_size;
+        self.max_value_size = options.max_value_size;
+    }
+
+    /// records are bounded only by size of file, for files which may be written
+    /// before size limits are recorded in OPTIONS file
+    pub fn clear_size_limits(&mut self) {
+        self.max_key_size = usize::MAX;
+        self.max_value_size = usize::MAX;
+    }
+
     pub fn get_offset(&self) -> u64 {
         *self.write_offset.read()
     }
@@ -46,105 +212,666 @@ impl DataFile {
         *self.file_id.read()
     }
 
-    pub fn sync(&self) -> Result<()> {
-        self.io_manager.sync()
+    /// flush buffered writes and sync them to consistant file
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        if std::mem::take(&mut self.torn) {
+            if let Err(e) = self.erase_tail(self.get_offset()) {
+                self.torn = true;
+                return Err(e);
+            }
+        }
+        self.io_manager
+            .sync()
+            .map_err(|e| e.at(self.file_id(), None))
+    }
+
+    /// hand buffered writes to io manager without syncing them
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.write_buffer.is_empty() {
+            let offset = self.get_offset() - self.write_buffer.len() as u64;
+            self.rewind()?;
+            let res = self.io_manager.write(&self.write_buffer);
+            self.torn |= res.is_err();
+            res.map_err(|e| e.at(self.file_id(), Some(offset)))?;
+            self.write_buffer.clear();
+        }
+        Ok(())
     }
 
     pub fn write(&mut self, record: &[u8]) -> Result<usize> {
-        let n_bytes = self.io_manager.write(record)?;
+        if self.write_buffer.len() + record.len() > self.write_buffer_size {
+            self.flush()?;
+        }
+
+        let n_bytes = if record.len() < self.write_buffer_size {
+            self.write_buffer.extend_from_slice(record);
+            record.len()
+        } else {
+            self.rewind()?;
+            let res = self.io_manager.write(record);
+            self.torn |= res.is_err();
+            res.map_err(|e| e.at(self.file_id(), Some(self.get_offset())))?
+        };
         *self.write_offset.write() += n_bytes as u64;
 
         Ok(n_bytes)
     }
 
+    pub fn write_and_sync(&mut self, record: &[u8]) -> Result<usize> {
+        self.rewind()?;
+        let n_bytes = if self.write_buffer.is_empty() {
+            let res = self.io_manager.write_and_sync(record);
+            self.torn |= res.is_err();
+            res.map_err(|e| e.at(self.file_id(), Some(self.get_offset())))?
+        } else {
+            self.write_buffer.extend_from_slice(record);
+            let offset = self.get_offset() - (self.write_buffer.len() - record.len()) as u64;
+            let res = self
+                .io_manager
+                .write_and_sync(&self.write_buffer)
+                .map_err(|e| e.at(self.file_id(), Some(offset)));
+            if res.is_err() {
+                self.torn = true;
+                self.write_buffer
+                    .truncate(self.write_buffer.len() - record.len());
+            }
+            res?;
+            self.write_buffer.clear();
+            record.len()
+        };
+        *self.write_offset.write() += n_bytes as u64;
+
+        Ok(n_bytes)
+    }
+
+    /// move write cursor of io manager back to the end of flushed records after
+    /// a failed write, so bytes it left are overwritten instead of skipped
+    fn rewind(&mut self) -> Result<()> {
+        if self.torn {
+            let offset = self.get_offset() - self.write_buffer.len() as u64;
+            self.io_manager
+                .set_write_offset(offset)
+                .map_err(|e| e.at(self.file_id(), Some(offset)))?;
+        }
+        Ok(())
+    }
+
+    /// read from @offset, bytes which are still in write buffer are served from it
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let read = |buf: &mut [u8]| {
+            self.io_manager
+                .read(buf, offset)
+                .map_err(|e| e.at(self.file_id(), Some(offset)))
+        };
+        if self.write_buffer.is_empty() {
+            return read(buf);
+        }
+
+        let flushed_end = self.get_offset() - self.write_buffer.len() as u64;
+        let mut n_bytes = 0;
+        if offset < flushed_end {
+            n_bytes = read(buf)?;
+            if offset + (n_bytes as u64) < flushed_end {
+                return Ok(n_bytes);
+            }
+            n_bytes = (flushed_end - offset) as usize;
+        }
+
+        let buffer_offset = (offset + n_bytes as u64 - flushed_end) as usize;
+        if n_bytes < buf.len() && buffer_offset < self.write_buffer.len() {
+            let len = (buf.len() - n_bytes).min(self.write_buffer.len() - buffer_offset);
+            buf[n_bytes..n_bytes + len]
+                .copy_from_slice(&self.write_buffer[buffer_offset..buffer_offset + len]);
+            n_bytes += len;
+        }
+        Ok(n_bytes)
+    }
+
+    fn read_batch(&self, reads: &mut [(&mut [u8], u64)]) -> Result<Vec<usize>> {
+        if self.write_buffer.is_empty() {
+            return self
+                .io_manager
+                .read_batch(reads)
+                .map_err(|e| e.at(self.file_id(), None));
+        }
+        reads
+            .iter_mut()
+            .map(|(buf, offset)| self.read_at(buf, *offset))
+            .collect()
+    }
+
     pub fn read_log_record(&self, offset: u64) -> Result<ReadLogRecord> {
+        self.read_log_record_with(offset, &ReadOptions::default())
+    }
+
+    /// read record at @offset, checksum is not verified when @options disables it
+    pub fn read_log_record_with(
+        &self,
+        offset: u64,
+        options: &ReadOptions,
+    ) -> Result<ReadLogRecord> {
         let mut header_buf = BytesMut::zeroed(log_record_max_size());
-        self.io_manager.read(&mut header_buf, offset)?;
+        self.read_at(&mut header_buf, offset)?;
+        let header = self.decode_header(header_buf, offset, None)?;
 
-        let record_type = header_buf.get_u8();
+        let mut kv_buffer = BytesMut::zeroed(header.body_size());
+        if self.read_at(&mut kv_buffer, offset + header.header_size as u64)? < kv_buffer.len() {
+            error!("record exceeds end of file, datafile may be corrupted");
+            return Err(self.corrupted(offset));
+        }
 
-        let map_err = |e: DecodeError| {
-            error!("failed to decode key size from log record header: {:?}", e);
-            Errors::DatabaseFileCorrupted
-        };
-        let key_size = decode_length_delimiter(&mut header_buf).map_err(map_err)?;
-        let value_size = decode_length_delimiter(&mut header_buf).map_err(map_err)?;
+        self.decode_record_body(&header, offset, kv_buffer, options.verify_checksum)
+    }
 
-        if key_size == 0 && value_size == 0 {
-            return Err(Errors::ReadEOF);
+    /// read at most @len bytes of value of record at @offset, starting from @value_offset,
+    /// only header and the requested range are read, so crc is not checked,
+    /// unless value is compressed or encrypted and the whole record has to be decoded
+    pub fn read_value_range(
+        &self,
+        offset: u64,
+        value_offset: u64,
+        len: u64,
+    ) -> Result<(LogRecordType, Vec<u8>)> {
+        let mut header_buf = BytesMut::zeroed(log_record_max_size());
+        self.read_at(&mut header_buf, offset)?;
+        let header = self.decode_header(header_buf, offset, None)?;
+
+        if header.codec_id() != NO_CODEC_ID || header.is_encrypted() {
+            let record = self.read_log_record(offset)?.record;
+            let value_size = record.value.len() as u64;
+            let start = value_offset.min(value_size);
+            let end = start + len.min(value_size - start);
+            return Ok((
+                record.record_type,
+                record.value[start as usize..end as usize].to_vec(),
+            ));
         }
 
-        let actual_header_size =
-            LOG_TYPE_FLAG_SIZE + length_delimiter_len(key_size) + length_delimiter_len(value_size);
+        let value_size = header.value_size as u64;
+        let start = value_offset.min(value_size);
+        let mut value = vec![0u8; len.min(value_size - start) as usize];
+        let value_pos = offset + (header.header_size + header.key_size) as u64 + start;
+        if self.read_at(&mut value, value_pos)? < value.len() {
+            error!("value range exceeds end of file, datafile may be corrupted");
+            return Err(self.corrupted(offset));
+        }
 
-        let mut kv_buffer = BytesMut::zeroed(key_size + value_size + LOG_CRC_SIZE);
-        self.io_manager
-            .read(&mut kv_buffer, offset + actual_header_size as u64)?;
+        Ok((header.record_type(), value))
+    }
 
-        let record = ReadLogRecord {
-            record: LogRecord {
-                key: kv_buffer.get(..key_size).unwrap().to_vec(),
-                value: kv_buffer
-                    .get(key_size..(key_size + value_size))
-                    .unwrap()
-                    .to_vec(),
-                record_type: LogRecordType::from_u8(record_type),
-            },
-            size: (actual_header_size + key_size + value_size + LOG_CRC_SIZE) as u64,
+    /// error of corrupted record at @offset
+    fn corrupted(&self, offset: u64) -> Errors {
+        Errors::CorruptedRecord {
+            file_id: self.file_id(),
+            offset,
+        }
+    }
+
+    /// decode header of record at @offset, sizes in it are validated against limits
+    /// and @file_size before body is allocated, as a broken header may claim a huge
+    /// record, size of file is taken when it is not given and the record is large
+    fn decode_header(
+        &self,
+        header_buf: BytesMut,
+        offset: u64,
+        file_size: Option<u64>,
+    ) -> Result<RecordHeader> {
+        let header =
+            decode_record_header(header_buf, self.format_version).map_err(|e| match e {
+                Errors::DatabaseFileCorrupted => self.corrupted(offset),
+                e => e,
+            })?;
+
+        if exceeds_size_limits(&header, self.max_key_size, self.max_value_size) {
+            error!(
+                "record at offset {} of datafile {} exceeds size limits, key: {}, value: {}",
+                offset,
+                self.file_id(),
+                header.key_size,
+                header.value_size
+            );
+            return Err(self.corrupted(offset));
+        }
+        // small records are checked by the length read, which saves a stat call
+        let size = (header.header_size + header.body_size()) as u64;
+        let file_size = match file_size {
+            Some(file_size) => file_size,
+            None if header.body_size() > SCAN_CHUNK_SIZE => self.size()?,
+            None => u64::MAX,
         };
+        if offset + size > file_size {
+            error!(
+                "record at offset {} of datafile {} exceeds end of file, size: {}",
+                offset,
+                self.file_id(),
+                size
+            );
+            return Err(self.corrupted(offset));
+        }
+        Ok(header)
+    }
+
+    fn decode_record_body(
+        &self,
+        header: &RecordHeader,
+        offset: u64,
+        kv_buffer: BytesMut,
+        verify_checksum: bool,
+    ) -> Result<ReadLogRecord> {
+        let (key_size, value_size) = (header.key_size, header.value_size);
+        let key = kv_buffer.get(..key_size).unwrap();
+        let value = kv_buffer.get(key_size..(key_size + value_size)).unwrap();
         let crc = kv_buffer
             .get((key_size + value_size)..kv_buffer.len())
             .ok_or_else(|| {
                 error!("can't read record crc, maybe datafile is corrupted");
-                Errors::DatabaseFileCorrupted
+                self.corrupted(offset)
             })?;
 
+        // crc covers key and value as they are stored, so a record which passes
+        // crc but fails authentication is sealed by another key
         let expect_crc = u32::from_le_bytes(crc.try_into().unwrap());
-        let actual_crc = record.record.get_crc();
+        let actual_crc = match verify_checksum {
+            true => self.checksum.checksum(&[&header.raw, key, value]),
+            false => expect_crc,
+        };
         if expect_crc != actual_crc {
             error!(
                 "expect crc: {:?}, got: {:?}, database file may be corrupted",
                 expect_crc, actual_crc
             );
-            Err(Errors::DatabaseFileCorrupted)
-        } else {
-            Ok(record)
+            return Err(Errors::ChecksumMismatch {
+                file_id: self.file_id(),
+                offset,
+                expected: expect_crc,
+                actual: actual_crc,
+            });
+        }
+
+        let (key, value) = match (header.is_encrypted(), &self.cipher) {
+            (true, Some(cipher)) => {
+                let key_aad = key_aad(
+                    header.record_type,
+                    header.batch_id,
+                    header.seq_id,
+                    &self.db_id,
+                );
+                (
+                    cipher.decrypt(key, &key_aad)?,
+                    cipher.decrypt(value, &value_aad(key_aad, key))?,
+                )
+            }
+            (true, None) => return Err(Errors::CipherNotConfigured),
+            // a plain record can't be authenticated, it may be injected by anyone
+            // who can write the file
+            (false, Some(_)) => {
+                error!(
+                    "record at offset {} of datafile {} is not encrypted",
+                    offset,
+                    self.file_id()
+                );
+                return Err(Errors::UnencryptedRecord);
+            }
+            (false, None) => (key.to_vec(), value.to_vec()),
+        };
+        let value = match header.codec_id() {
+            NO_CODEC_ID => value,
+            codec_id => {
+                let codec = self
+                    .compression
+                    .codec_by_id(codec_id)
+                    .ok_or(Errors::UnknownCodec(codec_id))?;
+                if codec
+                    .decompressed_len(&value)
+                    .is_some_and(|len| len > self.max_value_size)
+                {
+                    error!("decompressed value exceeds max value size");
+                    return Err(self.corrupted(offset));
+                }
+                codec.decompress(&value)?
+            }
+        };
+        // legacy and v1 datafiles keep batch id and sequence in an envelope of key
+        let (batch_id, seq_id, key) = match self.format_version <= 1 && !self.is_blob {
+            true => decode_v1_key(&key)?,
+            false => (header.batch_id, header.seq_id, key),
+        };
+        Ok(ReadLogRecord {
+            record: LogRecord {
+                key,
+                value,
+                record_type: header.record_type(),
+                batch_id,
+                seq_id,
+            },
+            size: (header.header_size + header.body_size()) as u64,
+        })
+    }
+
+    /// size of file, including preallocated space and buffered writes
+    pub fn size(&self) -> Result<u64> {
+        let size = self
+            .io_manager
+            .size()
+            .map_err(|e| e.at(self.file_id(), None))?;
+        Ok(size.max(self.get_offset()))
+    }
+
+    /// offset of the first valid record from @from, it is used to skip corrupted bytes
+    /// whose record boundaries are lost, returns `None` when no record is found before
+    /// end of file or a chunk of zeros, which is preallocated space after the last record
+    pub fn find_next_record(&self, from: u64) -> Result<Option<u64>> {
+        let file_size = self.size()?;
+        let mut chunk = vec![0u8; SCAN_CHUNK_SIZE];
+        let (mut start, mut zeros) = (from, 0);
+        while start < file_size {
+            let n_bytes = self.read_at(&mut chunk, start)?;
+            let bytes = &chunk[..n_bytes];
+            let leading_zeros = bytes.iter().take_while(|b| **b == 0).count();
+            if leading_zeros == n_bytes || zeros + leading_zeros >= SCAN_CHUNK_SIZE {
+                break;
+            }
+            // only bytes which may be a type flag are tried as record start
+            let candidates = bytes
+                .iter()
+                .enumerate()
+                .filter(|(_, flag)| LogRecordType::from_u8(*flag & RECORD_TYPE_MASK).is_some());
+            for (i, _) in candidates {
+                let offset = start + i as u64;
+                match self.check_record(offset, file_size, &bytes[i..]) {
+                    Ok(RecordCheck::Valid(_)) => return Ok(Some(offset)),
+                    Ok(RecordCheck::Corrupted(_)) | Err(Errors::ReadEOF) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            zeros = bytes.iter().rev().take_while(|b| **b == 0).count();
+            start += n_bytes as u64;
+        }
+        Ok(None)
+    }
+
+    /// key and value sizes of the first record which exceeds size limits of @options,
+    /// i.e. which is read as corrupted with them, the file must be read without limits,
+    /// scan stops at the end of valid records
+    pub fn find_oversize_record(&self, options: &Options) -> Result<Option<(usize, usize)>> {
+        let file_size = self.size()?;
+        let mut offset = self.records_offset();
+        while offset < file_size {
+            let mut header_buf = BytesMut::zeroed(log_record_max_size());
+            self.read_at(&mut header_buf, offset)?;
+            let header = match self.decode_header(header_buf, offset, Some(file_size)) {
+                Ok(header) => header,
+                Err(Errors::ReadEOF) => break,
+                Err(e) if e.is_corruption() => break,
+                Err(e) => return Err(e),
+            };
+            if exceeds_size_limits(&header, options.max_key_size, options.max_value_size) {
+                return Ok(Some((header.key_size, header.value_size)));
+            }
+            offset += (header.header_size + header.body_size()) as u64;
         }
+        Ok(None)
     }
 
-    pub(crate) fn set_offset(&mut self, offset: u64) {
-        *self.write_offset.write() = offset
+    /// verify checksum of record at @offset of a file of @file_size without decoding
+    /// key and value, a record whose header is broken, which exceeds size limits or
+    /// end of file takes the rest of file, as where next record starts is unknown
+    pub fn verify_record(&self, offset: u64, file_size: u64) -> Result<RecordCheck> {
+        self.check_record(offset, file_size, &[])
+    }
+
+    /// verify record at @offset, @prefetched holds bytes from @offset which are read
+    /// already, the record is read from file only when it is not in them
+    fn check_record(&self, offset: u64, file_size: u64, prefetched: &[u8]) -> Result<RecordCheck> {
+        if offset >= file_size {
+            return Err(Errors::ReadEOF);
+        }
+
+        let header_buf = match prefetched.get(..log_record_max_size()) {
+            Some(bytes) => BytesMut::from(bytes),
+            None => {
+                let mut header_buf = BytesMut::zeroed(log_record_max_size());
+                self.read_at(&mut header_buf, offset)?;
+                header_buf
+            }
+        };
+        let header = match self.decode_header(header_buf, offset, Some(file_size)) {
+            Ok(header) => header,
+            Err(e) if e.is_corruption() => return Ok(RecordCheck::Corrupted(file_size - offset)),
+            Err(e) => return Err(e),
+        };
+        let size = (header.header_size + header.body_size()) as u64;
+
+        let mut kv_buffer = Vec::new();
+        let body = match prefetched.get(header.header_size..size as usize) {
+            Some(body) => body,
+            None => {
+                kv_buffer.resize(header.body_size(), 0);
+                self.read_at(&mut kv_buffer, offset + header.header_size as u64)?;
+                &kv_buffer
+            }
+        };
+        let (kv, crc) = body.split_at(header.key_size + header.value_size);
+        let expect_crc = u32::from_le_bytes(crc.try_into().unwrap());
+        match self.checksum.checksum(&[&header.raw, kv]) == expect_crc {
+            true => Ok(RecordCheck::Valid(size)),
+            false => Ok(RecordCheck::Corrupted(size)),
+        }
+    }
+
+    /// read records at each of @offsets, headers and bodies are read in two batches
+    /// so that io manager can submit them together
+    pub fn read_log_records(
+        &self,
+        offsets: &[u64],
+        options: &ReadOptions,
+    ) -> Result<Vec<ReadLogRecord>> {
+        let mut header_bufs = offsets
+            .iter()
+            .map(|_| BytesMut::zeroed(log_record_max_size()))
+            .collect::<Vec<_>>();
+        self.read_batch(
+            &mut header_bufs
+                .iter_mut()
+                .zip(offsets)
+                .map(|(buf, offset)| (buf.as_mut(), *offset))
+                .collect::<Vec<_>>(),
+        )?;
+        let headers = header_bufs
+            .into_iter()
+            .zip(offsets)
+            .map(|(header_buf, offset)| self.decode_header(header_buf, *offset, None))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut kv_buffers = headers
+            .iter()
+            .map(|header| BytesMut::zeroed(header.body_size()))
+            .collect::<Vec<_>>();
+        let n_bytes = self.read_batch(
+            &mut kv_buffers
+                .iter_mut()
+                .zip(headers.iter().zip(offsets))
+                .map(|(buf, (header, offset))| (buf.as_mut(), offset + header.header_size as u64))
+                .collect::<Vec<_>>(),
+        )?;
+
+        headers
+            .iter()
+            .zip(kv_buffers.into_iter().zip(offsets.iter().zip(n_bytes)))
+            .map(|(header, (kv_buffer, (offset, n_bytes)))| {
+                if n_bytes < kv_buffer.len() {
+                    error!("record exceeds end of file, datafile may be corrupted");
+                    return Err(self.corrupted(*offset));
+                }
+                self.decode_record_body(header, *offset, kv_buffer, options.verify_checksum)
+            })
+            .collect()
+    }
+
+    /// overwrite bytes from @offset up to the first zeroed chunk or end of file with
+    /// zeros, so a torn write at the end of records is never read as records again,
+    /// returns count of bytes erased
+    pub fn erase_tail(&mut self, offset: u64) -> Result<u64> {
+        let mut chunk = vec![0u8; SCAN_CHUNK_SIZE];
+        let (mut start, mut end) = (offset, offset);
+        loop {
+            let n_bytes = self.read_at(&mut chunk, start)?;
+            match chunk[..n_bytes].iter().rposition(|b| *b != 0) {
+                Some(i) => end = start + i as u64 + 1,
+                None => break,
+            }
+            start += n_bytes as u64;
+        }
+
+        if end > offset {
+            self.set_offset(offset)?;
+            chunk.fill(0);
+            let mut pos = offset;
+            while pos < end {
+                let len = (end - pos).min(SCAN_CHUNK_SIZE as u64) as usize;
+                self.write(&chunk[..len])?;
+                pos += len as u64;
+            }
+            self.sync()?;
+            self.set_offset(offset)?;
+        }
+        Ok(end - offset)
+    }
+
+    pub(crate) fn set_offset(&mut self, offset: u64) -> Result<()> {
+        self.io_manager.set_write_offset(offset)?;
+        *self.write_offset.write() = offset;
+        Ok(())
+    }
+}
+
+/// result of verifying a record, with its size on disk
+#[derive(Debug, PartialEq)]
+pub enum RecordCheck {
+    Valid(u64),
+    Corrupted(u64),
+}
+
+/// decoded | type | batch_id | seq_id | key_size | value_size | part of a record,
+/// batch id and sequence are absent in v1 format
+struct RecordHeader {
+    /// type flag, with codec id and encryption bit
+    record_type: u8,
+    log_type: LogRecordType,
+    batch_id: u64,
+    seq_id: usize,
+    key_size: usize,
+    value_size: usize,
+    header_size: usize,
+    /// encoded header, covered by crc
+    raw: BytesMut,
+}
+
+impl RecordHeader {
+    fn record_type(&self) -> LogRecordType {
+        self.log_type
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.record_type & RECORD_ENCRYPTED_FLAG != 0
+    }
+
+    /// id of codec which compressed value
+    fn codec_id(&self) -> u8 {
+        self.record_type >> RECORD_CODEC_SHIFT & RECORD_CODEC_MASK
+    }
+
+    /// size of | key | value | crc | part
+    fn body_size(&self) -> usize {
+        self.key_size + self.value_size + LOG_CRC_SIZE
     }
 }
 
-fn generate_datafile_name(path: &Path, fid: u32) -> String {
+/// whether stored key or value size of @header exceeds @max_key_size or @max_value_size
+fn exceeds_size_limits(header: &RecordHeader, max_key_size: usize, max_value_size: usize) -> bool {
+    header.key_size > max_key_size.saturating_add(SEALED_SIZE_SLACK)
+        || header.value_size > max_value_size.saturating_add(SEALED_SIZE_SLACK)
+}
+
+fn decode_record_header(mut header_buf: BytesMut, version: u32) -> Result<RecordHeader> {
+    let mut buf = &header_buf[..];
+    let record_type = buf.get_u8();
+    // zeroed space after the last record, a v2 record always has a type
+    if version >= 2 && record_type == 0 {
+        return Err(Errors::ReadEOF);
+    }
+
+    let map_err = |e: DecodeError| {
+        error!("failed to decode log record header: {:?}", e);
+        Errors::DatabaseFileCorrupted
+    };
+    let (batch_id, seq_id) = match version {
+        0 | 1 => (0, 0),
+        _ => (
+            decode_varint(&mut buf).map_err(map_err)?,
+            decode_varint(&mut buf).map_err(map_err)? as usize,
+        ),
+    };
+    let key_size = decode_length_delimiter(&mut buf).map_err(map_err)?;
+    let value_size = decode_length_delimiter(&mut buf).map_err(map_err)?;
+
+    if version <= 1 && key_size == 0 && value_size == 0 {
+        return Err(Errors::ReadEOF);
+    }
+
+    let log_type = LogRecordType::from_u8(record_type & RECORD_TYPE_MASK).ok_or_else(|| {
+        error!("unknown log record type flag: {:#04x}", record_type);
+        Errors::DatabaseFileCorrupted
+    })?;
+
+    let header_size = header_buf.len() - buf.len();
+    Ok(RecordHeader {
+        record_type,
+        log_type,
+        batch_id,
+        seq_id,
+        key_size,
+        value_size,
+        header_size,
+        raw: header_buf.split_to(header_size),
+    })
+}
+
+pub(crate) fn generate_datafile_name(path: &Path, fid: u32) -> String {
     let file_name = std::format!("{:09}{}", fid, DATAFILE_NAME_SUFFIX);
     String::from(path.join(file_name).to_str().unwrap())
 }
 
+pub(crate) fn generate_blobfile_name(path: &Path, fid: u32) -> String {
+    let file_name = std::format!("{:09}{}", fid, BLOBFILE_NAME_SUFFIX);
+    String::from(path.join(file_name).to_str().unwrap())
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::data::codec::Codec;
     use tempfile::Builder;
 
     #[test]
     fn test_datafile_new() {
         let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
 
-        let datafile_0 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_0 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_0.is_ok());
         assert_eq!(datafile_0.unwrap().file_id(), 0);
 
-        let datafile_1 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_1 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_1.is_ok());
         assert_eq!(datafile_1.unwrap().file_id(), 1);
 
-        let datafile_2 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_2 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_2.is_ok());
         assert_eq!(datafile_2.unwrap().file_id(), 0);
 
-        let datafile_3 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_3 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_3.is_ok());
         assert_eq!(datafile_3.unwrap().file_id(), 1);
     }
@@ -153,16 +880,16 @@ mod tests {
     fn test_data_file_write() {
         let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
 
-        let datafile_0 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_0 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_0.is_ok());
 
-        let datafile_1 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_1 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_1.is_ok());
 
-        let datafile_2 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_2 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_2.is_ok());
 
-        let datafile_3 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_3 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_3.is_ok());
 
         let mut datafile_0 = datafile_0.unwrap();
@@ -191,7 +918,7 @@ mod tests {
         let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
         let mut offset = 0;
 
-        let datafile = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile.is_ok());
 
         let mut datafile = datafile.unwrap();
@@ -200,6 +927,8 @@ mod tests {
             key: "\0".as_bytes().to_vec(),
             value: Default::default(),
             record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (data, crc1) = (rec1.encode(), rec1.get_crc());
         let size = datafile.write(&data);
@@ -217,6 +946,8 @@ mod tests {
             key: "\0sdaas".as_bytes().to_vec(),
             value: "dasdsadsadea\0dsada\0".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (data, crc2) = (rec2.encode(), rec2.get_crc());
         let size = datafile.write(&data);
@@ -239,6 +970,8 @@ mod tests {
             key: "ssdda\0sdaas".as_bytes().to_vec(),
             value: Default::default(),
             record_type: LogRecordType::Deleted,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (data, crc3) = (rec3.encode(), rec3.get_crc());
         let size = datafile.write(&data);
@@ -268,16 +1001,16 @@ mod tests {
     fn test_file_sync() {
         let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
 
-        let datafile_0 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_0 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_0.is_ok());
 
-        let datafile_1 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_1 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_1.is_ok());
 
-        let datafile_2 = DataFile::new(&tmp_dir.path().to_path_buf(), 0);
+        let datafile_2 = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO);
         assert!(datafile_2.is_ok());
 
-        let datafile_3 = DataFile::new(&tmp_dir.path().to_path_buf(), 1);
+        let datafile_3 = DataFile::new(tmp_dir.path(), 1, &IOType::StandardFIO);
         assert!(datafile_3.is_ok());
 
         let mut datafile_0 = datafile_0.unwrap();
@@ -306,4 +1039,369 @@ mod tests {
         assert!(datafile_2.sync().is_ok());
         assert!(datafile_3.sync().is_ok());
     }
+
+    #[test]
+    fn test_data_file_mixed_codecs() {
+        struct ReverseCodec;
+        impl Codec for ReverseCodec {
+            fn id(&self) -> u8 {
+                5
+            }
+            fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+                Ok(data.iter().rev().take(data.len() - 1).copied().collect())
+            }
+            fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+                let mut value = data.iter().rev().copied().collect::<Vec<_>>();
+                value.push(value[0]);
+                Ok(value)
+            }
+        }
+
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        let record = LogRecord {
+            key: "key".as_bytes().to_vec(),
+            value: "a".repeat(100).into_bytes(),
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let custom = Compression::Custom(Arc::new(ReverseCodec));
+        let mut offsets = vec![];
+        for compression in [Compression::None, Compression::Snappy, custom.clone()] {
+            offsets.push(datafile.get_offset());
+            let data = record
+                .encode_with(compression.codec(), None, &Uuid::nil(), ChecksumType::Crc32)
+                .unwrap();
+            assert!(datafile.write(&data).is_ok());
+        }
+
+        // built-in codecs are always readable, custom ones must be configured
+        datafile.set_compression(Compression::None);
+        assert_eq!(datafile.read_log_record(offsets[0]).unwrap().record, record);
+        assert_eq!(datafile.read_log_record(offsets[1]).unwrap().record, record);
+        assert_eq!(
+            datafile.read_log_record(offsets[2]).err(),
+            Some(Errors::UnknownCodec(5))
+        );
+        datafile.set_compression(custom);
+        assert_eq!(datafile.read_log_record(offsets[2]).unwrap().record, record);
+        assert_eq!(
+            datafile.read_value_range(offsets[1], 10, 5),
+            Ok((LogRecordType::Normal, "aaaaa".as_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_data_file_encrypted_records() {
+        use crate::data::cipher::XChaCha20Poly1305Cipher;
+        use crate::data::log_record::{encode_record, RECORD_ENCRYPTED_FLAG};
+
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        let db_id = Uuid::new_v4();
+        assert!(datafile
+            .write_header(&FileHeader::new(db_id, ChecksumType::Crc32))
+            .is_ok());
+        let cipher = Arc::new(XChaCha20Poly1305Cipher::new(&[3; 32]));
+        datafile.set_cipher(Some(cipher.clone()));
+
+        let record = LogRecord {
+            key: "key".as_bytes().to_vec(),
+            value: "value".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            batch_id: 3,
+            seq_id: 1,
+        };
+        let offset = datafile.get_offset();
+        let sealed = record
+            .encode_with(None, Some(cipher.as_ref()), &db_id, ChecksumType::Crc32)
+            .unwrap();
+        assert!(datafile.write(&sealed).is_ok());
+        assert_eq!(datafile.read_log_record(offset).unwrap().record, record);
+
+        // record sealed for another database
+        let offset = datafile.get_offset();
+        let foreign = record
+            .encode_with(
+                None,
+                Some(cipher.as_ref()),
+                &Uuid::new_v4(),
+                ChecksumType::Crc32,
+            )
+            .unwrap();
+        assert!(datafile.write(&foreign).is_ok());
+        assert_eq!(
+            datafile.read_log_record(offset).err(),
+            Some(Errors::DecryptionFailed)
+        );
+
+        // sealed key and value moved under another header with a valid crc
+        let type_flag = LogRecordType::Normal as u8 | RECORD_ENCRYPTED_FLAG;
+        let key_aad = key_aad(type_flag, 3, 1, &db_id);
+        let key = cipher.encrypt(&record.key, &key_aad).unwrap();
+        let value = cipher
+            .encrypt(&record.value, &value_aad(key_aad, &key))
+            .unwrap();
+        for (batch_id, seq_id, readable) in [(3, 1, true), (3, 2, false), (4, 1, false)] {
+            let offset = datafile.get_offset();
+            let (data, _) = encode_record(
+                type_flag,
+                batch_id,
+                seq_id,
+                &key,
+                &value,
+                ChecksumType::Crc32,
+            );
+            assert!(datafile.write(&data).is_ok());
+            assert_eq!(datafile.read_log_record(offset).is_ok(), readable);
+        }
+
+        // plain record is rejected once a cipher is configured
+        let offset = datafile.get_offset();
+        assert!(datafile.write(&record.encode()).is_ok());
+        assert_eq!(
+            datafile.read_log_record(offset).err(),
+            Some(Errors::UnencryptedRecord)
+        );
+        datafile.set_cipher(None);
+        assert_eq!(datafile.read_log_record(offset).unwrap().record, record);
+    }
+
+    #[test]
+    fn test_data_file_read_value_range() {
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        let first = LogRecord {
+            key: "key-1".as_bytes().to_vec(),
+            value: "0123456789".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let second = LogRecord {
+            key: "key-2".as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::Deleted,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let first_size = datafile.write(&first.encode()).unwrap() as u64;
+        assert!(datafile.write(&second.encode()).is_ok());
+
+        let range = |offset, value_offset, len| {
+            datafile
+                .read_value_range(offset, value_offset, len)
+                .map(|(record_type, value)| (record_type, String::from_utf8(value).unwrap()))
+        };
+        assert_eq!(
+            range(0, 2, 3),
+            Ok((LogRecordType::Normal, String::from("234")))
+        );
+        assert_eq!(
+            range(0, 8, 100),
+            Ok((LogRecordType::Normal, String::from("89")))
+        );
+        assert_eq!(range(0, 20, 5), Ok((LogRecordType::Normal, String::new())));
+        assert_eq!(
+            range(first_size, 0, 5),
+            Ok((LogRecordType::Deleted, String::new()))
+        );
+    }
+
+    #[test]
+    fn test_data_file_write_buffer() {
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let path = generate_datafile_name(tmp_dir.path(), 0);
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        datafile.set_write_buffer_size(64);
+
+        let records = (0..10)
+            .map(|i| LogRecord {
+                key: format!("key-{}", i).into_bytes(),
+                value: format!("value-{}", i).into_bytes(),
+                record_type: LogRecordType::Normal,
+                batch_id: 0,
+                seq_id: 0,
+            })
+            .collect::<Vec<_>>();
+        let mut offsets = Vec::new();
+        for record in records.iter() {
+            offsets.push(datafile.get_offset());
+            assert!(datafile.write(&record.encode()).is_ok());
+        }
+
+        // some records are still in buffer, but all of them are readable
+        let file_len = std::fs::metadata(&path).unwrap().len();
+        assert!(file_len < datafile.get_offset());
+        for (record, offset) in records.iter().zip(offsets.iter()) {
+            assert_eq!(&datafile.read_log_record(*offset).unwrap().record, record);
+        }
+        let read = datafile
+            .read_log_records(&offsets, &ReadOptions::default())
+            .unwrap();
+        assert_eq!(
+            read.into_iter().map(|r| r.record).collect::<Vec<_>>(),
+            records
+        );
+
+        // a record larger than buffer is written through
+        let large = LogRecord {
+            key: "large".into(),
+            value: vec![7; 100],
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let large_offset = datafile.get_offset();
+        assert!(datafile.write(&large.encode()).is_ok());
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            datafile.get_offset()
+        );
+        assert_eq!(
+            datafile.read_log_record(large_offset).unwrap().record,
+            large
+        );
+
+        assert!(datafile.write(&records[0].encode()).is_ok());
+        assert!(std::fs::metadata(&path).unwrap().len() < datafile.get_offset());
+        assert!(datafile.flush().is_ok());
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            datafile.get_offset()
+        );
+    }
+
+    #[test]
+    fn test_data_file_malformed_records() {
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        let corrupted = |offset| Err(Errors::CorruptedRecord { file_id: 0, offset });
+        let with_crc = |record: &[u8]| {
+            let mut record = record.to_vec();
+            let crc = ChecksumType::Crc32.checksum(&[&record]);
+            record.extend_from_slice(&crc.to_le_bytes());
+            record
+        };
+
+        // unknown type, key size of 4GB and a record exceeding end of file
+        let records = [
+            with_crc(&[0x0e, 0, 0, 1, 1, b'k', b'v']),
+            vec![0x01, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x0f, 0],
+            vec![0x01, 0, 0, 1, 100, b'k', b'v'],
+        ];
+        let mut offsets = Vec::new();
+        for record in records.iter() {
+            offsets.push(datafile.get_offset());
+            assert!(datafile.write(record).is_ok());
+        }
+        let file_size = datafile.size().unwrap();
+        for offset in offsets {
+            assert_eq!(
+                datafile.read_log_record(offset).map(|_| ()),
+                corrupted(offset)
+            );
+            // verifying takes the same checks as reading
+            assert_eq!(
+                datafile.verify_record(offset, file_size),
+                Ok(RecordCheck::Corrupted(file_size - offset))
+            );
+            assert_eq!(
+                datafile
+                    .read_log_records(&[offset], &ReadOptions::default())
+                    .map(|_| ()),
+                corrupted(offset)
+            );
+        }
+
+        // snappy value claiming 4GB once decompressed
+        let offset = datafile.get_offset();
+        let bomb = with_crc(&[0x11, 0, 0, 1, 5, b'k', 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert!(datafile.write(&bomb).is_ok());
+        assert_eq!(
+            datafile.read_log_record(offset).map(|_| ()),
+            corrupted(offset)
+        );
+
+        // sizes are checked against configured limits
+        let offset = datafile.get_offset();
+        let record = LogRecord {
+            key: vec![1; 64],
+            value: vec![2; 4096],
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        assert!(datafile.write(&record.encode()).is_ok());
+        assert_eq!(datafile.read_log_record(offset).unwrap().record, record);
+        let mut options = Options {
+            max_value_size: 1024,
+            ..Default::default()
+        };
+        datafile.set_record_options(&options);
+        assert_eq!(
+            datafile.read_log_record(offset).map(|_| ()),
+            corrupted(offset)
+        );
+        let file_size = datafile.size().unwrap();
+        assert_eq!(
+            datafile.verify_record(offset, file_size),
+            Ok(RecordCheck::Corrupted(file_size - offset))
+        );
+        // stored key may exceed the limit by overhead of cipher
+        options.max_value_size = 4096;
+        options.max_key_size = 16;
+        datafile.set_record_options(&options);
+        assert_eq!(datafile.read_log_record(offset).unwrap().record, record);
+        assert_eq!(
+            datafile.verify_record(offset, file_size),
+            Ok(RecordCheck::Valid(file_size - offset))
+        );
+    }
+
+    #[test]
+    fn test_data_file_find_next_record() {
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let mut datafile = DataFile::new(tmp_dir.path(), 0, &IOType::StandardFIO).unwrap();
+        let record = |value: Vec<u8>| {
+            LogRecord {
+                key: "key".as_bytes().to_vec(),
+                value,
+                record_type: LogRecordType::Normal,
+                batch_id: 0,
+                seq_id: 0,
+            }
+            .encode()
+        };
+
+        // garbage claiming huge records, a record with a large value and a small one
+        let garbage = [
+            0x01, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x02, 0, 0, 0x80, 0x80,
+        ];
+        assert!(datafile.write(&garbage).is_ok());
+        let large = datafile.get_offset();
+        assert!(datafile.write(&record(vec![1; 4096])).is_ok());
+        let small = datafile.get_offset();
+        assert!(datafile.write(&record(vec![2; 16])).is_ok());
+        assert_eq!(datafile.find_next_record(0), Ok(Some(large)));
+
+        // candidates exceeding size limits are rejected before their body is read
+        let options = Options {
+            max_value_size: 1024,
+            ..Default::default()
+        };
+        datafile.set_record_options(&options);
+        assert_eq!(datafile.find_next_record(0), Ok(Some(small)));
+        let file_size = datafile.size().unwrap();
+        assert_eq!(
+            datafile.verify_record(large, file_size),
+            Ok(RecordCheck::Corrupted(file_size - large))
+        );
+
+        // a zeroed chunk is preallocated space after the last record
+        assert!(datafile.write(&vec![0; SCAN_CHUNK_SIZE]).is_ok());
+        assert!(datafile.write(&record(vec![3; 16])).is_ok());
+        assert_eq!(datafile.find_next_record(small + 1), Ok(None));
+    }
 }
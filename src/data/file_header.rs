@@ -0,0 +1,190 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::error;
+use uuid::Uuid;
+
+use super::checksum::ChecksumType;
+use crate::error::{Errors, Result};
+
+/// first bytes of every datafile and blob file
+pub(crate) const FILE_MAGIC: &[u8; 4] = b"BCRS";
+
+/// format version written to new files, files of older versions are still readable
+/// - 0: legacy files without header, records are encoded like version 1
+/// - 1: batch id and sequence are encoded in an envelope of record key
+/// - 2: batch id and sequence are fields of record header, keys are stored raw
+/// - 3: header records checksum algorithm of records
+pub(crate) const FORMAT_VERSION: u32 = 3;
+
+/// type flags of records in legacy files, normal, deleted and batch commit
+const LEGACY_RECORD_TYPES: std::ops::RangeInclusive<u8> = 1..=3;
+
+/// | magic | version u32 | created_at u64 | database id 16B | checksum u8 | reserved 3B | crc u32 |
+pub(crate) const FILE_HEADER_SIZE: usize = 4 + 4 + 8 + 16 + 4 + 4;
+
+/// header of version 1 and 2 has no checksum algorithm and reserved bytes
+/// | magic | version u32 | created_at u64 | database id 16B | crc u32 |
+pub(crate) const FILE_HEADER_V2_SIZE: usize = 4 + 4 + 8 + 16 + 4;
+
+/// FileHeader fixed header in front of records of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    /// on-disk format version of records
+    pub(crate) version: u32,
+    /// creation time in seconds since unix epoch
+    pub(crate) created_at: u64,
+    /// id of database which the file belongs to
+    pub(crate) db_id: Uuid,
+    /// algorithm of record checksums
+    pub(crate) checksum: ChecksumType,
+}
+
+impl FileHeader {
+    pub(crate) fn new(db_id: Uuid, checksum: ChecksumType) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        FileHeader {
+            version: FORMAT_VERSION,
+            created_at,
+            db_id,
+            checksum,
+        }
+    }
+
+    /// encoded size of header of @version, records start right after it
+    pub(crate) fn size(version: u32) -> usize {
+        match version {
+            0 => 0,
+            1 | 2 => FILE_HEADER_V2_SIZE,
+            _ => FILE_HEADER_SIZE,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(FILE_HEADER_SIZE);
+        buf.extend_from_slice(FILE_MAGIC);
+        buf.put_u32_le(self.version);
+        buf.put_u64_le(self.created_at);
+        buf.extend_from_slice(self.db_id.as_bytes());
+        if self.version >= 3 {
+            buf.put_u8(self.checksum.id());
+            buf.put_bytes(0, 3);
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.put_u32_le(crc);
+        buf.to_vec()
+    }
+
+    /// header of a legacy file, it has no database id and its records start at offset 0
+    pub(crate) fn legacy() -> Self {
+        FileHeader {
+            version: 0,
+            created_at: 0,
+            db_id: Uuid::nil(),
+            checksum: ChecksumType::Crc32,
+        }
+    }
+
+    /// decode header from first `FILE_HEADER_SIZE` bytes of a file,
+    /// returns `None` when they are all zero, i.e. header is never written,
+    /// a file which starts with a record instead of magic is a legacy file
+    pub(crate) fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        if buf.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if buf.first().is_some_and(|t| LEGACY_RECORD_TYPES.contains(t)) {
+            return Ok(Some(FileHeader::legacy()));
+        }
+        if buf.len() < FILE_HEADER_V2_SIZE || &buf[..FILE_MAGIC.len()] != FILE_MAGIC {
+            error!("invalid file magic, it is not a bitcask file");
+            return Err(Errors::InvalidFileHeader);
+        }
+
+        let mut content = &buf[FILE_MAGIC.len()..];
+        let version = content.get_u32_le();
+        if version == 0 || version > FORMAT_VERSION {
+            error!(
+                "file format version {} is newer than supported version {}",
+                version, FORMAT_VERSION
+            );
+            return Err(Errors::UnsupportedFormatVersion(version));
+        }
+        let size = FileHeader::size(version);
+        if buf.len() < size {
+            return Err(Errors::InvalidFileHeader);
+        }
+
+        let created_at = content.get_u64_le();
+        let db_id = Uuid::from_slice(&content[..16]).map_err(|_| Errors::InvalidFileHeader)?;
+        content.advance(16);
+        let checksum = match version {
+            1 | 2 => ChecksumType::Crc32,
+            _ => {
+                let id = content.get_u8();
+                content.advance(3);
+                ChecksumType::from_id(id).ok_or(Errors::InvalidFileHeader)?
+            }
+        };
+        let crc = content.get_u32_le();
+        if crc != crc32fast::hash(&buf[..size - 4]) {
+            error!("file header crc mismatch, file may be corrupted");
+            return Err(Errors::InvalidFileHeader);
+        }
+
+        Ok(Some(FileHeader {
+            version,
+            created_at,
+            db_id,
+            checksum,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_header_encode_and_decode() {
+        let header = FileHeader::new(Uuid::new_v4(), ChecksumType::Xxh3);
+        let mut buf = header.encode();
+        assert_eq!(buf.len(), FILE_HEADER_SIZE);
+        assert_eq!(FileHeader::decode(&buf), Ok(Some(header)));
+        assert_eq!(FileHeader::decode(&[0; FILE_HEADER_SIZE]), Ok(None));
+
+        buf[10] ^= 1;
+        assert_eq!(FileHeader::decode(&buf), Err(Errors::InvalidFileHeader));
+        assert_eq!(
+            FileHeader::decode(b"not a bitcask file, just text......."),
+            Err(Errors::InvalidFileHeader)
+        );
+
+        // header of version 2 is shorter and has no checksum algorithm
+        let v2 = FileHeader {
+            version: 2,
+            checksum: ChecksumType::Crc32,
+            ..header
+        };
+        assert_eq!(v2.encode().len(), FILE_HEADER_V2_SIZE);
+        assert_eq!(FileHeader::decode(&v2.encode()), Ok(Some(v2)));
+
+        // legacy file starts with its first record
+        assert_eq!(
+            FileHeader::decode(&[1, 8, 5, b'k']),
+            Ok(Some(FileHeader::legacy()))
+        );
+        assert_eq!(FileHeader::size(0), 0);
+
+        let newer = FileHeader {
+            version: FORMAT_VERSION + 1,
+            ..header
+        };
+        assert_eq!(
+            FileHeader::decode(&newer.encode()),
+            Err(Errors::UnsupportedFormatVersion(FORMAT_VERSION + 1))
+        );
+    }
+}
@@ -1,7 +1,16 @@
-use core::fmt;
+use std::borrow::Cow;
 
-use bytes::{BufMut, BytesMut};
-use prost::{encode_length_delimiter, length_delimiter_len};
+use bytes::{Buf, BufMut, BytesMut};
+use log::error;
+use prost::{
+    decode_length_delimiter, encode_length_delimiter,
+    encoding::{encode_varint, encoded_len_varint},
+    length_delimiter_len,
+};
+use uuid::Uuid;
+
+use super::{checksum::ChecksumType, cipher::Cipher, codec::Codec};
+use crate::error::{Errors, Result};
 
 /// LogRecordPos description of a record position with file id and offset
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -10,6 +19,70 @@ pub struct LogRecordPos {
     pub(crate) offset: u64,
 }
 
+/// BlobPos position of a value separated into a blob file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlobPos {
+    pub(crate) file_id: u32,
+    pub(crate) offset: u64,
+}
+
+pub(crate) const BLOB_POS_SIZE: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u64>();
+
+impl BlobPos {
+    /// encode position as below format
+    /// | file_id u32 | offset u64 |
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(BLOB_POS_SIZE);
+        buf.put_u32_le(self.file_id);
+        buf.put_u64_le(self.offset);
+        buf.to_vec()
+    }
+
+    pub(crate) fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.len() != BLOB_POS_SIZE {
+            return None;
+        }
+        Some(BlobPos {
+            file_id: buf.get_u32_le(),
+            offset: buf.get_u64_le(),
+        })
+    }
+}
+
+/// BlobChunk a chunk of a streamed value, which is stored as a blob
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobChunk {
+    pub(crate) pos: BlobPos,
+    pub(crate) size: u64,
+}
+
+pub(crate) const BLOB_CHUNK_SIZE: usize = BLOB_POS_SIZE + std::mem::size_of::<u64>();
+
+/// encode chunks as below format
+/// | blob pos | size u64 | blob pos | size u64 | ...
+pub(crate) fn encode_chunks(chunks: &[BlobChunk]) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(chunks.len() * BLOB_CHUNK_SIZE);
+    for chunk in chunks {
+        buf.extend_from_slice(&chunk.pos.encode());
+        buf.put_u64_le(chunk.size);
+    }
+    buf.to_vec()
+}
+
+pub(crate) fn decode_chunks(buf: &[u8]) -> Option<Vec<BlobChunk>> {
+    if !buf.len().is_multiple_of(BLOB_CHUNK_SIZE) {
+        return None;
+    }
+    buf.chunks(BLOB_CHUNK_SIZE)
+        .map(|chunk| {
+            Some(BlobChunk {
+                pos: BlobPos::decode(&chunk[..BLOB_POS_SIZE])?,
+                size: (&chunk[BLOB_POS_SIZE..]).get_u64_le(),
+            })
+        })
+        .collect()
+}
+
 /// types of a record in a log
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LogRecordType {
@@ -21,15 +94,26 @@ pub enum LogRecordType {
 
     /// batch commit finished
     BatchCommit = 3,
+
+    /// a normal record whose value is stored in a blob file,
+    /// its own value is an encoded `BlobPos`
+    BlobPointer = 4,
+
+    /// a normal record of a streamed value, its own value is an encoded list
+    /// of `BlobChunk` in blob files
+    ChunkList = 5,
 }
 
 impl LogRecordType {
-    pub(crate) fn from_u8(v: u8) -> Self {
+    /// type of value @v, `None` if it is unknown
+    pub(crate) fn from_u8(v: u8) -> Option<Self> {
         match v {
-            1 => LogRecordType::Normal,
-            2 => LogRecordType::Deleted,
-            3 => LogRecordType::BatchCommit,
-            _ => unreachable!(),
+            1 => Some(LogRecordType::Normal),
+            2 => Some(LogRecordType::Deleted),
+            3 => Some(LogRecordType::BatchCommit),
+            4 => Some(LogRecordType::BlobPointer),
+            5 => Some(LogRecordType::ChunkList),
+            _ => None,
         }
     }
 }
@@ -41,51 +125,146 @@ pub struct LogRecord {
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
     pub(crate) record_type: LogRecordType,
+    /// id of the engine session which wrote the batch, 0 for a record out of batch
+    pub(crate) batch_id: u64,
+    /// sequence of the batch in its session, 0 for a record out of batch
+    pub(crate) seq_id: usize,
 }
 
 impl LogRecord {
     /// encode record as below format
-    /// | type | key_size | value_size | key | value | crc |
+    /// | type | batch_id | seq_id | key_size | value_size | key | value | crc |
+    #[cfg(test)]
     pub(crate) fn encode(&self) -> Vec<u8> {
         self.encode_and_crc().0
     }
 
-    pub fn get_crc(&self) -> u32 {
+    /// encode record with value compressed by @codec, type flag keeps the codec id
+    /// in its high bits, value is stored as is when compression does not shrink it,
+    /// key and value are sealed by @cipher afterwards, bound to record header and
+    /// database @db_id, record ends with a checksum of @checksum algorithm
+    pub(crate) fn encode_with(
+        &self,
+        codec: Option<&dyn Codec>,
+        cipher: Option<&dyn Cipher>,
+        db_id: &Uuid,
+        checksum: ChecksumType,
+    ) -> Result<Vec<u8>> {
+        let mut type_flag = self.record_type as u8;
+        let mut key = Cow::Borrowed(&self.key);
+        let mut value = Cow::Borrowed(&self.value);
+
+        let codec =
+            codec.filter(|_| self.record_type == LogRecordType::Normal && !self.value.is_empty());
+        if let Some(codec) = codec {
+            let compressed = codec.compress(&self.value)?;
+            if compressed.len() < self.value.len() {
+                type_flag |= codec.id() << RECORD_CODEC_SHIFT;
+                value = Cow::Owned(compressed);
+            }
+        }
+
+        if let Some(cipher) = cipher {
+            type_flag |= RECORD_ENCRYPTED_FLAG;
+            let key_aad = key_aad(type_flag, self.batch_id, self.seq_id, db_id);
+            let sealed_key = cipher.encrypt(&key, &key_aad)?;
+            value = Cow::Owned(cipher.encrypt(&value, &value_aad(key_aad, &sealed_key))?);
+            key = Cow::Owned(sealed_key);
+        }
+
+        Ok(encode_record(
+            type_flag,
+            self.batch_id,
+            self.seq_id,
+            &key,
+            &value,
+            checksum,
+        )
+        .0)
+    }
+
+    /// checksum of record encoded by `encode`, i.e. crc32
+    #[cfg(test)]
+    pub(crate) fn get_crc(&self) -> u32 {
         self.encode_and_crc().1
     }
 
+    #[cfg(test)]
     fn encode_and_crc(&self) -> (Vec<u8>, u32) {
-        let mut buf = BytesMut::new();
-        buf.reserve(self.encoded_length());
+        encode_record(
+            self.record_type as u8,
+            self.batch_id,
+            self.seq_id,
+            &self.key,
+            &self.value,
+            ChecksumType::Crc32,
+        )
+    }
+}
+
+/// additional data authenticated with sealed key, binds key to type, batch id and
+/// sequence of its record and to database @db_id, so a sealed record can't be
+/// replayed under another header or in another database
+pub(crate) fn key_aad(type_flag: u8, batch_id: u64, seq_id: usize, db_id: &Uuid) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(33);
+    aad.push(type_flag);
+    aad.put_u64_le(batch_id);
+    aad.put_u64_le(seq_id as u64);
+    aad.extend_from_slice(db_id.as_bytes());
+    aad
+}
 
-        // type
-        buf.put_u8(self.record_type as u8);
+/// additional data authenticated with sealed value, binds value to its sealed key
+pub(crate) fn value_aad(mut key_aad: Vec<u8>, sealed_key: &[u8]) -> Vec<u8> {
+    key_aad.extend_from_slice(sealed_key);
+    key_aad
+}
 
-        // key size
-        let _: Result<(), _> = encode_length_delimiter(self.key.len(), &mut buf);
-        // value size
-        let _: Result<(), _> = encode_length_delimiter(self.value.len(), &mut buf);
+/// encode a record with raw @type_flag, returns encoded bytes and checksum
+pub(crate) fn encode_record(
+    type_flag: u8,
+    batch_id: u64,
+    seq_id: usize,
+    key: &[u8],
+    value: &[u8],
+    checksum: ChecksumType,
+) -> (Vec<u8>, u32) {
+    let mut buf = BytesMut::new();
+    buf.reserve(encoded_length(batch_id, seq_id, key, value));
 
-        // key
-        buf.extend_from_slice(&self.key);
-        // value
-        buf.extend_from_slice(&self.value);
+    // type
+    buf.put_u8(type_flag);
 
-        // crc
-        let crc = crc32fast::hash(&buf);
-        buf.put_u32_le(crc);
+    // batch id and sequence
+    encode_varint(batch_id, &mut buf);
+    encode_varint(seq_id as u64, &mut buf);
 
-        (buf.to_vec(), crc)
-    }
+    // key size
+    let _: std::result::Result<(), _> = encode_length_delimiter(key.len(), &mut buf);
+    // value size
+    let _: std::result::Result<(), _> = encode_length_delimiter(value.len(), &mut buf);
 
-    fn encoded_length(&self) -> usize {
-        LOG_TYPE_FLAG_SIZE
-            + length_delimiter_len(self.key.len())
-            + length_delimiter_len(self.value.len())
-            + self.key.len()
-            + self.value.len()
-            + LOG_CRC_SIZE
-    }
+    // key
+    buf.extend_from_slice(key);
+    // value
+    buf.extend_from_slice(value);
+
+    // checksum
+    let crc = checksum.checksum(&[&buf]);
+    buf.put_u32_le(crc);
+
+    (buf.to_vec(), crc)
+}
+
+fn encoded_length(batch_id: u64, seq_id: usize, key: &[u8], value: &[u8]) -> usize {
+    LOG_TYPE_FLAG_SIZE
+        + encoded_len_varint(batch_id)
+        + encoded_len_varint(seq_id as u64)
+        + length_delimiter_len(key.len())
+        + length_delimiter_len(value.len())
+        + key.len()
+        + value.len()
+        + LOG_CRC_SIZE
 }
 
 pub struct ReadLogRecord {
@@ -93,41 +272,54 @@ pub struct ReadLogRecord {
     pub(crate) size: u64,
 }
 
-/// | log_type | key_size | value_size | key | value | crc
-// pub struct LogRecordHeader {
-//     pub(crate) log_type: LogRecordType,
-//     pub(crate) key_size: u32,
-//     pub(crate) value_size: u32,
-//     pub(crate) crc: u32,
-// }
-
 pub(crate) const LOG_CRC_SIZE: usize = std::mem::size_of::<u32>();
 pub(crate) const LOG_TYPE_FLAG_SIZE: usize = std::mem::size_of::<u8>();
 
-pub(crate) fn log_record_max_size() -> usize {
-    LOG_TYPE_FLAG_SIZE + length_delimiter_len(std::u32::MAX as usize) * 2 + LOG_CRC_SIZE
-}
+/// low bits of type flag are `LogRecordType`
+pub(crate) const RECORD_TYPE_MASK: u8 = 0x0f;
+/// bits 4-6 of type flag are id of the codec which compressed value
+pub(crate) const RECORD_CODEC_SHIFT: u8 = 4;
+pub(crate) const RECORD_CODEC_MASK: u8 = 0x07;
+/// bit 7 of type flag marks key and value are encrypted
+pub(crate) const RECORD_ENCRYPTED_FLAG: u8 = 0x80;
 
-#[derive(PartialEq)]
-pub(crate) struct LogRecordKey {
-    pub(crate) prefix: Vec<u8>,
-    pub(crate) seq_id: usize,
-    pub(crate) key: Vec<u8>,
+pub(crate) fn log_record_max_size() -> usize {
+    LOG_TYPE_FLAG_SIZE
+        + encoded_len_varint(u64::MAX) * 2
+        + length_delimiter_len(u32::MAX as usize) * 2
+        + LOG_CRC_SIZE
 }
 
-impl fmt::Debug for LogRecordKey {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("LogRecordKey")
-            .field("prefix", &std::str::from_utf8(&self.prefix))
-            .field("seq_id", &self.seq_id)
-            .field("key", &std::str::from_utf8(&self.key))
-            .finish()
+/// split a key of legacy or v1 format into batch id, sequence and user key,
+/// batch id is the 8 bytes prefix of a batch, a nanosecond timestamp taken when
+/// its session opens, or 0 for a record out of batch, whose prefix is `non_txn`
+/// | prefix_size | prefix | seq_id | key |
+pub(crate) fn decode_v1_key(key: &[u8]) -> Result<(u64, usize, Vec<u8>)> {
+    let mut buffer = key;
+    let map_err = |e| {
+        error!("decode v1 record key failed: {}", e);
+        Errors::DecodingError
+    };
+    let prefix_size = decode_length_delimiter(&mut buffer).map_err(map_err)?;
+    if buffer.len() < prefix_size {
+        return Err(Errors::DecodingError);
     }
+    let (prefix, mut buffer) = buffer.split_at(prefix_size);
+    let seq_id = decode_length_delimiter(&mut buffer).map_err(map_err)?;
+    let batch_id = match <[u8; 8]>::try_from(prefix) {
+        Ok(prefix) => u64::from_le_bytes(prefix),
+        Err(_) => 0,
+    };
+    Ok((batch_id, seq_id, buffer.to_vec()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::{
+        cipher::XChaCha20Poly1305Cipher,
+        codec::{SNAPPY_CODEC, SNAPPY_CODEC_ID},
+    };
 
     #[test]
     fn test_log_record_encode_and_crc() {
@@ -135,27 +327,171 @@ mod tests {
             key: "my-key".as_bytes().to_vec(),
             value: "my_value".as_bytes().to_vec(),
             record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (vec, crc) = rec.encode_and_crc();
-        assert_eq!(vec.len(), 21);
-        assert_eq!(crc, 1579242186);
+        assert_eq!(vec.len(), 23);
+        assert_eq!(crc, 495067395);
 
         let rec = LogRecord {
             key: "my-key-1".as_bytes().to_vec(),
             value: vec![],
             record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (vec, crc) = rec.encode_and_crc();
-        assert_eq!(vec.len(), 15);
-        assert_eq!(crc, 4164702405);
+        assert_eq!(vec.len(), 17);
+        assert_eq!(crc, 2161615342);
 
         let rec = LogRecord {
             key: "my-key-1".as_bytes().to_vec(),
             value: vec![],
             record_type: LogRecordType::Deleted,
+            batch_id: 0,
+            seq_id: 0,
         };
         let (vec, crc) = rec.encode_and_crc();
-        assert_eq!(vec.len(), 15);
-        assert_eq!(crc, 1641952964);
+        assert_eq!(vec.len(), 17);
+        assert_eq!(crc, 1025363232);
+
+        // key is kept raw, batch id and sequence take a varint each
+        let rec = LogRecord {
+            key: "my-key-1".as_bytes().to_vec(),
+            value: vec![],
+            record_type: LogRecordType::Normal,
+            batch_id: 300,
+            seq_id: 89,
+        };
+        let (vec, _) = rec.encode_and_crc();
+        assert_eq!(vec.len(), 18);
+        assert_eq!(&vec[1..6], &[0xac, 0x02, 89, 8, 0]);
+    }
+
+    #[test]
+    fn test_log_record_encode_with_codec() {
+        let rec = LogRecord {
+            key: "my-key".as_bytes().to_vec(),
+            value: "my_value".repeat(10).into_bytes(),
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let plain = rec.encode();
+        let compressed = rec
+            .encode_with(Some(&SNAPPY_CODEC), None, &Uuid::nil(), ChecksumType::Crc32)
+            .unwrap();
+        assert!(compressed.len() < plain.len());
+        assert_eq!(
+            compressed[0],
+            LogRecordType::Normal as u8 | SNAPPY_CODEC_ID << RECORD_CODEC_SHIFT
+        );
+        assert_eq!(
+            rec.encode_with(None, None, &Uuid::nil(), ChecksumType::Crc32)
+                .unwrap(),
+            plain
+        );
+
+        // incompressible values and other records are kept as is
+        let rec = LogRecord {
+            key: "my-key".as_bytes().to_vec(),
+            value: "v".as_bytes().to_vec(),
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        assert_eq!(
+            rec.encode_with(Some(&SNAPPY_CODEC), None, &Uuid::nil(), ChecksumType::Crc32)
+                .unwrap(),
+            rec.encode()
+        );
+        let rec = LogRecord {
+            key: "my-key".as_bytes().to_vec(),
+            value: "my_value".repeat(10).into_bytes(),
+            record_type: LogRecordType::BlobPointer,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        assert_eq!(
+            rec.encode_with(Some(&SNAPPY_CODEC), None, &Uuid::nil(), ChecksumType::Crc32)
+                .unwrap(),
+            rec.encode()
+        );
+    }
+
+    #[test]
+    fn test_log_record_encode_with_cipher() {
+        let rec = LogRecord {
+            key: "my-key".as_bytes().to_vec(),
+            value: "my_value".repeat(10).into_bytes(),
+            record_type: LogRecordType::Normal,
+            batch_id: 0,
+            seq_id: 0,
+        };
+        let cipher = XChaCha20Poly1305Cipher::new(&[1; 32]);
+        let sealed = rec
+            .encode_with(
+                Some(&SNAPPY_CODEC),
+                Some(&cipher),
+                &Uuid::nil(),
+                ChecksumType::Crc32,
+            )
+            .unwrap();
+        assert_eq!(
+            sealed[0],
+            LogRecordType::Normal as u8
+                | SNAPPY_CODEC_ID << RECORD_CODEC_SHIFT
+                | RECORD_ENCRYPTED_FLAG
+        );
+        assert!(!sealed.windows(6).any(|w| w == b"my-key"));
+        assert!(!sealed.windows(8).any(|w| w == b"my_value"));
+    }
+
+    #[test]
+    fn test_decode_v1_key() {
+        let mut key = vec![7];
+        key.extend_from_slice(b"non_txn");
+        key.push(0);
+        key.extend_from_slice(b"my-key");
+        assert_eq!(decode_v1_key(&key), Ok((0, 0, b"my-key".to_vec())));
+
+        let mut key = vec![8];
+        key.extend_from_slice(&1234567u64.to_le_bytes());
+        key.push(89);
+        key.extend_from_slice(b"my-key");
+        assert_eq!(decode_v1_key(&key), Ok((1234567, 89, b"my-key".to_vec())));
+
+        assert_eq!(decode_v1_key(&[9, 1, 2]), Err(Errors::DecodingError));
+    }
+
+    #[test]
+    fn test_blob_pos_encode_and_decode() {
+        let pos = BlobPos {
+            file_id: 7,
+            offset: 1 << 40,
+        };
+        let buf = pos.encode();
+        assert_eq!(buf.len(), BLOB_POS_SIZE);
+        assert_eq!(BlobPos::decode(&buf), Some(pos));
+        assert_eq!(BlobPos::decode(&buf[1..]), None);
+    }
+
+    #[test]
+    fn test_blob_chunks_encode_and_decode() {
+        let chunks = (0..3)
+            .map(|i| BlobChunk {
+                pos: BlobPos {
+                    file_id: i,
+                    offset: i as u64 * 100,
+                },
+                size: 1 << 20,
+            })
+            .collect::<Vec<_>>();
+        let buf = encode_chunks(&chunks);
+        assert_eq!(buf.len(), 3 * BLOB_CHUNK_SIZE);
+        assert_eq!(decode_chunks(&buf), Some(chunks));
+        assert_eq!(decode_chunks(&[]), Some(vec![]));
+        assert_eq!(decode_chunks(&buf[1..]), None);
     }
 }
@@ -1,2 +1,6 @@
+pub mod checksum;
+pub mod cipher;
+pub mod codec;
 pub mod data_file;
+pub mod file_header;
 pub mod log_record;
@@ -1,53 +1,111 @@
 use std::{
-    borrow::{Borrow, BorrowMut},
+    borrow::Borrow,
     collections::HashMap,
-    fs,
+    fs::{self, File},
+    ops::Deref,
     path::Path,
-    sync::{atomic::AtomicUsize, Arc},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
 };
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use log::{debug, error, info, warn};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
 
 use crate::{
-    batch::{log_record_key_parse, log_record_key_with_sequence, NON_TXN_PREFIX},
+    blob::{load_blob_files, BlobPin, BlobReaders},
     data::{
-        data_file::{DataFile, DATAFILE_NAME_SUFFIX, DATAFILE_SEPARATOR},
+        data_file::{generate_datafile_name, DataFile},
+        file_header::{FileHeader, FILE_HEADER_SIZE, FORMAT_VERSION},
         log_record::{LogRecord, LogRecordPos, LogRecordType},
     },
     error::{Errors, Result},
+    fio::{
+        file_io::{lock_file, unlock_file},
+        io_manager::sync_dir,
+    },
     index::{self, indexer::new_indexer},
-    options::Options,
+    manifest::{Manifest, ManifestEdit, ManifestState},
+    options::{Options, ReadOptions, RecoveryMode},
+    options_file::{
+        check_persisted_options, check_unrecorded_options, read_options_file,
+        remove_options_tmp_file, write_options_file, PersistedOptions,
+    },
+    recovery::{
+        put_clean_shutdown_marker, salvage_log_record, take_clean_shutdown_marker, RecoveryReport,
+        SkippedRange,
+    },
+    scrub::{spawn_scrubber, ScrubState},
 };
 
 const INITAIL_FILE_ID: u32 = 0;
-const NON_BATCH_COMMIT_ID: usize = 0;
+/// file locked by an open engine, so a database is never opened twice at once
+const LOCK_FILE_NAME: &str = "LOCK";
+pub(crate) const NON_BATCH_COMMIT_ID: usize = 0;
+pub(crate) const NON_BATCH_ID: u64 = 0;
+/// count of locks keys are spread over, see `Engine::lock_keys`
+const KEY_LOCK_STRIPES: usize = 64;
+
+/// sealed datafiles by id, they never change, so a snapshot of them is replaced
+/// as a whole when a file is sealed and they are read without any lock
+pub(crate) type SealedFiles = HashMap<u32, Arc<DataFile>>;
 
 pub struct Engine {
-    options: Arc<Options>,
+    pub(crate) options: Arc<Options>,
+    pub(crate) db_id: Uuid, // id of database, recorded in header of every file
 
-    active_file: Arc<RwLock<DataFile>>, // current active file
-    old_files: Arc<RwLock<HashMap<u32, DataFile>>>, // old files
+    pub(crate) active_file: Arc<RwLock<DataFile>>, // current active file
+    pub(crate) old_files: Arc<ArcSwap<SealedFiles>>, // snapshot of old files
+    pub(crate) active_blob_file: Arc<RwLock<Option<DataFile>>>, // blob file for new values
+    pub(crate) old_blob_files: Arc<RwLock<HashMap<u32, DataFile>>>, // sealed blob files
+    pub(crate) blob_merge_lock: Mutex<()>,         // only one blob merge runs at a time
+    pub(crate) blob_readers: Mutex<BlobReaders>, // readers which may hold pointers to merged blobs
     pub(crate) indexer: Box<dyn index::Indexer>, // memory index manager
+    pub(crate) manifest: Mutex<Manifest>,        // log of live datafiles and blob files
 
     file_ids: Vec<u32>, // file id list, only use in database initialize
 
     pub(crate) batch_commit_lock: Mutex<()>, // batch commit global lock
-    pub(crate) batch_prefix: Vec<u8>,
+    pub(crate) index_lock: RwLock<()>,       // shared by writers from append to index update
+    key_locks: Vec<Mutex<()>>, // held by a writer of a key from append to index update
+    pub(crate) batch_id: u64,  // id of batches written in this session, greater than any in files
     pub(crate) batch_commit_id: Arc<AtomicUsize>, // latest batch commit id
+
+    pub(crate) scrub_state: Arc<ScrubState>, // corrupt ranges and stats of scrubbing
+    pub(crate) scrubber: Mutex<Option<JoinHandle<()>>>, // background scrub thread
+
+    pub(crate) recovery_report: RecoveryReport, // corruption skipped by salvage recovery
+    pub(crate) clean_shutdown: bool, // whether the database is closed cleanly before open
+
+    closed: AtomicBool,              // every read and write fails after shutdown
+    poisoned: Mutex<Option<Errors>>, // failed sync, every write and sync fails with it
+    dir_lock: File,                  // lock of database directory, released by shutdown
+}
+
+/// Db shared handle of an engine, it is cheap to clone and can be sent to other threads,
+/// the engine is shut down when the last handle is dropped or any handle is closed
+#[derive(Clone)]
+pub struct Db {
+    engine: Arc<Engine>,
 }
 
 impl Drop for Engine {
     fn drop(&mut self) {
-        let _: Result<_> = self.close();
+        if let Err(e) = self.shutdown() {
+            error!("shutdown engine on drop failed: {}", e);
+        }
     }
 }
 
 impl Engine {
     pub fn open(opt: Options) -> Result<Self> {
-        check_options(&opt)?;
+        opt.validate()?;
 
         let dir_path = opt.clone().dir_path;
         if !dir_path.exists() {
@@ -55,30 +113,124 @@ impl Engine {
                 warn!("create database directory failed, error: {}", e);
                 Errors::FailToCreateDatabaseDirectory
             })?;
+            if let Some(parent) = dir_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                sync_dir(parent, &opt.io_type)?;
+            }
         }
 
-        let mut data_files = load_datafiles(&dir_path)?;
-        let fids = data_files.iter().map(|f| f.file_id()).collect();
-        let active_file = data_files.pop().ok_or(Errors::DataFileNotFound)?;
+        let dir_lock = lock_file(&dir_path.join(LOCK_FILE_NAME))?;
+
+        // options are checked before any file is touched
+        remove_options_tmp_file(&dir_path)?;
+        let persisted = read_options_file(&dir_path)?;
+        let rewrite_options = match &persisted {
+            Some(persisted) => check_persisted_options(persisted, &opt)?,
+            None => true,
+        };
+
+        // only files live in manifest are loaded, others are left by interrupted operations
+        let state = match Manifest::recover(&dir_path)? {
+            Some(state) => state,
+            None => ManifestState::scan(&dir_path)?,
+        };
+
+        // a new database has nothing to verify, otherwise the datafile which is active
+        // when database goes down may end with a torn write
+        let clean_shutdown =
+            take_clean_shutdown_marker(&dir_path, &opt.io_type)? || state.data_files.is_empty();
+        let tail_fid = match clean_shutdown {
+            true => None,
+            false => {
+                info!("database is not shut down cleanly, verify the last datafile");
+                state
+                    .data_files
+                    .last_key_value()
+                    .filter(|(_, sealed)| !**sealed)
+                    .map(|(fid, _)| *fid)
+            }
+        };
+        Manifest::remove_orphan_files(&dir_path, &state, &opt.io_type)?;
+
+        // every file must belong to the same database
+        let mut db_id = None;
+        let mut old_blob_files = load_blob_files(&dir_path, &state, &opt, &mut db_id)?;
+        let mut data_files = load_datafiles(&dir_path, &state, &opt, &mut db_id)?;
+        // a database without OPTIONS file is written before size limits exist,
+        // its records are checked against the limits before they are recorded
+        if persisted.is_none() {
+            data_files.iter_mut().for_each(DataFile::clear_size_limits);
+            old_blob_files
+                .values_mut()
+                .for_each(DataFile::clear_size_limits);
+            check_unrecorded_options(data_files.iter().chain(old_blob_files.values()), &opt)?;
+        }
+        let db_id = db_id.ok_or(Errors::InvalidFileHeader)?;
+        let fids: Vec<_> = data_files.iter().map(|f| f.file_id()).collect();
+
+        // a snapshot of loaded files replaces the manifest, blobs are written
+        // to a new blob file after open, so all blob files are sealed
+        let state = ManifestState {
+            data_files: fids
+                .iter()
+                .map(|&fid| (fid, Some(&fid) != fids.last()))
+                .collect(),
+            blob_files: old_blob_files.keys().map(|&fid| (fid, true)).collect(),
+            ..Default::default()
+        };
+        let manifest = Manifest::create(&dir_path, &state, &opt.io_type)?;
+        let mut active_file = data_files.pop().ok_or(Errors::DataFileNotFound)?;
+        active_file.set_write_buffer_size(opt.write_buffer_size);
         let old_files = data_files
             .into_iter()
-            .map(|f| (f.file_id(), f))
-            .collect::<HashMap<_, _>>();
+            .map(|f| (f.file_id(), Arc::new(f)))
+            .collect::<SealedFiles>();
 
         let indexer = Box::new(new_indexer(opt.index_type.clone()));
 
         let mut engine = Engine {
             options: Arc::new(opt),
+            db_id,
             active_file: Arc::new(RwLock::new(active_file)),
             indexer,
-            old_files: Arc::new(RwLock::new(old_files)),
+            old_files: Arc::new(ArcSwap::from_pointee(old_files)),
+            active_blob_file: Default::default(),
+            old_blob_files: Arc::new(RwLock::new(old_blob_files)),
+            blob_merge_lock: Default::default(),
+            blob_readers: Default::default(),
+            manifest: Mutex::new(manifest),
             file_ids: fids,
             batch_commit_lock: Default::default(),
-            batch_prefix: generate_nano_timestamp_prefix()?, // TODO: make it generated from a distributed system
+            index_lock: Default::default(),
+            key_locks: (0..KEY_LOCK_STRIPES).map(|_| Default::default()).collect(),
+            batch_id: NON_BATCH_ID,
             batch_commit_id: Arc::new(AtomicUsize::new(1)), // TODO: create a persistent sequence id, we can retrieve it when we replay batches
+            scrub_state: Default::default(),
+            scrubber: Default::default(),
+            recovery_report: Default::default(),
+            clean_shutdown,
+            // an engine which fails to open is dropped without shutdown
+            closed: AtomicBool::new(true),
+            poisoned: Default::default(),
+            dir_lock,
         };
-        engine.load_index_from_data_files()?;
+        engine.load_index_from_data_files(tail_fid)?;
+        if rewrite_options {
+            write_options_file(
+                &dir_path,
+                &PersistedOptions::new(&engine.options),
+                &engine.options.io_type,
+            )?;
+        }
 
+        if let Some(scrub_options) = engine.options.scrub.clone() {
+            *engine.scrubber.lock() = Some(spawn_scrubber(
+                engine.old_files.clone(),
+                engine.scrub_state.clone(),
+                scrub_options,
+            ));
+        }
+
+        engine.closed.store(false, Ordering::Release);
         Ok(engine)
     }
 
@@ -86,13 +238,19 @@ impl Engine {
         if key.is_empty() {
             return Err(Errors::EmptyKey);
         }
+        self.check_size(&key, &value)?;
 
         let record = LogRecord {
-            key: log_record_key_with_sequence(&key, NON_TXN_PREFIX, NON_BATCH_COMMIT_ID)?,
+            key: key.to_vec(),
             value: value.to_vec(),
             record_type: LogRecordType::Normal,
+            batch_id: NON_BATCH_ID,
+            seq_id: NON_BATCH_COMMIT_ID,
         };
+        let record = self.separate_value(&key, record)?;
 
+        let _key_locks = self.lock_keys([key.as_ref()]);
+        let _index_lock = self.index_lock.read();
         let record_pos = self.append_log_record(&record)?;
 
         match self.indexer.put(key.to_vec(), record_pos) {
@@ -102,43 +260,210 @@ impl Engine {
     }
 
     pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    /// get value of @key, checksum is not verified when @options disables it
+    pub fn get_with_options(&self, key: Bytes, options: &ReadOptions) -> Result<Bytes> {
         if key.is_empty() {
             return Err(Errors::EmptyKey);
         }
 
+        let _blob_pin = BlobPin::new(self);
         let record_pos = match self.indexer.get(key.to_vec()) {
             Some(record) => Ok(record),
             None => Err(Errors::KeyNotFound),
         }?;
 
-        self.get_by_position(&record_pos)
+        self.get_by_position(&record_pos, options)
     }
 
-    pub(crate) fn get_by_position(&self, pos: &LogRecordPos) -> Result<Bytes> {
-        let mut active_file = self.active_file.read();
-        let old_files = self.old_files.read();
-        let hint_file = match active_file.file_id() == pos.file_id {
-            true => {
-                drop(old_files);
-                active_file.borrow_mut()
-            }
-            false => {
-                drop(active_file);
-                old_files
-                    .get(pos.file_id.borrow())
-                    .ok_or(Errors::DataFileNotFound)?
+    pub(crate) fn get_by_position(
+        &self,
+        pos: &LogRecordPos,
+        options: &ReadOptions,
+    ) -> Result<Bytes> {
+        self.value_at_position(pos, options)?
+            .ok_or(Errors::KeyNotFound)
+    }
+
+    /// read value of record at @pos, returns `None` for a tombstone
+    pub(crate) fn value_at_position(
+        &self,
+        pos: &LogRecordPos,
+        options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
+        self.scrub_state.check(pos)?;
+        let record = self.with_datafile(pos.file_id, |data_file| {
+            Ok(data_file.read_log_record_with(pos.offset, options)?.record)
+        })?;
+        self.resolve_value(record, options)
+    }
+
+    /// read at most @len bytes of value of @key from @offset, only the requested
+    /// part is read from disk, less bytes are returned when range exceeds the value
+    pub fn get_range(&self, key: Bytes, offset: u64, len: u64) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::EmptyKey);
+        }
+
+        let _blob_pin = BlobPin::new(self);
+        let pos = self.indexer.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
+        self.scrub_state.check(&pos)?;
+        let (record_type, value) = self.with_datafile(pos.file_id, |data_file| {
+            data_file.read_value_range(pos.offset, offset, len)
+        })?;
+        match record_type {
+            LogRecordType::Deleted => Err(Errors::KeyNotFound),
+            LogRecordType::BlobPointer | LogRecordType::ChunkList => {
+                self.resolve_range(self.read_record_at(&pos)?, offset, len)
             }
+            _ => Ok(value.into()),
+        }
+    }
+
+    /// read the raw record at @pos of a datafile
+    pub(crate) fn read_record_at(&self, pos: &LogRecordPos) -> Result<LogRecord> {
+        self.with_datafile(pos.file_id, |data_file| {
+            Ok(data_file.read_log_record(pos.offset)?.record)
+        })
+    }
+
+    /// run @f with active or old datafile of @file_id, only the active file is locked
+    fn with_datafile<T>(&self, file_id: u32, f: impl FnOnce(&DataFile) -> Result<T>) -> Result<T> {
+        self.check_open()?;
+        if let Some(data_file) = self.old_files.load().get(&file_id) {
+            return f(data_file);
+        }
+
+        let active_file = self.active_file.read();
+        if active_file.file_id() == file_id {
+            return f(&active_file);
+        }
+        drop(active_file);
+
+        // the file is sealed after the snapshot is loaded
+        let old_files = self.old_files.load();
+        f(old_files.get(&file_id).ok_or(Errors::DataFileNotFound)?)
+    }
+
+    /// get values of several keys at once, reads to a same datafile are submitted
+    /// together, a key which does not exist gets `None`
+    pub fn multi_get(&self, keys: &[Bytes]) -> Result<Vec<Option<Bytes>>> {
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err(Errors::EmptyKey);
+        }
+
+        let _blob_pin = BlobPin::new(self);
+        let found = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.indexer.get(key.to_vec()).map(|pos| (i, pos)))
+            .collect::<Vec<_>>();
+        let positions = found.iter().map(|(_, pos)| *pos).collect::<Vec<_>>();
+
+        let mut values = vec![None; keys.len()];
+        let values_found = self.get_by_positions(&positions, &ReadOptions::default())?;
+        for ((i, _), value) in found.into_iter().zip(values_found) {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    /// read values of several positions, positions in a same datafile are read as a batch
+    /// returns `None` for a tombstone record
+    pub(crate) fn get_by_positions(
+        &self,
+        positions: &[LogRecordPos],
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<Bytes>>> {
+        self.check_open()?;
+        let mut file_positions: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, pos) in positions.iter().enumerate() {
+            self.scrub_state.check(pos)?;
+            file_positions.entry(pos.file_id).or_default().push(i);
+        }
+
+        let mut records = Vec::with_capacity(positions.len());
+        let mut read_records = |data_file: &DataFile, ids: Vec<usize>| -> Result<()> {
+            let offsets = ids.iter().map(|&i| positions[i].offset).collect::<Vec<_>>();
+            records.extend(
+                ids.into_iter()
+                    .zip(data_file.read_log_records(&offsets, options)?),
+            );
+            Ok(())
         };
 
-        let record = hint_file.read_log_record(pos.offset)?;
-        if record.record.record_type == LogRecordType::Deleted {
-            Err(Errors::KeyNotFound)
-        } else {
-            Ok(record.record.value.into())
+        // sealed files are read without lock, others are in the active file
+        let old_files = self.old_files.load();
+        let mut unsealed = Vec::new();
+        for (file_id, ids) in file_positions {
+            match old_files.get(&file_id) {
+                Some(data_file) => read_records(data_file, ids)?,
+                None => unsealed.push((file_id, ids)),
+            }
         }
+        drop(old_files);
+        if !unsealed.is_empty() {
+            let active_file = self.active_file.read();
+            for (file_id, ids) in unsealed {
+                match active_file.file_id() == file_id {
+                    true => read_records(&active_file, ids)?,
+                    // the file is sealed after the snapshot is loaded
+                    false => read_records(
+                        self.old_files
+                            .load()
+                            .get(&file_id)
+                            .ok_or(Errors::DataFileNotFound)?,
+                        ids,
+                    )?,
+                }
+            }
+        }
+
+        // blob pointers are resolved after datafile locks are released
+        let mut values = vec![None; positions.len()];
+        for (i, record) in records {
+            values[i] = self.resolve_value(record.record, options)?;
+        }
+        Ok(values)
     }
 
-    // pub fn get(&self, key: &Bytes) -> Result<Bytes> {}
+    /// lock stripes of @keys, a writer holds them from appending records of the keys
+    /// until the index is updated, so the index keeps the order of records in files,
+    /// stripes are locked in order, so writers of several keys never deadlock
+    pub(crate) fn lock_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes = keys
+            .into_iter()
+            .map(|key| xxh3_64(key) as usize % KEY_LOCK_STRIPES)
+            .collect::<Vec<_>>();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.key_locks[stripe].lock())
+            .collect()
+    }
+
+    /// reject @key and @value exceeding size limits of options
+    pub(crate) fn check_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > self.options.max_key_size {
+            return Err(Errors::KeyTooLarge {
+                size: key.len(),
+                max: self.options.max_key_size,
+            });
+        }
+        if value.len() > self.options.max_value_size {
+            return Err(Errors::ValueTooLarge {
+                size: value.len(),
+                max: self.options.max_value_size,
+            });
+        }
+        Ok(())
+    }
 
     /// this function is for new log append to a active file.
     /// if current active file is reached threshold, then create a new one and put current file
@@ -151,23 +476,55 @@ impl Engine {
     ///
     /// This function will return an error if active file sync, create or write failure.
     pub(crate) fn append_log_record(&self, record: &LogRecord) -> Result<LogRecordPos> {
-        let encode_log = record.encode();
+        let encode_log = record.encode_with(
+            self.options.compression.codec(),
+            self.options.encryption.as_deref(),
+            &self.db_id,
+            self.options.checksum,
+        )?;
+        // a record never spans files, one larger than a datafile is rejected,
+        // values are moved to blob files before, so it only happens with huge keys
+        let max_size = self
+            .options
+            .datafile_size
+            .saturating_sub(FILE_HEADER_SIZE as u64);
+        if encode_log.len() as u64 > max_size {
+            return Err(Errors::RecordTooLarge {
+                size: encode_log.len() as u64,
+                max: max_size,
+            });
+        }
         let mut active_file = self.active_file.write();
+        // shutdown holds the lock while it syncs and marks a clean shutdown
+        self.check_writable()?;
         if active_file.get_offset() + encode_log.len() as u64 > self.options.datafile_size {
-            active_file.sync()?;
+            // blobs referred by the sealed file must be durable before it
+            self.sync_blob()?;
+            self.poison_on_sync_failure(active_file.sync())?;
             // let prev_active_file =
             //     DataFile::new(self.options.dir_path.clone(), active_file.file_id())?;
-            let mut old_files = self.old_files.write();
-            let mut tmp_active_file =
-                DataFile::new(self.options.dir_path.borrow(), active_file.file_id() + 1)?;
+            let mut tmp_active_file = self.poison_on_sync_failure(DataFile::new_active(
+                self.options.dir_path.borrow(),
+                active_file.file_id() + 1,
+                &self.options,
+                self.db_id,
+            ))?;
+            self.poison_on_sync_failure(self.manifest.lock().log(&[
+                ManifestEdit::SealData(active_file.file_id()),
+                ManifestEdit::NewData(tmp_active_file.file_id()),
+            ]))?;
             std::mem::swap(&mut *active_file, &mut tmp_active_file);
-            old_files.insert(tmp_active_file.file_id(), tmp_active_file);
+            // rotation holds the lock of active file, so no other snapshot
+            // is stored between load and store
+            let mut old_files = SealedFiles::clone(&self.old_files.load());
+            old_files.insert(tmp_active_file.file_id(), Arc::new(tmp_active_file));
+            self.old_files.store(Arc::new(old_files));
         }
         let offset = active_file.get_offset();
-        active_file.write(&encode_log)?;
-
         if self.options.sync_in_write {
-            active_file.sync()?;
+            self.poison_on_sync_failure(active_file.write_and_sync(&encode_log))?;
+        } else {
+            active_file.write(&encode_log)?;
         }
 
         Ok(LogRecordPos {
@@ -176,20 +533,32 @@ impl Engine {
         })
     }
 
-    fn load_index_from_data_files(&mut self) -> Result<()> {
+    /// replay datafiles into index, in strict mode, a corrupted record at the end of
+    /// datafile @tail_fid is taken as a torn write and it is erased
+    fn load_index_from_data_files(&mut self, tail_fid: Option<u32>) -> Result<()> {
         if self.file_ids.is_empty() {
             return Ok(());
         }
 
         let mut active_file = self.active_file.write();
-        let old_files = self.old_files.read();
+        // snapshot is taken out while opening, so sealed files can be erased in it
+        let mut old_files = self.old_files.swap(Default::default());
 
         // batch replay commit into index's order is guaranteed by commit (txn-fin) record,
         // so we don't need to use a ordered map here
         let mut commit_tasks: HashMap<_, Vec<_>> = HashMap::new();
+        let mut max_batch_id = NON_BATCH_ID;
+        let mut report = RecoveryReport::default();
 
         for (i, fid) in self.file_ids.iter().enumerate() {
-            let mut offset: u64 = 0;
+            let mut offset = match *fid == active_file.file_id() {
+                true => active_file.records_offset(),
+                false => old_files
+                    .get(fid)
+                    .ok_or(Errors::FailToReadDatabaseDirectory)?
+                    .records_offset(),
+            };
+            let mut torn = false;
             loop {
                 let data_file = if *fid == active_file.file_id() {
                     &*active_file
@@ -199,65 +568,101 @@ impl Engine {
                         .ok_or(Errors::FailToReadDatabaseDirectory)?
                 };
 
-                let (log_record, size) = match data_file.read_log_record(offset) {
-                    Ok(res) => Ok((res.record, res.size)),
-                    Err(e) => {
-                        if e == Errors::ReadEOF {
-                            break;
-                        };
+                let read = match self.options.recovery_mode {
+                    RecoveryMode::Strict => match data_file.read_log_record(offset) {
+                        Ok(res) => res,
+                        Err(Errors::ReadEOF) => break,
+                        // a torn write is the last one, no valid record follows it
                         Err(e)
+                            if e.is_corruption()
+                                && tail_fid == Some(*fid)
+                                && data_file.find_next_record(offset + 1)?.is_none() =>
+                        {
+                            torn = true;
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    // end of a file with trailing corruption stays at corruption start,
+                    // so the active file overwrites it
+                    RecoveryMode::Salvage => {
+                        match salvage_log_record(data_file, offset, &mut report)? {
+                            Some((next, res)) => {
+                                offset = next;
+                                res
+                            }
+                            None => break,
+                        }
                     }
-                }?;
-                let key = log_record_key_parse(&log_record.key)?;
+                };
+                let (log_record, size) = (read.record, read.size);
                 let pos = LogRecordPos {
                     file_id: *fid,
                     offset,
                 };
                 debug!(
-                    "load key: {:?}, pos: {:?}, type: {:?}",
-                    key, pos, log_record.record_type
+                    "load key: {:?}, batch: {}-{}, pos: {:?}, type: {:?}",
+                    std::str::from_utf8(&log_record.key),
+                    log_record.batch_id,
+                    log_record.seq_id,
+                    pos,
+                    log_record.record_type
                 );
+                max_batch_id = max_batch_id.max(log_record.batch_id);
+                let batch = (log_record.batch_id, log_record.seq_id);
+                let key = log_record.key;
                 match log_record.record_type {
                     // TODO: update data loading for batch commit
-                    LogRecordType::Normal => {
-                        if key.seq_id == NON_BATCH_COMMIT_ID {
-                            if self.indexer.put(key.key, pos) {
+                    LogRecordType::Normal
+                    | LogRecordType::BlobPointer
+                    | LogRecordType::ChunkList => {
+                        if log_record.seq_id == NON_BATCH_COMMIT_ID {
+                            if self.indexer.put(key, pos) {
                                 Ok(())
                             } else {
                                 Err(Errors::FailToUpdateIndex)
                             }
                         } else {
-                            debug!("push commit add key: {:?}", std::str::from_utf8(&key.key));
-                            commit_tasks
-                                .entry((key.prefix, key.seq_id))
-                                .or_default()
-                                .push((key.key, pos, LogRecordType::Normal));
+                            debug!("push commit add key: {:?}", std::str::from_utf8(&key));
+                            commit_tasks.entry(batch).or_default().push((
+                                key,
+                                pos,
+                                LogRecordType::Normal,
+                            ));
                             Ok(())
                         }
                     }
                     LogRecordType::Deleted => {
-                        if key.seq_id == NON_BATCH_COMMIT_ID {
-                            if self.indexer.delete(key.key) {
+                        if log_record.seq_id == NON_BATCH_COMMIT_ID {
+                            if self.indexer.delete(key) {
                                 Ok(())
                             } else {
                                 Err(Errors::FailToUpdateIndex)
                             }
                         } else {
-                            debug!(
-                                "push commit delete key: {:?}",
-                                std::str::from_utf8(&key.key)
-                            );
-                            commit_tasks
-                                .entry((key.prefix, key.seq_id))
-                                .or_default()
-                                .push((key.key, pos, LogRecordType::Deleted));
+                            debug!("push commit delete key: {:?}", std::str::from_utf8(&key));
+                            commit_tasks.entry(batch).or_default().push((
+                                key,
+                                pos,
+                                LogRecordType::Deleted,
+                            ));
                             Ok(())
                         }
                     }
+                    // all records of a batch may have been skipped by salvage recovery
+                    LogRecordType::BatchCommit
+                        if !commit_tasks.contains_key(&batch)
+                            && self.options.recovery_mode == RecoveryMode::Salvage =>
+                    {
+                        Ok(())
+                    }
                     LogRecordType::BatchCommit => {
                         commit_tasks
-                            .remove(&(key.prefix, key.seq_id))
-                            .ok_or(Errors::DatabaseFileCorrupted)
+                            .remove(&batch)
+                            .ok_or(Errors::CorruptedRecord {
+                                file_id: pos.file_id,
+                                offset: pos.offset,
+                            })
                             .and_then(|task| {
                                 // TODO: optimize this task for add and remove same key
                                 task.iter()
@@ -279,7 +684,9 @@ impl Engine {
                                                 Ok(())
                                             }
                                         }
-                                        LogRecordType::BatchCommit => unreachable!(),
+                                        LogRecordType::BatchCommit
+                                        | LogRecordType::BlobPointer
+                                        | LogRecordType::ChunkList => unreachable!(),
                                     })
                             })
                     }
@@ -287,11 +694,36 @@ impl Engine {
                 offset += size;
             }
 
+            if torn {
+                let data_file = match *fid == active_file.file_id() {
+                    true => &mut *active_file,
+                    false => Arc::get_mut(&mut old_files)
+                        .and_then(|old_files| old_files.get_mut(fid))
+                        .and_then(Arc::get_mut)
+                        .ok_or(Errors::FailToReadDatabaseDirectory)?,
+                };
+                let end = offset + data_file.erase_tail(offset)?;
+                warn!(
+                    "erase torn write of datafile {}, range: {}..{}",
+                    fid, offset, end
+                );
+                report.torn_tail = Some(SkippedRange {
+                    file_id: *fid,
+                    start: offset,
+                    end,
+                    records: 1,
+                });
+            }
+
             if i == self.file_ids.len() - 1 {
-                active_file.set_offset(offset);
+                active_file.set_offset(offset)?;
             }
         }
 
+        // batches of this session never mix with uncommitted ones in files
+        self.batch_id = max_batch_id + 1;
+        self.recovery_report = report;
+        self.old_files.store(old_files);
         Ok(())
     }
 
@@ -299,14 +731,20 @@ impl Engine {
         if key.is_empty() {
             return Err(Errors::EmptyKey);
         }
+        self.check_size(&key, &[])?;
+        self.check_writable()?;
 
+        let _key_locks = self.lock_keys([key.as_ref()]);
         match self.indexer.get(key.to_vec()) {
             Some(_) => {
                 let record = LogRecord {
-                    key: log_record_key_with_sequence(&key, NON_TXN_PREFIX, NON_BATCH_COMMIT_ID)?,
+                    key: key.to_vec(),
                     value: Default::default(),
                     record_type: LogRecordType::Deleted,
+                    batch_id: NON_BATCH_ID,
+                    seq_id: NON_BATCH_COMMIT_ID,
                 };
+                let _index_lock = self.index_lock.read();
                 self.append_log_record(&record).map(|_| ())?;
                 match self.indexer.delete(key.to_vec()) {
                     true => Ok(()),
@@ -320,16 +758,92 @@ impl Engine {
         }
     }
 
-    pub fn close(&self) -> Result<()> {
-        self.active_file.read().sync()
+    /// shut down engine and release its resources, all files are flushed and synced,
+    /// background threads are stopped and database directory is unlocked, the database
+    /// is marked as shut down cleanly only when all of them succeed
+    pub fn close(self) -> Result<()> {
+        self.shutdown()
+    }
+
+    /// shut down engine once, reads and writes fail with `EngineClosed` afterwards,
+    /// a running merge is waited for, it is a no-op for a closed engine
+    pub(crate) fn shutdown(&self) -> Result<()> {
+        self.stop_scrubber();
+        let _merge_lock = self.blob_merge_lock.lock();
+        // no write lands between sync and the marker
+        let mut active_file = self.active_file.write();
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        // a poisoned database is left dirty, its synced data is unknown
+        let res = self
+            .check_poisoned()
+            .and_then(|_| self.sync_blob())
+            .and_then(|_| self.poison_on_sync_failure(active_file.sync()))
+            .and_then(|_| put_clean_shutdown_marker(&self.options.dir_path, &self.options.io_type));
+        // directory is unlocked even if sync fails, the database is dirty then
+        res.and(unlock_file(&self.dir_lock))
     }
 
+    /// drop engine without shutdown, as if the process is killed, nothing is synced
+    /// and the database is not marked as shut down cleanly
+    #[cfg(test)]
+    pub(crate) fn kill(self) {
+        self.stop_scrubber();
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// fail with `EngineClosed` when engine is shut down
+    pub(crate) fn check_open(&self) -> Result<()> {
+        match self.closed.load(Ordering::Acquire) {
+            true => Err(Errors::EngineClosed),
+            false => Ok(()),
+        }
+    }
+
+    /// fail with `EngineClosed` when engine is shut down, or with the failed
+    /// sync which poisons it, see `poison_on_sync_failure`
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        self.check_open()?;
+        self.check_poisoned()
+    }
+
+    fn check_poisoned(&self) -> Result<()> {
+        match self.poisoned.lock().as_ref() {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// a failed sync may have dropped dirty pages, a later sync which succeeds proves
+    /// nothing about them, so the engine is poisoned by it, every write and sync
+    /// fails with it until the database is reopened and recovered
+    pub(crate) fn poison_on_sync_failure<T>(&self, res: Result<T>) -> Result<T> {
+        if let Err(e @ Errors::FailToSyncDataFile(_)) = &res {
+            self.poisoned.lock().get_or_insert_with(|| e.clone());
+        }
+        res
+    }
+
+    /// flush buffered writes of active file and sync it to disk,
+    /// blobs are synced first so no synced record points to a lost blob
     pub fn sync(&self) -> Result<()> {
-        self.active_file.read().sync()
+        self.check_writable()?;
+        self.sync_blob()?;
+        self.poison_on_sync_failure(self.active_file.write().sync())
     }
 
-    pub fn list_keys(&self) -> Vec<Bytes> {
-        self.indexer.list_keys()
+    /// flush buffered writes of active file to os without syncing it,
+    /// they are readable by other processes but may be lost on power failure
+    pub fn flush(&self) -> Result<()> {
+        self.check_writable()?;
+        self.active_file.write().flush()
+    }
+
+    pub fn list_keys(&self) -> Result<Vec<Bytes>> {
+        self.check_open()?;
+        Ok(self.indexer.list_keys())
     }
 
     pub fn fold<F>(&self, mut f: F) -> Result<()>
@@ -337,8 +851,9 @@ impl Engine {
         Self: Sized,
         F: FnMut(Bytes, Bytes) -> bool,
     {
+        self.check_open()?;
         let iterator = self.iterator(Default::default());
-        while let Ok(Some((key, value))) = iterator.next() {
+        while let Some((key, value)) = iterator.next()? {
             if !f(key, value) {
                 return Ok(());
             }
@@ -347,20 +862,131 @@ impl Engine {
     }
 }
 
-fn check_options(option: &Options) -> Result<()> {
-    option
-        .dir_path
-        .to_str()
-        .ok_or(Errors::InvalidDatabasePath)?;
+impl Db {
+    pub fn open(opt: Options) -> Result<Self> {
+        Engine::open(opt).map(Self::from)
+    }
+
+    /// shut down engine shared by all handles, operations of other handles fail
+    /// with `EngineClosed` afterwards, see `Engine::close`
+    pub fn close(&self) -> Result<()> {
+        self.engine.shutdown()
+    }
+
+    pub(crate) fn engine(&self) -> Arc<Engine> {
+        self.engine.clone()
+    }
+}
+
+impl From<Engine> for Db {
+    fn from(engine: Engine) -> Self {
+        Self {
+            engine: Arc::new(engine),
+        }
+    }
+}
+
+impl Deref for Db {
+    type Target = Engine;
+
+    fn deref(&self) -> &Engine {
+        &self.engine
+    }
+}
+
+fn load_datafiles(
+    directory_path: &Path,
+    state: &ManifestState,
+    options: &Options,
+    db_id: &mut Option<Uuid>,
+) -> Result<Vec<DataFile>> {
+    let mut data_files = Vec::new();
+    let mut header_lost = false;
+    for (i, &fid) in state.data_files.keys().enumerate() {
+        if !Path::new(&generate_datafile_name(directory_path, fid)).exists() {
+            warn!("datafile {} in manifest is missing", fid);
+            return Err(Errors::DataFileNotFound);
+        }
+        let mut df = DataFile::new(directory_path, fid, &options.io_type)?;
+        df.set_record_options(options);
+        if check_file_header(&mut df, db_id)?.is_none() {
+            // only the last file may crash right after it is created
+            if i + 1 != state.data_files.len() {
+                warn!("header of datafile {} is missing", fid);
+                return Err(Errors::InvalidFileHeader);
+            }
+            header_lost = true;
+        }
+        data_files.push(df);
+    }
+    let last_sealed = state
+        .data_files
+        .last_key_value()
+        .is_some_and(|(_, &sealed)| sealed);
 
-    if option.datafile_size == 0 {
-        return Err(Errors::DatafileSizeTooSmall);
+    let db_id = *db_id.get_or_insert_with(Uuid::new_v4);
+    if data_files.is_empty() {
+        info!("no datafile in directory, create a new one");
+        let df = DataFile::new_active(directory_path, INITAIL_FILE_ID, options, db_id)?;
+        data_files.push(df);
+    } else if header_lost {
+        info!("rewrite lost header of the last datafile");
+        let df = data_files.last_mut().unwrap();
+        df.write_header(&FileHeader::new(db_id, options.checksum))?;
+        df.sync()?;
+    } else if last_sealed
+        || data_files.last().unwrap().format_version() < FORMAT_VERSION
+        || data_files.last().unwrap().checksum() != options.checksum
+    {
+        // records are never appended to a sealed file, or a file of another
+        // format or checksum algorithm, write to a new file instead
+        let fid = data_files.last().unwrap().file_id() + 1;
+        info!("seal the last datafile, create datafile {}", fid);
+        let df = DataFile::new_active(directory_path, fid, options, db_id)?;
+        data_files.push(df);
     }
 
-    Ok(())
+    Ok(data_files)
 }
 
-fn load_datafiles(directory_path: &Path) -> Result<Vec<DataFile>> {
+/// read and validate header of @data_file, it must belong to database @db_id,
+/// which is set by the first file checked, records of the file are decoded in
+/// format version and checksum algorithm of header
+pub(crate) fn check_file_header(
+    data_file: &mut DataFile,
+    db_id: &mut Option<Uuid>,
+) -> Result<Option<FileHeader>> {
+    let header = match data_file.read_header()? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    // legacy files have no database id, they take the id of other files
+    if header.version == 0 {
+        data_file.set_header(&header);
+        return Ok(Some(header));
+    }
+    if *db_id.get_or_insert(header.db_id) != header.db_id {
+        warn!(
+            "file {} belongs to database {}, not {:?}",
+            data_file.file_id(),
+            header.db_id,
+            db_id
+        );
+        return Err(Errors::ForeignDataFile);
+    }
+    data_file.set_header(&header);
+    Ok(Some(header))
+}
+
+/// id of file named like 000000001.bcdata, `None` if @name is not such a name of @suffix
+pub(crate) fn parse_file_id(name: &str, suffix: &str) -> Option<u32> {
+    name.strip_suffix(suffix)
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|id| id.parse().ok())
+}
+
+/// sorted ids of files in @directory_path named by `parse_file_id` with @suffix
+pub(crate) fn list_file_ids(directory_path: &Path, suffix: &str) -> Result<Vec<u32>> {
     let dir = directory_path.read_dir().map_err(|e| {
         warn!(
             "Error reading directory: {}, error: {}",
@@ -371,7 +997,6 @@ fn load_datafiles(directory_path: &Path) -> Result<Vec<DataFile>> {
     })?;
 
     let mut file_ids = Vec::new();
-    let mut data_files = Vec::new();
     for entry in dir {
         let entry = entry.map_err(|e| {
             warn!(
@@ -385,42 +1010,15 @@ fn load_datafiles(directory_path: &Path) -> Result<Vec<DataFile>> {
         let name = entry.file_name();
         let filename = name.to_str().ok_or(Errors::FailToReadDatabaseDirectory)?;
 
-        // filename is like 00000.bcdata
-        if filename.ends_with(DATAFILE_NAME_SUFFIX) {
-            let split_names: Vec<&str> = filename.split(DATAFILE_SEPARATOR).collect();
-            let file_id = split_names[0].parse::<u32>().map_err(|e| {
-                warn!("database directory may be corrupted: {}", e);
-                Errors::DatabaseFileCorrupted
-            })?;
-            file_ids.push(file_id);
+        if filename.ends_with(suffix) {
+            match parse_file_id(filename, suffix) {
+                Some(file_id) => file_ids.push(file_id),
+                // e.g. a temp file, it is removed as an orphan
+                None => warn!("skip file {} with an invalid name", filename),
+            }
         }
     }
 
     file_ids.sort();
-
-    for fid in file_ids.iter() {
-        let df = DataFile::new(directory_path, *fid)?;
-        data_files.push(df);
-    }
-
-    if data_files.is_empty() {
-        info!("no datafile in directory, create a new one");
-        let df = DataFile::new(directory_path, INITAIL_FILE_ID)?;
-        data_files.push(df);
-    }
-
-    Ok(data_files)
-}
-
-// fn load_index(files: &vec![DataFile]) -> Indexer {}
-
-fn generate_nano_timestamp_prefix() -> Result<Vec<u8>> {
-    let start = SystemTime::now();
-    let since_epoch = start.duration_since(UNIX_EPOCH).map_err(|err| {
-        error!("generate nano timestamp prefix failed: {}", err);
-        Errors::InitializeFailed
-    })?;
-    let nanos = since_epoch.as_nanos() as i64;
-    let bytes = nanos.to_le_bytes();
-    Ok(bytes.into())
+    Ok(file_ids)
 }
@@ -1,23 +1,34 @@
+use std::{sync::mpsc, thread, time::Duration};
+
 use bytes::Bytes;
 use tempfile::Builder;
 
 use crate::{
-    db::Engine,
+    data::{
+        file_header::{FileHeader, FILE_HEADER_SIZE, FORMAT_VERSION},
+        log_record::{LogRecord, LogRecordType},
+    },
+    db::{Db, Engine},
     error::Errors,
-    options::Options,
+    options::{
+        ChecksumType, Compression, IndexIteratorOptions, IndexType, Options, ReadOptions,
+        WriteBatchOptions,
+    },
     utils::rand_kv::{get_test_key, get_test_value},
 };
 
 #[test]
 fn test_engine_put() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
     assert!(engine.put(get_test_key(100), get_test_value(100)).is_ok());
@@ -60,14 +71,16 @@ fn test_engine_put() {
 
 #[test]
 fn test_engine_get() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
@@ -144,14 +157,16 @@ fn test_engine_get() {
 
 #[test]
 fn test_engine_delete() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
@@ -199,59 +214,63 @@ fn test_engine_delete() {
 
 #[test]
 fn test_list_keys_add_and_delete() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
-    assert_eq!(engine.list_keys(), Vec::<Bytes>::default());
+    assert_eq!(engine.list_keys(), Ok(Vec::<Bytes>::default()));
 
     assert_eq!(engine.put("key0".into(), "value0".into()), Ok(()));
-    assert_eq!(engine.list_keys(), vec![Bytes::from("key0")]);
+    assert_eq!(engine.list_keys(), Ok(vec![Bytes::from("key0")]));
 
     assert_eq!(engine.put("key1".into(), "value1".into()), Ok(()));
     assert_eq!(
         engine.list_keys(),
-        vec![Bytes::from("key0"), Bytes::from("key1")]
+        Ok(vec![Bytes::from("key0"), Bytes::from("key1")])
     );
 
     assert_eq!(engine.put("key2".into(), "value2".into()), Ok(()));
     assert_eq!(
         engine.list_keys(),
-        vec![
+        Ok(vec![
             Bytes::from("key0"),
             Bytes::from("key1"),
             Bytes::from("key2")
-        ]
+        ])
     );
 
     assert_eq!(engine.delete("key1".into()), Ok(()));
     assert_eq!(
         engine.list_keys(),
-        vec![Bytes::from("key0"), Bytes::from("key2")]
+        Ok(vec![Bytes::from("key0"), Bytes::from("key2")])
     );
 }
 
 #[test]
 fn test_list_keys_large_size() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
-    assert_eq!(engine.list_keys(), Vec::<Bytes>::default());
+    assert_eq!(engine.list_keys(), Ok(Vec::<Bytes>::default()));
 
     const SIZE: usize = 1000000;
 
@@ -261,9 +280,9 @@ fn test_list_keys_large_size() {
 
     assert_eq!(
         engine.list_keys(),
-        (0..SIZE + 1)
+        Ok((0..SIZE + 1)
             .map(|x| { get_test_key(x) })
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>())
     );
 
     for i in 0..=SIZE - 10 {
@@ -272,32 +291,28 @@ fn test_list_keys_large_size() {
 
     assert_eq!(
         engine.list_keys(),
-        (SIZE - 10 + 1..SIZE + 1)
+        Ok((SIZE - 10 + 1..SIZE + 1)
             .map(|x| { get_test_key(x) })
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>())
     );
 }
 
 #[test]
 fn test_fold_keys() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
-        .prefix("bitcast-rs")
-        .tempdir()
-        .unwrap()
-        .path()
-        .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
-    assert_eq!(
-        engine.fold(|_, _| -> bool {
-            assert!(false);
-            true
-        }),
-        Ok(())
-    );
+    assert_eq!(engine.fold(|_, _| -> bool { unreachable!() }), Ok(()));
 
     const SIZE: usize = 1000000;
 
@@ -348,44 +363,2187 @@ fn test_fold_keys() {
 
 #[test]
 fn test_close() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    const SIZE: usize = 1000000;
+
+    for i in 0..=SIZE {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+
+    assert_eq!(engine.close(), Ok(()));
+}
+
+#[test]
+fn test_sync() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    const SIZE: usize = 1000000;
+
+    for i in 0..=SIZE {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+
+    assert_eq!(engine.sync(), Ok(()));
+}
+
+#[test]
+fn test_multi_get() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.multi_get(&[]), Ok(vec![]));
+    assert_eq!(
+        engine.multi_get(&[get_test_key(0), Bytes::new()]),
+        Err(Errors::EmptyKey)
+    );
+
+    for i in 0..10000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(engine.delete(get_test_key(5)), Ok(()));
+
+    let keys = (0..10).map(|i| get_test_key(i * 999)).collect::<Vec<_>>();
+    assert_eq!(
+        engine.multi_get(&keys),
+        Ok((0..10).map(|i| Some(get_test_value(i * 999))).collect())
+    );
+
+    assert_eq!(
+        engine.multi_get(&[get_test_key(5), get_test_key(6), get_test_key(20000)]),
+        Ok(vec![None, Some(get_test_value(6)), None])
+    );
+}
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+#[test]
+fn test_engine_io_uring() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        sync_in_write: true,
+        io_type: crate::options::IOType::IoUring,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(engine.delete(get_test_key(10)), Ok(()));
+    drop(engine);
+
+    let engine = Engine::open(opts).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(10)), Err(Errors::KeyNotFound));
+    assert_eq!(engine.get(get_test_key(1999)), Ok(get_test_value(1999)));
+    let keys = (0..2000).map(get_test_key).collect::<Vec<_>>();
+    let values = engine.multi_get(&keys).expect("failed to multi get");
+    assert_eq!(values.iter().filter(|v| v.is_some()).count(), 1999);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_engine_direct_io() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        io_type: crate::options::IOType::DirectIO,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(engine.delete(get_test_key(10)), Ok(()));
+    assert_eq!(engine.get(get_test_key(1000)), Ok(get_test_value(1000)));
+    drop(engine);
+
+    // appends after reopen continue from logical end instead of zero padding
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 2000..2100 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    drop(engine);
+
+    let engine = Engine::open(opts).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(10)), Err(Errors::KeyNotFound));
+    for i in (0..2100).filter(|i| *i != 10) {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_write_buffer_flush() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        write_buffer_size: 4 * 1024,
+        preallocate_datafile: false,
+        ..Default::default()
+    };
+
+    let datafile_len = |fid: u32| {
+        std::fs::metadata(opts.dir_path.join(format!("{:09}.bcdata", fid)))
+            .map(|m| m.len())
+            .unwrap_or_default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine.put(get_test_key(0), get_test_value(0)).is_ok());
+    assert_eq!(engine.get(get_test_key(0)), Ok(get_test_value(0)));
+    // only the file header is written
+    assert_eq!(datafile_len(0), FILE_HEADER_SIZE as u64);
+
+    assert_eq!(engine.flush(), Ok(()));
+    assert!(datafile_len(0) > FILE_HEADER_SIZE as u64);
+
+    // rotation flushes the sealed file
+    for i in 1..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert!(datafile_len(0) > 60 * 1024);
+    for i in 0..2000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+    drop(engine);
+
+    // close flushes the active file
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_preallocate_datafile() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let datafile_len = |fid: u32| {
+        std::fs::metadata(opts.dir_path.join(format!("{:09}.bcdata", fid)))
+            .map(|m| m.len())
+            .unwrap_or_default()
+    };
+
+    // datafiles grow with their records by default
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(datafile_len(0), FILE_HEADER_SIZE as u64);
+    engine.close().expect("failed to close engine");
+    std::fs::remove_dir_all(&opts.dir_path).unwrap();
+
+    let mut opts = opts.clone();
+    opts.preallocate_datafile = true;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(datafile_len(0), opts.datafile_size);
+    for i in 0..100 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(datafile_len(0), opts.datafile_size);
+    drop(engine);
+
+    // replay stops at the zeroed preallocated region, later writes continue from there
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 100..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(datafile_len(1), opts.datafile_size);
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_blob_separation() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        min_blob_size: 1024,
+        ..Default::default()
+    };
+
+    let blob_files = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(".bcblob")
+            })
+            .count()
+    };
+    let large_value = |i: usize| Bytes::from(format!("{:09}", i).repeat(500));
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..100 {
+        assert!(engine.put(get_test_key(i), large_value(i)).is_ok());
+        assert!(engine.put(get_test_key(i + 100), get_test_value(i)).is_ok());
+    }
+    let mut batch = engine
+        .write_batch(&WriteBatchOptions::default())
+        .expect("failed to create write batch");
+    assert!(batch.put(&get_test_key(200), &large_value(200)).is_ok());
+    assert!(batch.commit().is_ok());
+    assert!(blob_files(&opts.dir_path) > 1);
+
+    let check = |engine: &Engine| {
+        for i in 0..100 {
+            assert_eq!(engine.get(get_test_key(i)), Ok(large_value(i)));
+            assert_eq!(engine.get(get_test_key(i + 100)), Ok(get_test_value(i)));
+        }
+        assert_eq!(engine.get(get_test_key(200)), Ok(large_value(200)));
+        assert_eq!(
+            engine.multi_get(&[get_test_key(1), get_test_key(101)]),
+            Ok(vec![Some(large_value(1)), Some(get_test_value(1))])
+        );
+        let iterator = engine.iterator(IndexIteratorOptions {
+            prefetch: 16,
+            ..Default::default()
+        });
+        let mut count = 0;
+        while let Ok(Some((_, value))) = iterator.next() {
+            assert!(!value.is_empty());
+            count += 1;
+        }
+        assert_eq!(count, 201);
+    };
+    check(&engine);
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    check(&engine);
+
+    // overwritten and deleted values are garbage, merge keeps only live ones
+    for i in 0..50 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        assert!(engine.delete(get_test_key(i + 50)).is_ok());
+    }
+    let before = blob_files(&opts.dir_path);
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    assert!(blob_files(&opts.dir_path) < before);
+    for i in 0..50 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+        assert_eq!(engine.get(get_test_key(i + 50)), Err(Errors::KeyNotFound));
+    }
+    assert_eq!(engine.get(get_test_key(200)), Ok(large_value(200)));
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..50 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+        assert_eq!(engine.get(get_test_key(i + 50)), Err(Errors::KeyNotFound));
+    }
+    assert_eq!(engine.get(get_test_key(200)), Ok(large_value(200)));
+}
+
+#[test]
+fn test_engine_merge_blobs_concurrent_put() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        min_blob_size: 100,
+        ..Default::default()
+    };
+
+    let value = |round: usize, i: usize| Bytes::from(format!("{:04}-{:04}", round, i).repeat(20));
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Acquire) {
+                assert_eq!(engine.merge_blobs(), Ok(()));
+            }
+        });
+        s.spawn(|| {
+            for round in 0..300 {
+                for i in 0..20 {
+                    assert_eq!(engine.put(get_test_key(i), value(round, i)), Ok(()));
+                }
+            }
+            done.store(true, Ordering::Release);
+        });
+    });
+
+    // a put during merge is never overwritten by a moved value, in memory or on replay
+    for i in 0..20 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(value(299, i)));
+    }
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..20 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(value(299, i)));
+    }
+}
+
+#[test]
+fn test_engine_merge_blobs_concurrent_read() {
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        min_blob_size: 100,
+        ..Default::default()
+    };
+
+    let value = |round: usize, i: usize| Bytes::from(format!("{:04}-{:04}", round, i).repeat(20));
+    // a value read during merge is one written by some round
+    let check = |i: usize, read: &[u8]| {
+        let round = std::str::from_utf8(&read[..4]).unwrap().parse().unwrap();
+        assert_eq!(read, value(round, i));
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..20 {
+        assert_eq!(engine.put(get_test_key(i), value(0, i)), Ok(()));
+    }
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Acquire) {
+                assert_eq!(engine.merge_blobs(), Ok(()));
+            }
+        });
+        for _ in 0..2 {
+            s.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    for i in 0..20 {
+                        check(i, &engine.get(get_test_key(i)).unwrap());
+                        let mut reader = engine.get_reader(get_test_key(i)).unwrap();
+                        let mut buf = Vec::new();
+                        reader.read_to_end(&mut buf).unwrap();
+                        check(i, &buf);
+                    }
+                }
+            });
+        }
+        s.spawn(|| {
+            for round in 1..300 {
+                for i in 0..20 {
+                    assert_eq!(engine.put(get_test_key(i), value(round, i)), Ok(()));
+                }
+            }
+            done.store(true, Ordering::Release);
+        });
+    });
+
+    for i in 0..20 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(value(299, i)));
+    }
+}
+
+#[test]
+fn test_engine_merge_blobs_pinned() {
+    use std::io::{Cursor, Read};
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 2 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let blob_files = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "bcblob"))
+            .collect::<std::collections::HashSet<_>>()
+    };
+    let value = (0..=255u8)
+        .cycle()
+        .take(3 * 1024 * 1024 + 100)
+        .collect::<Vec<_>>();
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        engine.put_stream(get_test_key(0), Cursor::new(&value), value.len() as u64),
+        Ok(())
+    );
+    let mut reader = engine.get_reader(get_test_key(0)).unwrap();
+    let iterator = engine.iterator(IndexIteratorOptions::default());
+
+    // the streamed value is garbage once overwritten, its sealed files are merged
+    assert_eq!(
+        engine.put_stream(get_test_key(0), Cursor::new(&value), value.len() as u64),
+        Ok(())
+    );
+    let before = blob_files(&opts.dir_path);
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    assert!(blob_files(&opts.dir_path).is_superset(&before));
+
+    // readers created before merge still see the old value
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, value);
+    let (key, read) = iterator.next().unwrap().unwrap();
+    assert_eq!((key, read), (get_test_key(0), Bytes::from(value.clone())));
+
+    // merged files are removed with the last reader
+    drop(reader);
+    assert!(blob_files(&opts.dir_path).is_superset(&before));
+    drop(iterator);
+    assert!(!blob_files(&opts.dir_path).is_superset(&before));
+    assert_eq!(engine.get(get_test_key(0)), Ok(Bytes::from(value.clone())));
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(0)), Ok(Bytes::from(value)));
+}
+
+#[test]
+fn test_engine_stream_value() {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 2 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let value = (0..=255u8)
+        .cycle()
+        .take(3 * 1024 * 1024 + 100)
+        .collect::<Vec<_>>();
+    let read_all = |engine: &Engine, key: Bytes| {
+        let mut reader = engine.get_reader(key).expect("failed to get reader");
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).expect("failed to read value");
+        buf
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        engine.put_stream(get_test_key(0), Cursor::new(&value), value.len() as u64),
+        Ok(())
+    );
+    assert_eq!(read_all(&engine, get_test_key(0)), value);
+    assert_eq!(engine.get(get_test_key(0)), Ok(Bytes::from(value.clone())));
+
+    // seek across chunk boundaries
+    let mut reader = engine.get_reader(get_test_key(0)).unwrap();
+    assert_eq!(reader.len(), value.len() as u64);
+    let mut buf = vec![0u8; 300];
+    assert_eq!(
+        reader.seek(SeekFrom::Start(1024 * 1024 - 100)).unwrap(),
+        1024 * 1024 - 100
+    );
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, value[1024 * 1024 - 100..1024 * 1024 + 200]);
+    assert_eq!(
+        reader.seek(SeekFrom::End(-50)).unwrap(),
+        value.len() as u64 - 50
+    );
+    assert_eq!(reader.read(&mut buf).unwrap(), 50);
+    assert_eq!(buf[..50], value[value.len() - 50..]);
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    assert!(reader
+        .seek(SeekFrom::Current(-(value.len() as i64) - 1))
+        .is_err());
+
+    // stream which is shorter than declared length is not stored
+    assert!(matches!(
+        engine.put_stream(get_test_key(1), Cursor::new(&value[..10]), 20),
+        Err(Errors::FailToReadStream(_))
+    ));
+    assert_eq!(engine.get(get_test_key(1)), Err(Errors::KeyNotFound));
+
+    // inline values are readable as streams too
+    assert!(engine.put(get_test_key(2), get_test_value(2)).is_ok());
+    assert_eq!(read_all(&engine, get_test_key(2)), get_test_value(2));
+    assert_eq!(
+        engine.put_stream(get_test_key(3), Cursor::new(&[]), 0),
+        Ok(())
+    );
+    assert!(engine.get_reader(get_test_key(3)).unwrap().is_empty());
+    drop(reader);
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(read_all(&engine, get_test_key(0)), value);
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    assert_eq!(read_all(&engine, get_test_key(0)), value);
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(read_all(&engine, get_test_key(0)), value);
+}
+
+#[test]
+fn test_engine_stream_value_size_limit() {
+    use std::io::{Cursor, Read};
+
+    let mut opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 2 * 1024 * 1024,
+        max_value_size: 128 * 1024,
+        ..Default::default()
+    };
+
+    // chunks are no larger than a value, so each of them is readable
+    let value = (0..=255u8)
+        .cycle()
+        .take(1024 * 1024 + 100)
+        .collect::<Vec<_>>();
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        engine.put_stream(get_test_key(0), Cursor::new(&value), value.len() as u64),
+        Ok(())
+    );
+    assert_eq!(engine.get(get_test_key(0)), Ok(Bytes::from(value.clone())));
+    let mut buf = Vec::new();
+    let mut reader = engine.get_reader(get_test_key(0)).unwrap();
+    assert_eq!(
+        reader.read_to_end(&mut buf).map_err(|_| ()),
+        Ok(value.len())
+    );
+    assert_eq!(buf, value);
+    drop(reader);
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(0)), Ok(Bytes::from(value.clone())));
+    drop(engine);
+
+    // list of chunks is a value too, it limits length of a stream
+    std::fs::remove_dir_all(&opts.dir_path).unwrap();
+    opts.max_value_size = 1024;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let max_len = 1024 / 20 * 1024;
+    assert_eq!(
+        engine.put_stream(get_test_key(1), Cursor::new(&value), max_len as u64 + 1),
+        Err(Errors::ValueTooLarge {
+            size: max_len + 1,
+            max: max_len
+        })
+    );
+    assert_eq!(
+        engine.put_stream(get_test_key(1), Cursor::new(&value), max_len as u64),
+        Ok(())
+    );
+    assert_eq!(
+        engine.get(get_test_key(1)),
+        Ok(Bytes::copy_from_slice(&value[..max_len]))
+    );
+}
+
+#[test]
+fn test_engine_get_range() {
+    use std::io::Cursor;
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 4 * 1024 * 1024,
+        min_blob_size: 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let inline = Bytes::from("0123456789");
+    let blob = Bytes::from((0..=255u8).cycle().take(4096).collect::<Vec<_>>());
+    let streamed = (0..=255u8)
+        .cycle()
+        .take(2 * 1024 * 1024 + 100)
+        .collect::<Vec<_>>();
+    assert!(engine.put(get_test_key(0), inline.clone()).is_ok());
+    assert!(engine.put(get_test_key(1), blob.clone()).is_ok());
+    assert_eq!(
+        engine.put_stream(
+            get_test_key(2),
+            Cursor::new(&streamed),
+            streamed.len() as u64
+        ),
+        Ok(())
+    );
+
+    assert_eq!(
+        engine.get_range(get_test_key(0), 2, 3),
+        Ok(inline.slice(2..5))
+    );
+    assert_eq!(
+        engine.get_range(get_test_key(0), 8, 10),
+        Ok(inline.slice(8..))
+    );
+    assert_eq!(engine.get_range(get_test_key(0), 20, 10), Ok(Bytes::new()));
+    assert_eq!(
+        engine.get_range(get_test_key(1), 1000, 100),
+        Ok(blob.slice(1000..1100))
+    );
+    let start = 1024 * 1024 - 10;
+    assert_eq!(
+        engine.get_range(get_test_key(2), start as u64, 20),
+        Ok(Bytes::copy_from_slice(&streamed[start..start + 20]))
+    );
+    assert_eq!(
+        engine.get_range(get_test_key(2), 0, u64::MAX),
+        Ok(Bytes::from(streamed.clone()))
+    );
+
+    assert!(engine.delete(get_test_key(0)).is_ok());
+    assert_eq!(
+        engine.get_range(get_test_key(0), 0, 1),
+        Err(Errors::KeyNotFound)
+    );
+    assert_eq!(
+        engine.get_range(get_test_key(3), 0, 1),
+        Err(Errors::KeyNotFound)
+    );
+}
+
+#[test]
+fn test_engine_compression() {
+    let mut opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        preallocate_datafile: false,
+        min_blob_size: 4096,
+        ..Default::default()
+    };
+
+    let json_value = |i: usize| {
+        Bytes::from(format!(
+            "{{\"id\":{},\"name\":\"bitcask\",\"tags\":[{}]}}",
+            i,
+            "\"kv\",".repeat(i % 1000)
+        ))
+    };
+    let disk_usage = |dir: &std::path::Path, suffix: &str| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_str().unwrap().ends_with(suffix))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert!(engine.put(get_test_key(i), json_value(i)).is_ok());
+    }
+    drop(engine);
+    let plain_size = disk_usage(&opts.dir_path, ".bcdata");
+    let plain_blob_size = disk_usage(&opts.dir_path, ".bcblob");
+
+    // values of new records are compressed, old ones stay readable
+    opts.compression = Compression::Snappy;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert!(engine.put(get_test_key(i + 1000), json_value(i)).is_ok());
+    }
+    assert!(disk_usage(&opts.dir_path, ".bcdata") - plain_size < plain_size / 2);
+    assert_eq!(
+        engine.get_range(get_test_key(1010), 0, 7),
+        Ok(Bytes::from("{\"id\":1"))
+    );
+
+    // blob merge recompresses values with current codec
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    assert!(disk_usage(&opts.dir_path, ".bcblob") < plain_blob_size);
+    drop(engine);
+
+    // built-in codecs are readable without configuring them
+    opts.compression = Compression::None;
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(json_value(i)));
+        assert_eq!(engine.get(get_test_key(i + 1000)), Ok(json_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_encryption() {
+    use std::sync::Arc;
+
+    use crate::data::cipher::XChaCha20Poly1305Cipher;
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        min_blob_size: 1024,
+        compression: Compression::Snappy,
+        encryption: Some(Arc::new(XChaCha20Poly1305Cipher::new(&[1; 32]))),
+        ..Default::default()
+    };
+
+    let secret_value = |i: usize| Bytes::from(format!("ssn-000-00-{:04}", i).repeat(i % 100));
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..500 {
+        assert!(engine.put(get_test_key(i), secret_value(i)).is_ok());
+    }
+    let mut batch = engine
+        .write_batch(&WriteBatchOptions::default())
+        .expect("failed to create write batch");
+    assert!(batch.put(&get_test_key(500), &secret_value(500)).is_ok());
+    assert!(batch.delete(&get_test_key(0)).is_ok());
+    assert!(batch.commit().is_ok());
+    drop(engine);
+
+    // neither keys nor values are stored in plain text
+    for entry in std::fs::read_dir(&opts.dir_path).unwrap() {
+        let content = std::fs::read(entry.unwrap().path()).unwrap();
+        assert!(!content.windows(7).any(|w| w == b"ssn-000"));
+        assert!(!content.windows(15).any(|w| w == b"bitcast-rs-test"));
+    }
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(0)), Err(Errors::KeyNotFound));
+    for i in 1..=500 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(secret_value(i)));
+    }
+    assert_eq!(engine.merge_blobs(), Ok(()));
+    assert_eq!(
+        engine.get_range(get_test_key(99), 4, 3),
+        Ok(Bytes::from("000"))
+    );
+    drop(engine);
+
+    let mut wrong_key = opts.clone();
+    wrong_key.encryption = Some(Arc::new(XChaCha20Poly1305Cipher::new(&[2; 32])));
+    assert_eq!(
+        Engine::open(wrong_key).err(),
+        Some(Errors::DecryptionFailed)
+    );
+    let mut no_key = opts.clone();
+    no_key.encryption = None;
+    assert_eq!(
+        Engine::open(no_key).err(),
+        Some(Errors::CipherNotConfigured)
+    );
+}
+
+#[test]
+fn test_engine_file_header() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 1024 * 1024,
+        ..Default::default()
+    };
+    let datafile = |opts: &Options, fid: u32| opts.dir_path.join(format!("{:09}.bcdata", fid));
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+    drop(engine);
+    let content = std::fs::read(datafile(&opts, 0)).unwrap();
+    assert_eq!(&content[..4], b"BCRS");
+
+    let mut other_opts = opts.clone();
+    other_opts.dir_path = Builder::new()
         .prefix("bitcast-rs")
         .tempdir()
         .unwrap()
         .path()
         .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    drop(Engine::open(other_opts.clone()).expect("failed to open engine"));
+    std::fs::copy(datafile(&other_opts, 0), datafile(&opts, 5)).unwrap();
+    // files of a database created before manifest are found by their names,
+    // otherwise datafile 5 is removed as an orphan
+    std::fs::remove_file(opts.dir_path.join("MANIFEST")).unwrap();
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::ForeignDataFile)
+    );
+
+    // a file written by a newer version
+    let mut newer = content.clone();
+    newer[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    let crc = crc32fast::hash(&newer[..FILE_HEADER_SIZE - 4]);
+    newer[FILE_HEADER_SIZE - 4..FILE_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    std::fs::write(datafile(&opts, 5), newer).unwrap();
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::UnsupportedFormatVersion(FORMAT_VERSION + 1))
+    );
 
+    std::fs::write(datafile(&opts, 5), "not a bitcask datafile").unwrap();
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::InvalidFileHeader)
+    );
+
+    std::fs::remove_file(datafile(&opts, 5)).unwrap();
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.get(get_test_key(1)), Ok(get_test_value(1)));
+}
 
-    const SIZE: usize = 1000000;
+/// datafiles of a database written by the version before file headers, keys keep
+/// batch prefix and sequence in an envelope, the last file is active
+fn baseline_datafiles() -> Vec<(std::ffi::OsString, Vec<u8>)> {
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/baseline");
+    let mut files = std::fs::read_dir(fixture)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (
+                path.file_name().unwrap().to_owned(),
+                std::fs::read(path).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
 
-    for i in 0..=SIZE {
+#[test]
+fn test_engine_read_v1_datafile() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 1024 * 1024,
+        ..Default::default()
+    };
+    std::fs::create_dir_all(&opts.dir_path).unwrap();
+
+    // records of baseline files under a v1 header, the first batch is committed,
+    // the commit record of the second batch is in the last file, which is left out
+    let header = FileHeader {
+        version: 1,
+        ..FileHeader::new(uuid::Uuid::new_v4(), ChecksumType::Crc32)
+    };
+    let baseline = baseline_datafiles();
+    let mut content = header.encode();
+    for (_, records) in &baseline[..baseline.len() - 1] {
+        content.extend(records);
+    }
+    std::fs::write(opts.dir_path.join("000000000.bcdata"), content).unwrap();
+
+    let key = |i: usize| Bytes::from(format!("key-{:03}", i));
+    let value = |i: usize| Bytes::from(format!("value-{:03}", i));
+    let check = |engine: &Engine| {
+        for i in [0, 1, 2, 6, 7, 8, 9] {
+            assert_eq!(engine.get(key(i)), Ok(value(i)));
+        }
+        assert_eq!(engine.get(key(3)), Err(Errors::KeyNotFound));
+        assert_eq!(engine.get(key(4)), Ok(value(40)));
+        assert_eq!(engine.get(key(5)), Err(Errors::KeyNotFound));
+        assert_eq!(engine.get(key(10)), Err(Errors::KeyNotFound));
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    check(&engine);
+
+    // new records go to a new file of current format, batch ids of baseline
+    // are nanosecond timestamps, new batches never mix with them
+    assert!(engine.batch_id > 1_600_000_000_000_000_000);
+    assert!(engine.put(key(11), value(11)).is_ok());
+    let mut write_batch = engine
+        .write_batch(&Default::default())
+        .expect("failed to create write batch");
+    assert_eq!(write_batch.put(&key(12), &value(12)), Ok(()));
+    assert_eq!(write_batch.commit(), Ok(()));
+    drop(engine);
+    let content = std::fs::read(opts.dir_path.join("000000001.bcdata")).unwrap();
+    assert_eq!(content[4..8], FORMAT_VERSION.to_le_bytes());
+    assert!(!content.windows(7).any(|w| w == b"non_txn"));
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    check(&engine);
+    assert_eq!(engine.get(key(11)), Ok(value(11)));
+    assert_eq!(engine.get(key(12)), Ok(value(12)));
+    drop(engine);
+
+    // same records in headerless files
+    std::fs::remove_dir_all(&opts.dir_path).unwrap();
+    std::fs::create_dir_all(&opts.dir_path).unwrap();
+    for (name, records) in &baseline[..baseline.len() - 1] {
+        std::fs::write(opts.dir_path.join(name), records).unwrap();
+    }
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    check(&engine);
+}
+
+#[test]
+fn test_engine_upgrade_baseline_database() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 1024 * 1024,
+        ..Default::default()
+    };
+    std::fs::create_dir_all(&opts.dir_path).unwrap();
+
+    for (name, records) in baseline_datafiles() {
+        std::fs::write(opts.dir_path.join(name), records).unwrap();
+    }
+    let legacy = std::fs::read(opts.dir_path.join("000000004.bcdata")).unwrap();
+
+    let key = |i: usize| Bytes::from(format!("key-{:03}", i));
+    let value = |i: usize| Bytes::from(format!("value-{:03}", i));
+    let check = |engine: &Engine| {
+        for i in [0, 1, 2, 8, 9, 10] {
+            assert_eq!(engine.get(key(i)), Ok(value(i)));
+        }
+        for i in [4, 6, 7] {
+            assert_eq!(engine.get(key(i)), Ok(value(i * 10)));
+        }
+        assert_eq!(engine.get(key(3)), Err(Errors::KeyNotFound));
+        assert_eq!(engine.get(key(5)), Err(Errors::KeyNotFound));
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    check(&engine);
+    assert_eq!(engine.list_keys().unwrap().len(), 9);
+
+    // legacy files are kept as they are, new records go to a new file
+    assert!(engine.put(key(11), value(11)).is_ok());
+    assert!(engine.delete(key(0)).is_ok());
+    drop(engine);
+    assert_eq!(
+        std::fs::read(opts.dir_path.join("000000004.bcdata")).unwrap(),
+        legacy
+    );
+    let content = std::fs::read(opts.dir_path.join("000000005.bcdata")).unwrap();
+    assert_eq!(content[..4], *b"BCRS");
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.get(key(0)), Err(Errors::KeyNotFound));
+    assert_eq!(engine.get(key(11)), Ok(value(11)));
+    assert_eq!(engine.get(key(4)), Ok(value(40)));
+}
+
+#[test]
+fn test_engine_read_baseline_large_value() {
+    use bytes::{BufMut, BytesMut};
+
+    let mut opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 1024 * 1024,
+        ..Default::default()
+    };
+    std::fs::create_dir_all(&opts.dir_path).unwrap();
+
+    // baseline has no size limits, a value larger than the default limit is
+    // bounded only by size of its file
+    let key = b"\x07non_txn\x00key-large";
+    let value = (0..=255u8)
+        .cycle()
+        .take(opts.max_value_size + 64 * 1024)
+        .collect::<Vec<_>>();
+    let mut record = BytesMut::new();
+    record.put_u8(1);
+    prost::encode_length_delimiter(key.len(), &mut record).unwrap();
+    prost::encode_length_delimiter(value.len(), &mut record).unwrap();
+    record.extend_from_slice(key);
+    record.extend_from_slice(&value);
+    let crc = crc32fast::hash(&record);
+    record.put_u32_le(crc);
+    std::fs::write(opts.dir_path.join("000000000.bcdata"), record).unwrap();
+
+    // limits recorded by the first open can't be less than existing records
+    assert!(matches!(
+        Engine::open(opts.clone()),
+        Err(Errors::IncompatibleOptions(_))
+    ));
+    assert!(!opts.dir_path.join("OPTIONS").exists());
+    opts.max_value_size = value.len();
+    for _ in 0..2 {
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let read = engine.get(Bytes::from("key-large"));
+        assert_eq!(read.map(|read| read == value), Ok(true));
+    }
+}
+
+#[test]
+fn test_engine_checksum() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 1024 * 1024,
+        checksum: ChecksumType::Crc32c,
+        ..Default::default()
+    };
+    let datafile = |fid: u32| opts.dir_path.join(format!("{:09}.bcdata", fid));
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..100 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    drop(engine);
+
+    // files written with another algorithm stay readable
+    let mut xxh3_opts = opts.clone();
+    xxh3_opts.checksum = ChecksumType::Xxh3;
+    let engine = Engine::open(xxh3_opts.clone()).expect("failed to open engine");
+    for i in 100..200 {
         assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
     }
+    drop(engine);
+    assert_eq!(std::fs::read(datafile(0)).unwrap()[32], 1);
+    assert_eq!(std::fs::read(datafile(1)).unwrap()[32], 2);
+
+    let engine = Engine::open(xxh3_opts.clone()).expect("failed to open engine");
+    for i in 0..200 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
 
+    // flip a byte in value of the last record
+    let mut content = std::fs::read(datafile(1)).unwrap();
+    let value = get_test_value(199);
+    let pos = content
+        .windows(value.len())
+        .position(|w| w == value.as_ref())
+        .unwrap();
+    content[pos] ^= 1;
+    std::fs::write(datafile(1), content).unwrap();
+
+    let err = engine.get(get_test_key(199)).unwrap_err();
+    assert!(matches!(err, Errors::ChecksumMismatch { file_id: 1, .. }));
+    assert!(err.is_corruption() && err.is_fatal() && !err.is_retryable());
+    let unverified = ReadOptions {
+        verify_checksum: false,
+    };
+    let mut corrupted = get_test_value(199).to_vec();
+    corrupted[0] ^= 1;
+    assert_eq!(
+        engine.get_with_options(get_test_key(199), &unverified),
+        Ok(Bytes::from(corrupted.clone()))
+    );
+
+    let iterator = engine.iterator(IndexIteratorOptions {
+        prefix: get_test_key(199).to_vec(),
+        ..Default::default()
+    });
+    assert_eq!(iterator.next(), Err(err));
+    drop(iterator);
+    let mut folded = 0;
+    assert!(matches!(
+        engine.fold(|_, _| -> bool {
+            folded += 1;
+            true
+        }),
+        Err(Errors::ChecksumMismatch { file_id: 1, .. })
+    ));
+    assert_eq!(folded, 199);
+    let iterator = engine.iterator(IndexIteratorOptions {
+        prefix: get_test_key(199).to_vec(),
+        read_options: unverified,
+        ..Default::default()
+    });
+    assert_eq!(
+        iterator.next(),
+        Ok(Some((get_test_key(199), Bytes::from(corrupted))))
+    );
+    drop(iterator);
+    drop(engine);
+
+    assert!(matches!(
+        Engine::open(xxh3_opts),
+        Err(Errors::ChecksumMismatch { file_id: 1, .. })
+    ));
+}
+
+#[test]
+fn test_engine_scrub() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert_eq!(engine.scrub(), Ok(vec![]));
+    let stats = engine.scrub_stats();
+    assert_eq!(stats.passes, 1);
+    assert!(stats.files_scrubbed > 1);
+    assert_eq!(stats.corrupt_ranges, 0);
+
+    // flip a byte in value of a record in the first sealed file
+    let datafile = opts.dir_path.join(format!("{:09}.bcdata", 0));
+    let mut content = std::fs::read(&datafile).unwrap();
+    let value = get_test_value(10);
+    let pos = content
+        .windows(value.len())
+        .position(|w| w == value.as_ref())
+        .unwrap();
+    content[pos + 1] ^= 1;
+    std::fs::write(&datafile, content).unwrap();
+
+    let found = engine.scrub().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].file_id, 0);
+    assert!(found[0].start < pos as u64 && pos as u64 + 1 < found[0].end);
+    assert_eq!(engine.corrupt_ranges(), found);
+    assert_eq!(engine.scrub(), Ok(vec![]));
+    assert_eq!(engine.scrub_stats().corrupt_ranges, 1);
+
+    // corrupted record fails even when checksum is not verified
+    let err = || {
+        Err(Errors::CorruptedRecord {
+            file_id: 0,
+            offset: found[0].start,
+        })
+    };
+    assert_eq!(engine.get(get_test_key(10)), err());
+    let unverified = ReadOptions {
+        verify_checksum: false,
+    };
+    assert_eq!(
+        engine.get_with_options(get_test_key(10), &unverified),
+        err()
+    );
+    assert_eq!(engine.get_range(get_test_key(10), 0, 4), err());
+    for i in (0..2000).filter(|i| *i != 10) {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+
+    // a broken header loses the size of its record, records after it are
+    // found again, so only the broken record is corrupt
+    let (start, end) = (
+        engine.indexer.get(get_test_key(20).to_vec()).unwrap(),
+        engine.indexer.get(get_test_key(21).to_vec()).unwrap(),
+    );
+    assert_eq!((start.file_id, end.file_id), (0, 0));
+    let mut content = std::fs::read(&datafile).unwrap();
+    content[start.offset as usize] = 0x0e;
+    std::fs::write(&datafile, content).unwrap();
+    assert_eq!(
+        engine.scrub(),
+        Ok(vec![crate::scrub::CorruptRange {
+            file_id: 0,
+            start: start.offset,
+            end: end.offset,
+        }])
+    );
+    assert_eq!(
+        engine.get(get_test_key(20)),
+        Err(Errors::CorruptedRecord {
+            file_id: 0,
+            offset: start.offset,
+        })
+    );
+    for i in (0..2000).filter(|i| *i != 10 && *i != 20) {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_background_scrub() {
+    use crate::{options::ScrubOptions, scrub::CorruptRange};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    let found = Arc::new(Mutex::new(Vec::<CorruptRange>::new()));
+    let found_cloned = found.clone();
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        scrub: Some(ScrubOptions {
+            bytes_per_sec: 1024 * 1024,
+            interval: Duration::from_millis(10),
+            on_corruption: Some(Arc::new(move |range: &CorruptRange| {
+                found_cloned.lock().unwrap().push(*range)
+            })),
+        }),
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+
+    // flip the last byte of crc of the last record in a sealed file
+    let datafile = opts.dir_path.join(format!("{:09}.bcdata", 1));
+    let mut content = std::fs::read(&datafile).unwrap();
+    let last = content.iter().rposition(|b| *b != 0).unwrap();
+    content[last] ^= 1;
+    std::fs::write(&datafile, content).unwrap();
+
+    let start = Instant::now();
+    while found.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let found = found.lock().unwrap().clone();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].file_id, 1);
+    assert!(found[0].start <= last as u64 && (last as u64) < found[0].end);
+    assert!(engine.scrub_stats().passes >= 1);
+    assert_eq!(engine.corrupt_ranges(), found);
+
+    // close stops scrubber without waiting for next pass
     assert_eq!(engine.close(), Ok(()));
 }
 
 #[test]
-fn test_sync() {
-    let mut opts = Options::default();
-    opts.dir_path = Builder::new()
+fn test_engine_salvage_recovery() {
+    use crate::options::RecoveryMode;
+
+    let mut opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..2000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    assert!(engine.recovery_report().is_clean());
+    drop(engine);
+
+    let datafile = opts.dir_path.join(format!("{:09}.bcdata", 0));
+    let mut content = std::fs::read(&datafile).unwrap();
+    let find =
+        |content: &[u8], data: &[u8]| content.windows(data.len()).position(|w| w == data).unwrap();
+    // crc of key 10 fails
+    let pos = find(&content, &get_test_value(10));
+    content[pos + 1] ^= 1;
+    // key size of key 20 is broken, and record of key 30 is zeroed
+    let pos = find(&content, &get_test_key(20));
+    content[pos - 2] = 0xff;
+    let pos = find(&content, &get_test_key(30));
+    content[pos - 5..pos].fill(0);
+    std::fs::write(&datafile, content).unwrap();
+
+    assert!(matches!(
+        Engine::open(opts.clone()),
+        Err(Errors::ChecksumMismatch { file_id: 0, .. })
+    ));
+
+    opts.recovery_mode = RecoveryMode::Salvage;
+    let engine = Engine::open(opts.clone()).expect("failed to salvage engine");
+    let report = engine.recovery_report().clone();
+    assert_eq!(report.skipped.len(), 3);
+    assert_eq!(report.skipped_records(), 3);
+    for (range, i) in report.skipped.iter().zip([10, 20, 30]) {
+        assert_eq!(range.file_id, 0);
+        assert_eq!(range.records, 1);
+        // exactly one record is skipped
+        let record_size = 5 + get_test_key(i).len() + get_test_value(i).len() + 4;
+        assert_eq!((range.end - range.start) as usize, record_size);
+        let pos = find(&std::fs::read(&datafile).unwrap(), &get_test_key(i)) as u64;
+        assert!(range.start < pos && pos < range.end);
+    }
+    for i in 0..2000 {
+        match i {
+            10 | 20 | 30 => assert_eq!(engine.get(get_test_key(i)), Err(Errors::KeyNotFound)),
+            _ => assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i))),
+        }
+    }
+
+    // salvaged keys can be written again
+    assert!(engine.put(get_test_key(10), get_test_value(10)).is_ok());
+    drop(engine);
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert_eq!(engine.get(get_test_key(10)), Ok(get_test_value(10)));
+    assert_eq!(engine.recovery_report(), &report);
+}
+
+#[test]
+fn test_engine_size_limits() {
+    let mut opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 32,
+        max_value_size: 256 * 1024,
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    let long_key = Bytes::from(vec![b'k'; 33]);
+    let key_too_large = Err(Errors::KeyTooLarge { size: 33, max: 32 });
+    assert_eq!(
+        engine.put(long_key.clone(), get_test_value(0)),
+        key_too_large
+    );
+    assert_eq!(engine.delete(long_key.clone()), key_too_large);
+    assert_eq!(
+        engine.put_stream(long_key.clone(), std::io::Cursor::new(&[1]), 1),
+        key_too_large
+    );
+    assert_eq!(
+        engine.put(get_test_key(0), Bytes::from(vec![0u8; 256 * 1024 + 1])),
+        Err(Errors::ValueTooLarge {
+            size: 256 * 1024 + 1,
+            max: 256 * 1024
+        })
+    );
+    let mut batch = engine
+        .write_batch(&WriteBatchOptions::default())
+        .expect("failed to create write batch");
+    assert_eq!(batch.put(&long_key, b"value"), key_too_large);
+    assert_eq!(batch.delete(&long_key), key_too_large);
+    assert!(matches!(
+        batch.put(b"key", &[0u8; 256 * 1024 + 1]),
+        Err(Errors::ValueTooLarge { .. })
+    ));
+    assert_eq!(engine.get(long_key), Err(Errors::KeyNotFound));
+
+    // values which can't fit in a datafile take blob files of their own
+    let large = (0..2)
+        .map(|i| Bytes::from(vec![i as u8; 100 * 1024]))
+        .collect::<Vec<_>>();
+    for (i, value) in large.iter().enumerate() {
+        assert_eq!(engine.put(get_test_key(i), value.clone()), Ok(()));
+    }
+    assert_eq!(engine.put(get_test_key(2), get_test_value(2)), Ok(()));
+    let blob_files = std::fs::read_dir(&opts.dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bcblob"))
+        .collect::<Vec<_>>();
+    assert_eq!(blob_files.len(), 2);
+    for path in blob_files {
+        assert!(std::fs::metadata(path).unwrap().len() > 100 * 1024);
+    }
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    for (i, value) in large.iter().enumerate() {
+        assert_eq!(engine.get(get_test_key(i)), Ok(value.clone()));
+    }
+    assert_eq!(engine.get(get_test_key(2)), Ok(get_test_value(2)));
+    drop(engine);
+
+    // a key which can't fit in a datafile is rejected by options
+    opts.max_key_size = 64 * 1024;
+    assert!(matches!(
+        Engine::open(opts).err(),
+        Some(Errors::InvalidOption(_))
+    ));
+}
+
+#[test]
+fn test_engine_options_file() {
+    let dir_path = Builder::new()
         .prefix("bitcast-rs")
         .tempdir()
         .unwrap()
         .path()
         .to_path_buf();
-    opts.datafile_size = 64 * 1024 * 1024;
+    assert!(matches!(
+        Options::builder().dir_path("").build(),
+        Err(Errors::InvalidDatabasePath)
+    ));
+    assert!(matches!(
+        Options::builder().datafile_size(16).build(),
+        Err(Errors::DatafileSizeTooSmall)
+    ));
+    assert!(matches!(
+        Options::builder().max_key_size(0).build(),
+        Err(Errors::InvalidOption(_))
+    ));
+    assert!(matches!(
+        Options::builder().index_type(IndexType::SkipList).build(),
+        Err(Errors::InvalidOption(_))
+    ));
+
+    let opts = Options::builder()
+        .dir_path(&dir_path)
+        .datafile_size(64 * 1024)
+        .max_key_size(1024)
+        .build()
+        .expect("failed to build options");
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(engine.put(get_test_key(0), get_test_value(0)), Ok(()));
+    drop(engine);
+    let content = std::fs::read_to_string(dir_path.join("OPTIONS")).unwrap();
+    assert!(content.contains("datafile_size=65536\n"));
+    assert!(content.contains("max_key_size=1024\n"));
+
+    // existing records may be larger than a decreased limit
+    let mut incompatible = opts.clone();
+    incompatible.max_key_size = 512;
+    assert!(matches!(
+        Engine::open(incompatible),
+        Err(Errors::IncompatibleOptions(_))
+    ));
+
+    // compatible changes are migrated and persisted, a temporary file left by
+    // an interrupted rewrite is removed
+    std::fs::write(dir_path.join("OPTIONS.tmp"), "max_key_size=").unwrap();
+    let mut migrated = opts.clone();
+    migrated.checksum = ChecksumType::Crc32c;
+    migrated.datafile_size = 128 * 1024;
+    migrated.max_key_size = 2048;
+    let engine = Engine::open(migrated).expect("failed to reopen engine");
+    assert_eq!(engine.get(get_test_key(0)), Ok(get_test_value(0)));
+    drop(engine);
+    assert!(!dir_path.join("OPTIONS.tmp").exists());
+    let content = std::fs::read_to_string(dir_path.join("OPTIONS")).unwrap();
+    assert!(content.contains("datafile_size=131072\n"));
+    assert!(content.contains("max_key_size=2048\n"));
+    assert!(matches!(
+        Engine::open(opts.clone()),
+        Err(Errors::IncompatibleOptions(_))
+    ));
+
+    // options written by a newer version are rejected
+    let newer = content.replace(
+        &format!("format_version={}", FORMAT_VERSION),
+        &format!("format_version={}", FORMAT_VERSION + 1),
+    );
+    std::fs::write(dir_path.join("OPTIONS"), newer).unwrap();
+    let mut reopen = opts;
+    reopen.max_key_size = 2048;
+    assert_eq!(
+        Engine::open(reopen).err(),
+        Some(Errors::UnsupportedFormatVersion(FORMAT_VERSION + 1))
+    );
+}
+
+#[test]
+fn test_options_validate() {
+    use crate::options::ScrubOptions;
+
+    let invalid = |builder: crate::options::OptionsBuilder| {
+        matches!(builder.build(), Err(Errors::InvalidOption(_)))
+    };
+    let builder = || {
+        Options::builder()
+            .datafile_size(64 * 1024)
+            .max_key_size(1024)
+    };
+    assert!(builder().build().is_ok());
+
+    // write buffer is flushed before the file is full
+    assert!(builder().write_buffer_size(64 * 1024).build().is_ok());
+    assert!(invalid(builder().write_buffer_size(64 * 1024 + 1)));
+
+    // key and pointer to its value must fit in a datafile
+    assert!(invalid(builder().max_key_size(64 * 1024)));
+    assert!(invalid(Options::builder().datafile_size(4 * 1024)));
+
+    // sizes in record header are u32
+    assert!(invalid(builder().max_value_size(0)));
+    assert!(invalid(builder().max_value_size(u32::MAX as usize + 1)));
+    assert!(builder().max_value_size(u32::MAX as usize).build().is_ok());
+
+    // separated value is larger than its pointer and not larger than a value
+    assert!(builder().min_blob_size(0).build().is_ok());
+    assert!(invalid(builder().min_blob_size(1)));
+    assert!(invalid(builder().max_value_size(1024).min_blob_size(1025)));
+    assert!(builder()
+        .max_value_size(1024)
+        .min_blob_size(1024)
+        .build()
+        .is_ok());
+
+    let scrub = |bytes_per_sec| ScrubOptions {
+        bytes_per_sec,
+        ..Default::default()
+    };
+    assert!(builder().scrub(scrub(0)).build().is_ok());
+    assert!(invalid(builder().scrub(scrub(1024))));
+    assert!(builder().scrub(scrub(1024 * 1024)).build().is_ok());
+}
+
+#[test]
+fn test_engine_manifest() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        min_blob_size: 1024,
+        ..Default::default()
+    };
+    let datafile = |fid: u32| opts.dir_path.join(format!("{:09}.bcdata", fid));
 
     let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert_eq!(engine.put(get_test_key(i), get_test_value(i)), Ok(()));
+    }
+    assert_eq!(
+        engine.put(get_test_key(1000), Bytes::from(vec![1u8; 2048])),
+        Ok(())
+    );
+    drop(engine);
+    assert!(datafile(1).exists());
+
+    // files which never reach the manifest are removed on open,
+    // unless they hold records, then they are quarantined
+    let quarantine = opts.dir_path.join("quarantine");
+    let mut empty = std::fs::read(datafile(0)).unwrap()[..FILE_HEADER_SIZE].to_vec();
+    empty.resize(4096, 0);
+    std::fs::write(datafile(8), empty).unwrap();
+    std::fs::copy(datafile(0), datafile(9)).unwrap();
+    std::fs::copy(datafile(0), opts.dir_path.join("000000001.tmp.bcdata")).unwrap();
+    std::fs::write(opts.dir_path.join("000000007.bcblob"), "orphan blob").unwrap();
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(!datafile(8).exists());
+    assert!(!quarantine.join("000000008.bcdata").exists());
+    assert!(!datafile(9).exists());
+    assert_eq!(
+        std::fs::read(quarantine.join("000000009.bcdata")).unwrap(),
+        std::fs::read(datafile(0)).unwrap()
+    );
+    assert!(!opts.dir_path.join("000000001.tmp.bcdata").exists());
+    assert!(quarantine.join("000000001.tmp.bcdata").exists());
+    assert!(!opts.dir_path.join("000000007.bcblob").exists());
+    for i in 0..1000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+    assert_eq!(
+        engine.get(get_test_key(1000)),
+        Ok(Bytes::from(vec![1u8; 2048]))
+    );
+    drop(engine);
 
-    const SIZE: usize = 1000000;
+    // a live file which is gone can't be recovered
+    let manifest = std::fs::read(opts.dir_path.join("MANIFEST")).unwrap();
+    let first = std::fs::read(datafile(0)).unwrap();
+    std::fs::remove_file(datafile(0)).unwrap();
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::DataFileNotFound)
+    );
+    std::fs::write(datafile(0), first).unwrap();
+
+    // a torn edit at the end of manifest is dropped
+    let mut torn = manifest.clone();
+    torn.extend_from_slice(&[5, 0, 0]);
+    std::fs::write(opts.dir_path.join("MANIFEST"), torn).unwrap();
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert_eq!(engine.get(get_test_key(0)), Ok(get_test_value(0)));
+    drop(engine);
+    assert_eq!(
+        std::fs::read(opts.dir_path.join("MANIFEST")).unwrap(),
+        manifest
+    );
 
-    for i in 0..=SIZE {
-        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    // a corrupted edit followed by others can't be dropped, as its files are live
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    for i in 1000..3000 {
+        assert_eq!(engine.put(get_test_key(i), get_test_value(i)), Ok(()));
+    }
+    drop(engine);
+    let datafiles = || {
+        std::fs::read_dir(&opts.dir_path)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(".bcdata")
+            })
+            .count()
+    };
+    let count = datafiles();
+    let manifest = std::fs::read(opts.dir_path.join("MANIFEST")).unwrap();
+    let second = 8 + u32::from_le_bytes(manifest[..4].try_into().unwrap()) as usize;
+    assert!(manifest.len() > second + 2 * (8 + 10));
+    let mut corrupted = manifest.clone();
+    corrupted[second + 8] ^= 1;
+    std::fs::write(opts.dir_path.join("MANIFEST"), corrupted).unwrap();
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::DatabaseFileCorrupted)
+    );
+    assert_eq!(datafiles(), count);
+
+    std::fs::write(opts.dir_path.join("MANIFEST"), manifest).unwrap();
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    for i in 0..3000 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+}
+
+#[test]
+fn test_engine_clean_shutdown() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        preallocate_datafile: false,
+        ..Default::default()
+    };
+    let datafile = opts.dir_path.join(format!("{:09}.bcdata", 0));
+    let torn_record = LogRecord {
+        key: b"torn".to_vec(),
+        value: vec![1u8; 100],
+        record_type: LogRecordType::Normal,
+        batch_id: 0,
+        seq_id: 0,
     }
+    .encode();
+    let append = |content: &[u8]| {
+        use std::io::Write;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&datafile)
+            .unwrap()
+            .write_all(content)
+            .unwrap();
+    };
 
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine.last_shutdown_clean());
+    for i in 0..10 {
+        assert_eq!(engine.put(get_test_key(i), get_test_value(i)), Ok(()));
+    }
     assert_eq!(engine.sync(), Ok(()));
+    // crash with a torn write at the end of active file
+    engine.kill();
+    let end = std::fs::metadata(&datafile).unwrap().len();
+    append(&torn_record[..torn_record.len() / 2]);
+
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(!engine.last_shutdown_clean());
+    let torn_tail = engine.recovery_report().torn_tail.unwrap();
+    assert_eq!((torn_tail.file_id, torn_tail.start), (0, end));
+    assert!(engine.recovery_report().is_clean());
+    for i in 0..10 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+    assert_eq!(engine.put(get_test_key(10), get_test_value(10)), Ok(()));
+    drop(engine);
+
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(engine.last_shutdown_clean());
+    assert_eq!(engine.recovery_report().torn_tail, None);
+    for i in 0..=10 {
+        assert_eq!(engine.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
+
+    drop(engine);
+
+    // the end of datafile is trusted after a clean shutdown
+    append(&torn_record[..torn_record.len() / 2]);
+    assert!(matches!(
+        Engine::open(opts),
+        Err(Errors::CorruptedRecord { file_id: 0, .. })
+    ));
+}
+
+#[test]
+fn test_engine_close() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        scrub: Some(Default::default()),
+        ..Default::default()
+    };
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        Engine::open(opts.clone()).err(),
+        Some(Errors::DatabaseLocked)
+    );
+    assert_eq!(engine.put(get_test_key(0), get_test_value(0)), Ok(()));
+    assert_eq!(engine.close(), Ok(()));
+
+    // close releases lock of directory
+    let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(engine.last_shutdown_clean());
+    assert_eq!(engine.get(get_test_key(0)), Ok(get_test_value(0)));
+
+    // a shut down engine fails every read and write
+    assert_eq!(engine.shutdown(), Ok(()));
+    assert_eq!(engine.shutdown(), Ok(()));
+    assert_eq!(engine.get(get_test_key(0)), Err(Errors::EngineClosed));
+    assert_eq!(
+        engine.put(get_test_key(1), get_test_value(1)),
+        Err(Errors::EngineClosed)
+    );
+    assert_eq!(engine.delete(get_test_key(0)), Err(Errors::EngineClosed));
+    assert!(matches!(
+        engine.write_batch(&WriteBatchOptions::default()),
+        Err(Errors::EngineClosed)
+    ));
+    assert_eq!(
+        engine.iterator(Default::default()).next(),
+        Err(Errors::EngineClosed)
+    );
+    assert_eq!(engine.list_keys(), Err(Errors::EngineClosed));
+    assert_eq!(
+        engine.fold(|_, _| -> bool { unreachable!() }),
+        Err(Errors::EngineClosed)
+    );
+    assert_eq!(engine.sync(), Err(Errors::EngineClosed));
+    assert_eq!(engine.merge_blobs(), Err(Errors::EngineClosed));
+
+    // directory is unlocked by shutdown
+    let reopened = Engine::open(opts.clone()).expect("failed to reopen engine");
+    assert!(reopened.last_shutdown_clean());
+    assert_eq!(reopened.get(get_test_key(0)), Ok(get_test_value(0)));
+    drop(engine);
+}
+
+#[test]
+fn test_db_handle() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<Db>();
+    assert_send_sync::<crate::batch::OwnedWriteBatch>();
+    assert_send_sync::<crate::iterator::OwnedIterator>();
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let db = Db::open(opts.clone()).expect("failed to open db");
+    let handle = db.clone();
+    assert_eq!(handle.put(get_test_key(0), get_test_value(0)), Ok(()));
+    assert_eq!(db.get(get_test_key(0)), Ok(get_test_value(0)));
+
+    // owned batch and iterator outlive the handle creating them
+    let mut batch = handle
+        .write_batch(&WriteBatchOptions::default())
+        .expect("failed to create write batch");
+    let iterator = handle.iterator(IndexIteratorOptions::default());
+    drop(handle);
+    thread::spawn(move || {
+        assert_eq!(batch.put(&get_test_key(1), &get_test_value(1)), Ok(()));
+        assert_eq!(batch.commit(), Ok(()));
+        assert_eq!(
+            iterator.next(),
+            Ok(Some((get_test_key(0), get_test_value(0))))
+        );
+    })
+    .join()
+    .unwrap();
+    assert_eq!(db.get(get_test_key(1)), Ok(get_test_value(1)));
+
+    // engine is shut down when the last handle is dropped
+    drop(db);
+    let db = Db::open(opts.clone()).expect("failed to reopen db");
+    assert!(db.last_shutdown_clean());
+    assert_eq!(db.get(get_test_key(1)), Ok(get_test_value(1)));
+
+    // closing a handle shuts down engine for all of them
+    let handle = db.clone();
+    let iterator = handle.iterator(IndexIteratorOptions::default());
+    assert_eq!(db.close(), Ok(()));
+    assert_eq!(db.close(), Ok(()));
+    assert_eq!(handle.get(get_test_key(0)), Err(Errors::EngineClosed));
+    assert_eq!(
+        handle.put(get_test_key(2), get_test_value(2)),
+        Err(Errors::EngineClosed)
+    );
+    assert!(matches!(
+        handle.write_batch(&WriteBatchOptions::default()),
+        Err(Errors::EngineClosed)
+    ));
+    assert_eq!(iterator.next(), Err(Errors::EngineClosed));
+
+    let reopened = Db::open(opts.clone()).expect("failed to reopen db");
+    assert!(reopened.last_shutdown_clean());
+    assert_eq!(reopened.list_keys().unwrap().len(), 2);
+}
+
+#[test]
+fn test_db_concurrent_put_get() {
+    const THREADS: usize = 8;
+    const KEYS: usize = 2000;
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        // small datafiles, so writers race on rotation
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let db = Db::open(opts.clone()).expect("failed to open db");
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in (t..KEYS).step_by(THREADS) {
+                    assert_eq!(db.put(get_test_key(i), get_test_value(i)), Ok(()));
+                    assert_eq!(db.get(get_test_key(i)), Ok(get_test_value(i)));
+                    // keys of other threads are either missing or complete
+                    let other = (i + 1) % KEYS;
+                    match db.get(get_test_key(other)) {
+                        Ok(value) => assert_eq!(value, get_test_value(other)),
+                        Err(e) => assert_eq!(e, Errors::KeyNotFound),
+                    }
+                    if i % 3 == 0 {
+                        assert_eq!(db.delete(get_test_key(i)), Ok(()));
+                    }
+                }
+            })
+        })
+        .collect();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+
+    let check = |db: &Db| {
+        for i in 0..KEYS {
+            match i % 3 {
+                0 => assert_eq!(db.get(get_test_key(i)), Err(Errors::KeyNotFound)),
+                _ => assert_eq!(db.get(get_test_key(i)), Ok(get_test_value(i))),
+            }
+        }
+    };
+    check(&db);
+    assert_eq!(db.close(), Ok(()));
+    drop(db);
+
+    let db = Db::open(opts.clone()).expect("failed to reopen db");
+    check(&db);
+}
+
+#[test]
+fn test_db_concurrent_put_same_key() {
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 500;
+    const KEYS: usize = 4;
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let db = Db::open(opts.clone()).expect("failed to open db");
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let key = get_test_key(round % KEYS);
+                    let value = get_test_value(t * ROUNDS + round);
+                    match round % 5 {
+                        0 => assert_eq!(db.delete(key), Ok(())),
+                        1 => {
+                            let mut batch = db
+                                .write_batch(&WriteBatchOptions::default())
+                                .expect("failed to create write batch");
+                            assert_eq!(batch.put(&key, &value), Ok(()));
+                            assert_eq!(batch.put(&get_test_key(KEYS - 1), &value), Ok(()));
+                            assert_eq!(batch.commit(), Ok(()));
+                        }
+                        _ => assert_eq!(db.put(key, value), Ok(())),
+                    }
+                }
+            })
+        })
+        .collect();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+
+    // the index must agree with the order of records in datafiles
+    let values: Vec<_> = (0..KEYS).map(|i| db.get(get_test_key(i))).collect();
+    assert_eq!(db.close(), Ok(()));
+    drop(db);
+
+    let db = Db::open(opts.clone()).expect("failed to reopen db");
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(db.get(get_test_key(i)), value);
+    }
+}
+
+#[test]
+fn test_db_concurrent_batch_commit() {
+    const THREADS: usize = 8;
+    const BATCHES: usize = 50;
+    const BATCH_SIZE: usize = 10;
+
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let db = Db::open(opts.clone()).expect("failed to open db");
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for b in 0..BATCHES {
+                    let mut batch = db
+                        .write_batch(&WriteBatchOptions::default())
+                        .expect("failed to create write batch");
+                    for k in 0..BATCH_SIZE {
+                        let i = (t * BATCHES + b) * BATCH_SIZE + k;
+                        assert_eq!(batch.put(&get_test_key(i), &get_test_value(i)), Ok(()));
+                    }
+                    // a key shared by all threads is written by every batch
+                    assert_eq!(batch.put(b"shared", &get_test_value(t)), Ok(()));
+                    assert_eq!(batch.commit(), Ok(()));
+                    // iterate while others commit
+                    let iterator = db.iterator(IndexIteratorOptions::default());
+                    while let Some((key, value)) = iterator.next().expect("failed to iterate") {
+                        assert!(!key.is_empty() && !value.is_empty());
+                    }
+                }
+            })
+        })
+        .collect();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+
+    let check = |db: &Db| {
+        let total = THREADS * BATCHES * BATCH_SIZE;
+        for i in 0..total {
+            assert_eq!(db.get(get_test_key(i)), Ok(get_test_value(i)));
+        }
+        let shared = db.get(Bytes::from_static(b"shared")).unwrap();
+        assert!((0..THREADS).any(|t| shared == get_test_value(t)));
+        assert_eq!(db.list_keys().unwrap().len(), total + 1);
+        shared
+    };
+    let shared = check(&db);
+    drop(db);
+
+    // replay of interleaved batches gives the same state
+    let db = Db::open(opts.clone()).expect("failed to reopen db");
+    assert_eq!(check(&db), shared);
+}
+
+#[test]
+fn test_db_sealed_reads_without_lock() {
+    let opts = Options {
+        dir_path: Builder::new()
+            .prefix("bitcast-rs")
+            .tempdir()
+            .unwrap()
+            .path()
+            .to_path_buf(),
+        datafile_size: 64 * 1024,
+        max_key_size: 1024,
+        ..Default::default()
+    };
+
+    let db = Db::open(opts.clone()).expect("failed to open db");
+    for i in 0..2000 {
+        assert_eq!(db.put(get_test_key(i), get_test_value(i)), Ok(()));
+    }
+    assert!(db.old_files.load().len() > 1);
+
+    // reads of sealed files go on while the active file is locked by a writer
+    let active_file = db.active_file.write();
+    let (tx, rx) = mpsc::channel();
+    let reader = db.clone();
+    thread::spawn(move || {
+        let values = reader.multi_get(&[get_test_key(0), get_test_key(1)]);
+        let iterator = reader.iterator(IndexIteratorOptions::default());
+        tx.send((reader.get(get_test_key(0)), values, iterator.next()))
+            .unwrap();
+    });
+    let (value, values, next) = rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("reads of sealed files are blocked");
+    drop(active_file);
+    assert_eq!(value, Ok(get_test_value(0)));
+    assert_eq!(
+        values,
+        Ok(vec![Some(get_test_value(0)), Some(get_test_value(1))])
+    );
+    assert_eq!(next, Ok(Some((get_test_key(0), get_test_value(0)))));
+
+    // files sealed while reading are found in the new snapshot
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    match t {
+                        0 => assert_eq!(
+                            db.put(get_test_key(2000 + i), get_test_value(2000 + i)),
+                            Ok(())
+                        ),
+                        _ => {
+                            let key = (i * 7 + t) % 2000;
+                            assert_eq!(db.get(get_test_key(key)), Ok(get_test_value(key)));
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+    for i in 0..4000 {
+        assert_eq!(db.get(get_test_key(i)), Ok(get_test_value(i)));
+    }
 }
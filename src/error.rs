@@ -1,23 +1,26 @@
-use std::result;
+use std::{fmt, io, result};
 
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum Errors {
-    #[error("failed to read from file")]
-    FailToReadFromDataFile(String),
+    #[error("failed to read from file: {0}")]
+    FailToReadFromDataFile(IoError),
 
-    #[error("failed to sync file")]
-    FailToSyncDataFile(String),
+    #[error("failed to sync file: {0}")]
+    FailToSyncDataFile(IoError),
 
-    #[error("failed to write to file")]
-    FailToWriteToDataFile(String),
+    #[error("failed to write to file: {0}")]
+    FailToWriteToDataFile(IoError),
 
-    #[error("failed to open file")]
-    FailToOpenDataFile(String),
+    #[error("failed to open file: {0}")]
+    FailToOpenDataFile(IoError),
 
-    #[error("failed to close file")]
-    FailToCloseDataFile(String),
+    #[error("failed to close file: {0}")]
+    FailToCloseDataFile(IoError),
+
+    #[error("failed to remove file: {0}")]
+    FailToRemoveDataFile(IoError),
 
     #[error("key is empty")]
     EmptyKey,
@@ -37,9 +40,15 @@ pub enum Errors {
     #[error("database directory is empty")]
     InvalidDatabasePath,
 
-    #[error("datafile size must greater than zero")]
+    #[error("datafile size must be greater than size of file header")]
     DatafileSizeTooSmall,
 
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
+
+    #[error("options are incompatible with the database: {0}")]
+    IncompatibleOptions(String),
+
     #[error("create database directory failed")]
     FailToCreateDatabaseDirectory,
 
@@ -52,6 +61,21 @@ pub enum Errors {
     #[error("read end of file")]
     ReadEOF,
 
+    #[error("database directory is locked by another engine")]
+    DatabaseLocked,
+
+    #[error("engine is closed")]
+    EngineClosed,
+
+    #[error("key of {size} bytes exceeds max key size {max}")]
+    KeyTooLarge { size: usize, max: usize },
+
+    #[error("value of {size} bytes exceeds max value size {max}")]
+    ValueTooLarge { size: usize, max: usize },
+
+    #[error("record of {size} bytes can't fit in a datafile of {max} bytes")]
+    RecordTooLarge { size: u64, max: u64 },
+
     #[error("exceed maximum allowed batch size")]
     ExceedBatchMaxSize,
 
@@ -63,6 +87,224 @@ pub enum Errors {
 
     #[error("decode failure")]
     DecodingError,
+
+    #[error("failed to read value stream")]
+    FailToReadStream(String),
+
+    #[error("failed to compress or decompress value")]
+    CodecFailure(String),
+
+    #[error("value is compressed by an unknown codec")]
+    UnknownCodec(u8),
+
+    #[error("failed to encrypt record")]
+    EncryptionFailed,
+
+    #[error("failed to authenticate record, encryption key may be wrong")]
+    DecryptionFailed,
+
+    #[error("record is encrypted but no cipher is configured")]
+    CipherNotConfigured,
+
+    #[error("record is not encrypted but a cipher is configured")]
+    UnencryptedRecord,
+
+    #[error("invalid file header, it is not a bitcask file or it is corrupted")]
+    InvalidFileHeader,
+
+    #[error("unsupported file format version {0}")]
+    UnsupportedFormatVersion(u32),
+
+    #[error("file belongs to another database")]
+    ForeignDataFile,
+
+    #[error("record at offset {offset} of datafile {file_id} is corrupted")]
+    CorruptedRecord { file_id: u32, offset: u64 },
+
+    #[error(
+        "checksum of record at offset {offset} of datafile {file_id} mismatches, \
+         expect: {expected:#010x}, actual: {actual:#010x}"
+    )]
+    ChecksumMismatch {
+        file_id: u32,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
 }
 
 pub type Result<T> = result::Result<T, Errors>;
+
+/// IoError a failed io operation, with kind of the underlying error
+/// and where it happens when it is known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoError {
+    /// kind of underlying error, e.g. `StorageFull` for ENOSPC
+    pub kind: io::ErrorKind,
+    pub message: String,
+    pub file_id: Option<u32>,
+    pub offset: Option<u64>,
+}
+
+impl From<io::Error> for IoError {
+    fn from(e: io::Error) -> Self {
+        Self {
+            kind: e.kind(),
+            message: e.to_string(),
+            file_id: None,
+            offset: None,
+        }
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.kind)?;
+        if let Some(file_id) = self.file_id {
+            write!(f, ", datafile {}", file_id)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, ", offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl Errors {
+    fn io_error(&self) -> Option<&IoError> {
+        match self {
+            Errors::FailToReadFromDataFile(e)
+            | Errors::FailToSyncDataFile(e)
+            | Errors::FailToWriteToDataFile(e)
+            | Errors::FailToOpenDataFile(e)
+            | Errors::FailToCloseDataFile(e)
+            | Errors::FailToRemoveDataFile(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// kind of underlying io error, `None` if it is not an io failure
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        self.io_error().map(|e| e.kind)
+    }
+
+    /// the operation may succeed when it is retried later, e.g. after disk space
+    /// is freed, nothing is changed by the failed operation
+    pub fn is_retryable(&self) -> bool {
+        // a failed sync may have dropped dirty pages, retrying it can't recover them
+        if matches!(self, Errors::FailToSyncDataFile(_)) {
+            return false;
+        }
+        matches!(
+            self.io_kind(),
+            Some(
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::StorageFull
+                    | io::ErrorKind::QuotaExceeded
+                    | io::ErrorKind::ResourceBusy
+            )
+        )
+    }
+
+    /// data on disk is corrupted, retrying can't help
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            Errors::DatabaseFileCorrupted
+                | Errors::CorruptedRecord { .. }
+                | Errors::ChecksumMismatch { .. }
+                | Errors::InvalidFileHeader
+        )
+    }
+
+    /// database can't be used safely anymore and it has to be reopened,
+    /// which may need recovery, e.g. corruption or failed sync, the engine
+    /// fails every write and sync after a failed sync by itself
+    pub fn is_fatal(&self) -> bool {
+        let caller_fixable = |kind| {
+            matches!(
+                kind,
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::AlreadyExists
+                    | io::ErrorKind::InvalidInput
+            )
+        };
+        // EIO and other failures of device are fatal
+        self.is_corruption()
+            || matches!(self, Errors::FailToSyncDataFile(_))
+            || self
+                .io_kind()
+                .is_some_and(|kind| !self.is_retryable() && !caller_fixable(kind))
+    }
+
+    /// attach datafile @file_id and @offset to an io failure which has no location
+    pub(crate) fn at(self, file_id: u32, offset: Option<u64>) -> Self {
+        let locate = |mut e: IoError| {
+            e.file_id = e.file_id.or(Some(file_id));
+            e.offset = e.offset.or(offset);
+            e
+        };
+        match self {
+            Errors::FailToReadFromDataFile(e) => Errors::FailToReadFromDataFile(locate(e)),
+            Errors::FailToSyncDataFile(e) => Errors::FailToSyncDataFile(locate(e)),
+            Errors::FailToWriteToDataFile(e) => Errors::FailToWriteToDataFile(locate(e)),
+            Errors::FailToOpenDataFile(e) => Errors::FailToOpenDataFile(locate(e)),
+            Errors::FailToCloseDataFile(e) => Errors::FailToCloseDataFile(locate(e)),
+            Errors::FailToRemoveDataFile(e) => Errors::FailToRemoveDataFile(locate(e)),
+            e => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_error(errno: i32) -> IoError {
+        io::Error::from_raw_os_error(errno).into()
+    }
+
+    #[test]
+    fn test_error_classify() {
+        let no_space = Errors::FailToWriteToDataFile(os_error(libc::ENOSPC));
+        assert_eq!(no_space.io_kind(), Some(io::ErrorKind::StorageFull));
+        assert!(no_space.is_retryable() && !no_space.is_fatal());
+
+        let eio = Errors::FailToReadFromDataFile(os_error(libc::EIO));
+        assert!(!eio.is_retryable() && eio.is_fatal());
+
+        let sync = Errors::FailToSyncDataFile(os_error(libc::ENOSPC));
+        assert!(!sync.is_retryable() && sync.is_fatal());
+
+        let not_found = Errors::FailToOpenDataFile(os_error(libc::ENOENT));
+        assert!(!not_found.is_retryable() && !not_found.is_fatal());
+
+        let mismatch = Errors::ChecksumMismatch {
+            file_id: 1,
+            offset: 2,
+            expected: 3,
+            actual: 4,
+        };
+        assert!(mismatch.is_corruption() && mismatch.is_fatal());
+        assert_eq!(mismatch.io_kind(), None);
+        assert!(!Errors::KeyNotFound.is_fatal() && !Errors::KeyNotFound.is_retryable());
+    }
+
+    #[test]
+    fn test_error_location() {
+        let err = Errors::FailToReadFromDataFile(os_error(libc::EIO)).at(3, Some(100));
+        assert!(matches!(
+            &err,
+            Errors::FailToReadFromDataFile(e) if e.file_id == Some(3) && e.offset == Some(100)
+        ));
+        assert!(err.to_string().ends_with("datafile 3, offset 100"));
+        // location set first is kept
+        assert!(matches!(
+            err.at(4, None),
+            Errors::FailToReadFromDataFile(e) if e.file_id == Some(3) && e.offset == Some(100)
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    ops::{Deref, DerefMut},
+    os::unix::prelude::{FileExt, OpenOptionsExt},
+    path::PathBuf,
+    ptr::NonNull,
+};
+
+use log::error;
+use parking_lot::{const_mutex, Mutex};
+
+use super::{
+    file_io::{file_size, preallocate_file},
+    io_manager::IOManager,
+};
+use crate::error::{Errors, Result};
+
+/// offset, length and memory address of O_DIRECT io must be aligned to this size
+const BLOCK_SIZE: usize = 4096;
+/// size of each buffer in the pool, larger io gets a dedicated buffer
+const POOL_BUFFER_SIZE: usize = 64 * 1024;
+/// max count of idle buffers kept by the pool
+const POOL_CAPACITY: usize = 32;
+
+static BUFFER_POOL: Mutex<Vec<AlignedBuffer>> = const_mutex(Vec::new());
+
+/// zeroed heap buffer whose address and length are aligned to `BLOCK_SIZE`
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// buffer owns its memory exclusively
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(align_up(len.max(1) as u64) as usize, BLOCK_SIZE)
+            .expect("invalid aligned buffer layout");
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuffer { ptr, layout }
+    }
+
+    /// take a buffer of at least @len bytes from pool, its content is undefined
+    fn get(len: usize) -> Self {
+        if len > POOL_BUFFER_SIZE {
+            return AlignedBuffer::new(len);
+        }
+        BUFFER_POOL
+            .lock()
+            .pop()
+            .unwrap_or_else(|| AlignedBuffer::new(POOL_BUFFER_SIZE))
+    }
+
+    /// give buffer back to pool
+    fn put(self) {
+        if self.layout.size() != POOL_BUFFER_SIZE {
+            return;
+        }
+        let mut pool = BUFFER_POOL.lock();
+        if pool.len() < POOL_CAPACITY {
+            pool.push(self);
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+fn align_down(offset: u64) -> u64 {
+    offset - offset % BLOCK_SIZE as u64
+}
+
+fn align_up(offset: u64) -> u64 {
+    align_down(offset + BLOCK_SIZE as u64 - 1)
+}
+
+/// io which bypasses page cache with O_DIRECT
+///
+/// unaligned reads are rounded to whole blocks, appends rewrite the last partial block,
+/// so file is padded with zeros after the logical end of data
+pub struct DirectIO {
+    fd: File,          // file descriptor
+    write_offset: u64, // logical end of data
+    tail: Vec<u8>,     // content of the last partial block
+}
+
+impl DirectIO {
+    pub fn new(file_path: &PathBuf) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .custom_flags(libc::O_DIRECT)
+            .open(file_path.as_path())
+            .map_err(|e| {
+                error!("failed to open file: {:?}, error: {:?}", file_path, e);
+                Errors::FailToOpenDataFile(e.into())
+            })?;
+        let len = fd
+            .metadata()
+            .map_err(|e| {
+                error!("failed to stat file: {:?}, error: {:?}", file_path, e);
+                Errors::FailToOpenDataFile(e.into())
+            })?
+            .len();
+
+        let mut direct_io = DirectIO {
+            fd,
+            write_offset: 0,
+            tail: Vec::with_capacity(BLOCK_SIZE),
+        };
+        // logical end is unknown until records are replayed, see `set_write_offset`
+        direct_io.set_write_offset(len)?;
+        Ok(direct_io)
+    }
+
+    fn read_aligned(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.fd.read_at(&mut buf[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl IOManager for DirectIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let start = align_down(offset);
+        let end = align_up(offset + buf.len() as u64);
+        let mut aligned = AlignedBuffer::get((end - start) as usize);
+        let res = self.read_aligned(&mut aligned[..(end - start) as usize], start);
+
+        let n_bytes = res.map(|n| {
+            let skip = (offset - start) as usize;
+            let n_bytes = n.saturating_sub(skip).min(buf.len());
+            buf[..n_bytes].copy_from_slice(&aligned[skip..skip + n_bytes]);
+            n_bytes
+        });
+        aligned.put();
+        n_bytes.map_err(|e| {
+            error!("read data file failed: {:?}", e);
+            Errors::FailToReadFromDataFile(e.into())
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let start = align_down(self.write_offset);
+        let data_len = self.tail.len() + buf.len();
+        let aligned_len = align_up(data_len as u64) as usize;
+
+        let mut aligned = AlignedBuffer::get(aligned_len);
+        aligned[..self.tail.len()].copy_from_slice(&self.tail);
+        aligned[self.tail.len()..data_len].copy_from_slice(buf);
+        aligned[data_len..aligned_len].fill(0);
+
+        let res = self.fd.write_all_at(&aligned[..aligned_len], start);
+        if res.is_ok() {
+            let tail_start = align_down(data_len as u64) as usize;
+            self.tail.clear();
+            self.tail.extend_from_slice(&aligned[tail_start..data_len]);
+            self.write_offset += buf.len() as u64;
+        }
+        aligned.put();
+
+        res.map(|_| buf.len()).map_err(|e| {
+            error!("write data file failed: {:?}", e);
+            Errors::FailToWriteToDataFile(e.into())
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.fd.sync_all().map_err(|e| {
+            error!("sync data file failed: {:?}", e);
+            Errors::FailToSyncDataFile(e.into())
+        })
+    }
+
+    fn size(&self) -> Result<u64> {
+        file_size(&self.fd)
+    }
+
+    fn preallocate(&self, size: u64) -> Result<()> {
+        preallocate_file(&self.fd, size)
+    }
+
+    fn set_write_offset(&mut self, offset: u64) -> Result<()> {
+        let start = align_down(offset);
+        let mut tail = vec![0u8; (offset - start) as usize];
+        self.read(&mut tail, start)?;
+        self.tail = tail;
+        self.write_offset = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::Builder;
+
+    use super::*;
+
+    #[test]
+    fn test_direct_io_write_read() {
+        let tmp_dir = Builder::new().prefix("bitcast-rs").tempdir().unwrap();
+        let path = tmp_dir.path().join("direct-io");
+        let mut file = DirectIO::new(&path).expect("failed to open file");
+
+        assert_eq!(file.write(&[1, 2, 3]), Ok(3));
+        assert_eq!(file.write("sadads".as_bytes()), Ok(6));
+        assert_eq!(file.write(&[]), Ok(0));
+        assert_eq!(file.sync(), Ok(()));
+
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read(&mut buf, 0), Ok(4));
+        assert_eq!(buf, [1, 2, 3, b's']);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read(&mut buf, 7), Ok(4));
+        assert_eq!(buf, [b'd', b's', 0, 0]);
+
+        // file is padded to a whole block
+        assert_eq!(fs::metadata(&path).unwrap().len(), BLOCK_SIZE as u64);
+
+        let large = (0..=255u8)
+            .cycle()
+            .take(3 * BLOCK_SIZE + 100)
+            .collect::<Vec<_>>();
+        assert_eq!(file.write(&large), Ok(large.len()));
+        let mut buf = vec![0u8; large.len()];
+        assert_eq!(file.read(&mut buf, 9), Ok(large.len()));
+        assert_eq!(buf, large);
+
+        // content of the partial block survives reopen once logical end is restored
+        drop(file);
+        let mut file = DirectIO::new(&path).expect("failed to reopen file");
+        assert_eq!(file.set_write_offset(9 + large.len() as u64), Ok(()));
+        assert_eq!(file.write(&[7, 7]), Ok(2));
+        let mut buf = [0u8; 3];
+        assert_eq!(file.read(&mut buf, 8 + large.len() as u64), Ok(3));
+        assert_eq!(buf, [large[large.len() - 1], 7, 7]);
+    }
+
+    #[test]
+    fn test_aligned_buffer() {
+        let buf = AlignedBuffer::new(10);
+        assert_eq!(buf.len(), BLOCK_SIZE);
+        assert_eq!(buf.as_ptr() as usize % BLOCK_SIZE, 0);
+        assert!(buf.iter().all(|b| *b == 0));
+
+        let buf = AlignedBuffer::get(POOL_BUFFER_SIZE + 1);
+        assert_eq!(buf.len(), POOL_BUFFER_SIZE + BLOCK_SIZE);
+        buf.put();
+
+        let buf = AlignedBuffer::get(100);
+        assert_eq!(buf.len(), POOL_BUFFER_SIZE);
+        buf.put();
+    }
+}
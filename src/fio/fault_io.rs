@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::warn;
+use parking_lot::Mutex;
+
+use super::{
+    file_io::{self, FileIO},
+    io_manager::IOManager,
+};
+use crate::error::{Errors, Result};
+
+/// FaultInjector shared fault plan and durability state of all files opened by `FaultIO`,
+/// it is used to simulate io failures and power loss in crash-consistency tests
+#[derive(Default)]
+pub struct FaultInjector {
+    state: Mutex<FaultState>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// total write calls, include the failed ones
+    writes: usize,
+    /// total sync calls, include the failed ones
+    syncs: usize,
+    /// the nth write (1-based) fails without writing anything
+    fail_write_at: Option<usize>,
+    /// the nth write (1-based) only writes half of its buffer and fails
+    short_write_at: Option<usize>,
+    /// the nth sync (1-based) fails
+    fail_sync_at: Option<usize>,
+    /// after a crash every io operation fails
+    crashed: bool,
+    /// logical end of written and synced data of each file
+    files: HashMap<PathBuf, FileState>,
+}
+
+#[derive(Clone, Copy)]
+struct FileState {
+    len: u64,
+    synced_len: u64,
+    /// whether directory entry of file is synced, otherwise file is lost on crash
+    linked: bool,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn fail_write_at(&self, n: usize) {
+        self.state.lock().fail_write_at = Some(n);
+    }
+
+    pub fn short_write_at(&self, n: usize) {
+        self.state.lock().short_write_at = Some(n);
+    }
+
+    pub fn fail_sync_at(&self, n: usize) {
+        self.state.lock().fail_sync_at = Some(n);
+    }
+
+    pub fn writes(&self) -> usize {
+        self.state.lock().writes
+    }
+
+    pub fn syncs(&self) -> usize {
+        self.state.lock().syncs
+    }
+
+    /// simulate a power loss, every byte which is not synced is dropped,
+    /// so are files created in a directory which is not synced afterwards,
+    /// and all io operations fail from now on
+    pub fn crash(&self) {
+        let mut state = self.state.lock();
+        state.crashed = true;
+        state.files.retain(|path, file| {
+            if !file.linked {
+                // file may be never created if opening it failed
+                let _ = fs::remove_file(path);
+            }
+            // files removed by engine are forgotten
+            file.linked && path.exists()
+        });
+        for (path, file) in state.files.iter_mut() {
+            let fd = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .expect("failed to open file for crash simulation");
+            fd.set_len(file.synced_len)
+                .expect("failed to drop unsynced data");
+            file.len = file.synced_len;
+        }
+    }
+
+    /// flip bits of byte at @offset with @mask, the file is modified directly on disk
+    pub fn flip_bit(path: &Path, offset: u64, mask: u8) {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .expect("failed to open file for bit flip");
+        let mut buf = [0u8; 1];
+        fd.read_exact_at(&mut buf, offset)
+            .expect("failed to read byte to flip");
+        buf[0] ^= mask;
+        fd.write_all_at(&buf, offset)
+            .expect("failed to write flipped byte");
+    }
+
+    /// sync directory @dir_path, files inside it survive a crash from now on
+    pub fn sync_dir(&self, dir_path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        if state.crashed {
+            return Err(Errors::FailToSyncDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+        for (_, file) in state
+            .files
+            .iter_mut()
+            .filter(|(path, _)| path.parent() == Some(dir_path))
+        {
+            file.linked = true;
+        }
+        Ok(())
+    }
+
+    /// rename file @from to @to, its state moves along, it is linked if either is
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        if state.crashed {
+            return Err(Errors::FailToWriteToDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+        file_io::rename_file(from, to)?;
+        let replaced = state.files.remove(to);
+        if let Some(mut file) = state.files.remove(from) {
+            file.linked |= replaced.is_some_and(|replaced| replaced.linked);
+            state.files.insert(to.to_path_buf(), file);
+        }
+        Ok(())
+    }
+
+    fn register(&self, path: &Path) {
+        let metadata = fs::metadata(path);
+        let linked = metadata.is_ok();
+        let len = metadata.map(|m| m.len()).unwrap_or_default();
+        // data which is already on disk when file is opened is treated as synced
+        self.state
+            .lock()
+            .files
+            .entry(path.to_path_buf())
+            .or_insert(FileState {
+                len,
+                synced_len: len,
+                linked,
+            });
+    }
+}
+
+/// io manager wrapper which injects failures according to its `FaultInjector`
+pub struct FaultIO {
+    path: PathBuf,
+    inner: FileIO,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultIO {
+    pub fn new(path: PathBuf, injector: Arc<FaultInjector>) -> Result<Self> {
+        injector.register(&path);
+        let inner = FileIO::new(&path)?;
+        Ok(FaultIO {
+            path,
+            inner,
+            injector,
+        })
+    }
+}
+
+impl IOManager for FaultIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if self.injector.state.lock().crashed {
+            return Err(Errors::FailToReadFromDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+        self.inner.read(buf, offset)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut state = self.injector.state.lock();
+        if state.crashed {
+            return Err(Errors::FailToWriteToDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+
+        state.writes += 1;
+        let n = state.writes;
+        if state.fail_write_at == Some(n) {
+            warn!("inject write failure at write {}", n);
+            return Err(Errors::FailToWriteToDataFile(
+                io::Error::other("injected write failure").into(),
+            ));
+        }
+
+        let short = state.short_write_at == Some(n);
+        let buf = if short { &buf[..buf.len() / 2] } else { buf };
+        let written = self.inner.write(buf)?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.len += written as u64;
+        }
+
+        if short {
+            warn!(
+                "inject short write at write {}, {} bytes written",
+                n, written
+            );
+            return Err(Errors::FailToWriteToDataFile(
+                io::Error::other("injected short write").into(),
+            ));
+        }
+        Ok(written)
+    }
+
+    fn size(&self) -> Result<u64> {
+        if self.injector.state.lock().crashed {
+            return Err(Errors::FailToReadFromDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+        self.inner.size()
+    }
+
+    fn set_write_offset(&mut self, offset: u64) -> Result<()> {
+        self.inner.set_write_offset(offset)?;
+        if let Some(file) = self.injector.state.lock().files.get_mut(&self.path) {
+            file.len = offset;
+        }
+        Ok(())
+    }
+
+    fn preallocate(&self, size: u64) -> Result<()> {
+        self.inner.preallocate(size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut state = self.injector.state.lock();
+        if state.crashed {
+            return Err(Errors::FailToSyncDataFile(
+                io::Error::other("simulated crash").into(),
+            ));
+        }
+
+        state.syncs += 1;
+        if state.fail_sync_at == Some(state.syncs) {
+            warn!("inject sync failure at sync {}", state.syncs);
+            return Err(Errors::FailToSyncDataFile(
+                io::Error::other("injected sync failure").into(),
+            ));
+        }
+
+        self.inner.sync()?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.synced_len = file.len;
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::Write,
-    os::unix::prelude::FileExt,
-    path::PathBuf,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::prelude::{AsRawFd, FileExt},
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
@@ -15,6 +15,7 @@ use crate::error::{Errors, Result};
 /// standard system io
 pub struct FileIO {
     fd: Arc<RwLock<File>>, // file descriptor
+    write_offset: u64,     // logical end of data, file may be preallocated beyond it
 }
 
 impl FileIO {
@@ -23,42 +24,138 @@ impl FileIO {
             .create(true)
             .read(true)
             .write(true)
-            .append(true)
+            .truncate(false)
             .open(file_path.as_path())
-            .map(|f| FileIO {
-                fd: Arc::new(RwLock::new(f)),
+            .and_then(|f| {
+                Ok(FileIO {
+                    write_offset: f.metadata()?.len(),
+                    fd: Arc::new(RwLock::new(f)),
+                })
             })
             .map_err(|e| {
                 error!("failed to open file: {:?}, error: {:?}", file_path, e);
-                Errors::FailToOpenDataFile(e.to_string())
+                Errors::FailToOpenDataFile(e.into())
             })
     }
 }
 
+/// allocate disk space of first @size bytes of file, allocated range reads as zeros
+pub(crate) fn preallocate_file(fd: &File, size: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let res = unsafe { libc::fallocate(fd.as_raw_fd(), 0, 0, size as libc::off_t) };
+        if res != 0 {
+            let e = std::io::Error::last_os_error();
+            error!("preallocate data file failed: {:?}", e);
+            return Err(Errors::FailToWriteToDataFile(e.into()));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (fd, size);
+
+    Ok(())
+}
+
+/// size of file @fd on disk
+pub(crate) fn file_size(fd: &File) -> Result<u64> {
+    fd.metadata().map(|m| m.len()).map_err(|e| {
+        error!("read metadata of data file failed: {:?}", e);
+        Errors::FailToReadFromDataFile(e.into())
+    })
+}
+
+/// fsync directory @dir_path to persist its entries
+pub(crate) fn sync_dir(dir_path: &Path) -> Result<()> {
+    File::open(dir_path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| {
+            error!("sync directory {:?} failed: {:?}", dir_path, e);
+            Errors::FailToSyncDataFile(e.into())
+        })
+}
+
+/// rename file @from to @to, the entries are durable after its directory is synced
+pub(crate) fn rename_file(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to).map_err(|e| {
+        error!("rename file {:?} to {:?} failed: {:?}", from, to, e);
+        Errors::FailToWriteToDataFile(e.into())
+    })
+}
+
+/// take an exclusive advisory lock on file @path, it is created when absent,
+/// the lock is held until it is unlocked or the returned file is closed
+pub(crate) fn lock_file(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| {
+            error!("open lock file {:?} failed: {:?}", path, e);
+            Errors::FailToOpenDataFile(e.into())
+        })?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Err(Errors::DatabaseLocked);
+        }
+        error!("lock file {:?} failed: {:?}", path, e);
+        return Err(Errors::FailToOpenDataFile(e.into()));
+    }
+    Ok(file)
+}
+
+/// release lock taken by `lock_file`
+pub(crate) fn unlock_file(file: &File) -> Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } != 0 {
+        let e = io::Error::last_os_error();
+        error!("unlock file failed: {:?}", e);
+        return Err(Errors::FailToCloseDataFile(e.into()));
+    }
+    Ok(())
+}
+
 impl IOManager for FileIO {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         let read_guard = self.fd.read();
         read_guard.read_at(buf, offset).map_err(|e| {
             error!("read data file failed: {:?}", e);
-            Errors::FailToReadFromDataFile(e.to_string())
+            Errors::FailToReadFromDataFile(e.into())
         })
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut write_guard = self.fd.write();
-        write_guard.write(buf).map_err(|e| {
-            error!("write data file failed: {:?}", e);
-            Errors::FailToWriteToDataFile(e.to_string())
-        })
+        let write_guard = self.fd.write();
+        write_guard
+            .write_all_at(buf, self.write_offset)
+            .map_err(|e| {
+                error!("write data file failed: {:?}", e);
+                Errors::FailToWriteToDataFile(e.into())
+            })?;
+        self.write_offset += buf.len() as u64;
+        Ok(buf.len())
     }
 
     fn sync(&self) -> Result<()> {
         let read_guard = self.fd.read();
         read_guard.sync_all().map_err(|e| {
             error!("sync data file failed: {:?}", e);
-            Errors::FailToReadFromDataFile(e.to_string())
+            Errors::FailToSyncDataFile(e.into())
         })
     }
+
+    fn size(&self) -> Result<u64> {
+        file_size(&self.fd.read())
+    }
+
+    fn set_write_offset(&mut self, offset: u64) -> Result<()> {
+        self.write_offset = offset;
+        Ok(())
+    }
+
+    fn preallocate(&self, size: u64) -> Result<()> {
+        preallocate_file(&self.fd.read(), size)
+    }
 }
 
 #[cfg(test)]
@@ -152,4 +249,29 @@ mod tests {
 
         assert!(fs::remove_file(path).is_ok());
     }
+
+    #[test]
+    fn test_file_preallocate() {
+        let path = PathBuf::from_str(temp_file_path().as_str()).unwrap();
+        let mut file = FileIO::new(path.borrow()).unwrap();
+        assert_eq!(file.preallocate(4096), Ok(()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096);
+
+        // writes start from logical end instead of end of file
+        assert_eq!(file.write(&[1, 2, 3]), Ok(3));
+        let mut buf = [9u8; 5];
+        assert_eq!(file.read(&mut buf, 0), Ok(5));
+        assert_eq!(buf, [1, 2, 3, 0, 0]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096);
+
+        drop(file);
+        let mut file = FileIO::new(path.borrow()).unwrap();
+        assert_eq!(file.set_write_offset(3), Ok(()));
+        assert_eq!(file.write(&[4]), Ok(1));
+        let mut buf = [9u8; 5];
+        assert_eq!(file.read(&mut buf, 0), Ok(5));
+        assert_eq!(buf, [1, 2, 3, 4, 0]);
+
+        assert!(fs::remove_file(path).is_ok());
+    }
 }
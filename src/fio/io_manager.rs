@@ -1,11 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::error::Result;
+use crate::{error::Result, options::IOType};
 
-use super::file_io::FileIO;
+use super::file_io::{self, FileIO};
 
 /// IOManager provide a abstract interface for io manuplation
-pub trait IOManager {
+pub trait IOManager: Sync + Send {
     /// read from @offset of a file
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
 
@@ -14,9 +14,66 @@ pub trait IOManager {
 
     /// flush data to consistant file
     fn sync(&self) -> Result<()>;
+
+    /// size of file on disk, including preallocated space
+    fn size(&self) -> Result<u64>;
+
+    /// read each buffer from its own offset, returns bytes read of each buffer
+    fn read_batch(&self, reads: &mut [(&mut [u8], u64)]) -> Result<Vec<usize>> {
+        reads
+            .iter_mut()
+            .map(|(buf, offset)| self.read(buf, *offset))
+            .collect()
+    }
+
+    /// move write cursor to @offset, it is the logical end of data found by replaying records
+    fn set_write_offset(&mut self, _offset: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// allocate disk space for first @size bytes of file, it never moves write cursor
+    fn preallocate(&self, _size: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// write buffer to a file and flush it to consistant file
+    fn write_and_sync(&mut self, buf: &[u8]) -> Result<usize> {
+        let n_bytes = self.write(buf)?;
+        self.sync()?;
+        Ok(n_bytes)
+    }
+}
+
+pub(crate) fn new_io_manager(file_path: PathBuf, io_type: &IOType) -> Result<Box<dyn IOManager>> {
+    match io_type {
+        IOType::StandardFIO => Ok(Box::new(FileIO::new(&file_path)?)),
+        #[cfg(target_os = "linux")]
+        IOType::DirectIO => Ok(Box::new(super::direct_io::DirectIO::new(&file_path)?)),
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        IOType::IoUring => Ok(Box::new(super::uring_io::UringIO::new(&file_path)?)),
+        #[cfg(test)]
+        IOType::FaultInjection(injector) => Ok(Box::new(super::fault_io::FaultIO::new(
+            file_path,
+            injector.clone(),
+        )?)),
+    }
+}
+
+/// sync directory entries of @dir_path, so files created, renamed or removed
+/// inside it survive a power loss
+pub(crate) fn sync_dir(dir_path: &Path, io_type: &IOType) -> Result<()> {
+    match io_type {
+        #[cfg(test)]
+        IOType::FaultInjection(injector) => injector.sync_dir(dir_path),
+        _ => file_io::sync_dir(dir_path),
+    }
 }
 
-pub(crate) fn new_io_manager(file_path: PathBuf) -> Result<Box<impl IOManager>> {
-    let file_io = FileIO::new(&file_path)?;
-    Ok(Box::new(file_io))
+/// rename file @from to @to, it survives a power loss after `sync_dir`
+pub(crate) fn rename_file(from: &Path, to: &Path, io_type: &IOType) -> Result<()> {
+    match io_type {
+        #[cfg(test)]
+        IOType::FaultInjection(injector) => injector.rename(from, to),
+        _ => file_io::rename_file(from, to),
+    }
 }
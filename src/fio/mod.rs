@@ -1,4 +1,13 @@
 pub mod file_io;
 pub mod io_manager;
 
+#[cfg(target_os = "linux")]
+pub mod direct_io;
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+pub mod uring_io;
+
+#[cfg(test)]
+pub mod fault_io;
+
 pub use io_manager::IOManager;
@@ -0,0 +1,388 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::prelude::AsRawFd,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use io_uring::{opcode, squeue, types, EnterFlags, IoUring};
+use log::error;
+use parking_lot::Mutex;
+
+use super::{
+    file_io::{file_size, preallocate_file},
+    io_manager::IOManager,
+};
+use crate::error::{Errors, Result};
+
+/// max in-flight operations of a ring, larger batches are split
+const URING_ENTRIES: usize = 64;
+
+/// io_uring based io, batch reads and linked write + fsync are submitted
+/// with a single system call
+pub struct UringIO {
+    fd: File,             // file descriptor
+    ring: Mutex<IoUring>, // submission and completion queues
+    broken: AtomicBool,   // a submission failed, the ring is never entered again
+    write_offset: u64,    // logical end of data, writes are positional
+}
+
+impl UringIO {
+    pub fn new(file_path: &PathBuf) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(file_path.as_path())
+            .map_err(|e| {
+                error!("failed to open file: {:?}, error: {:?}", file_path, e);
+                Errors::FailToOpenDataFile(e.into())
+            })?;
+        let write_offset = fd
+            .metadata()
+            .map_err(|e| {
+                error!("failed to stat file: {:?}, error: {:?}", file_path, e);
+                Errors::FailToOpenDataFile(e.into())
+            })?
+            .len();
+        let ring = IoUring::new(URING_ENTRIES as u32).map_err(|e| {
+            error!("failed to setup io_uring, error: {:?}", e);
+            Errors::FailToOpenDataFile(e.into())
+        })?;
+
+        Ok(UringIO {
+            fd,
+            ring: Mutex::new(ring),
+            broken: AtomicBool::new(false),
+            write_offset,
+        })
+    }
+
+    fn fd(&self) -> types::Fd {
+        types::Fd(self.fd.as_raw_fd())
+    }
+
+    /// submit @entries and wait all of them, result of each entry is returned in order,
+    /// every entry the kernel takes is reaped before returning, even when submission
+    /// fails, a failed submission breaks the ring and every later one fails
+    ///
+    /// # Safety
+    ///
+    /// buffers referred by entries must be valid until this function returns
+    unsafe fn submit(&self, entries: &[squeue::Entry]) -> io::Result<Vec<i32>> {
+        let mut ring = self.ring.lock();
+        if self.broken.load(Ordering::Acquire) {
+            return Err(io::Error::other(
+                "io_uring is broken by a failed submission",
+            ));
+        }
+        let mut results = vec![0; entries.len()];
+        for (chunk_id, chunk) in entries.chunks(URING_ENTRIES).enumerate() {
+            let base = chunk_id * URING_ENTRIES;
+            let mut queued = 0;
+            let mut pushed = Ok(());
+            for (i, entry) in chunk.iter().enumerate() {
+                if ring
+                    .submission()
+                    .push(&entry.clone().user_data((base + i) as u64))
+                    .is_err()
+                {
+                    pushed = Err(io::Error::other("io_uring submission queue is full"));
+                    break;
+                }
+                queued += 1;
+            }
+
+            // queued entries point to buffers of caller, returning before they complete
+            // lets kernel use freed memory, and stale entries would be submitted later
+            let mut completed = 0;
+            while completed < queued {
+                if let Err(e) = ring.submit_and_wait(queued - completed) {
+                    match e.raw_os_error() {
+                        Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {}
+                        _ => {
+                            error!("io_uring submission failed with entries queued: {:?}", e);
+                            self.broken.store(true, Ordering::Release);
+                            // entries left in submission queue are never submitted,
+                            // those the kernel took still use buffers of caller
+                            let in_flight = queued - completed - ring.submission().len();
+                            drain(&mut ring, in_flight);
+                            return Err(e);
+                        }
+                    }
+                }
+                for cqe in ring.completion() {
+                    results[cqe.user_data() as usize] = cqe.result();
+                    completed += 1;
+                }
+            }
+            pushed?;
+        }
+        Ok(results)
+    }
+}
+
+/// wait @in_flight entries of a broken @ring to complete without submitting anything,
+/// the kernel completes every entry it takes, so this ends once it reports them
+fn drain(ring: &mut IoUring, mut in_flight: usize) {
+    while in_flight > 0 {
+        let res = unsafe {
+            ring.submitter().enter::<libc::sigset_t>(
+                0,
+                in_flight as u32,
+                EnterFlags::GETEVENTS.bits(),
+                None,
+            )
+        };
+        if let Err(e) = res {
+            if e.raw_os_error() != Some(libc::EINTR) {
+                error!("wait io_uring completions failed: {:?}", e);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        in_flight -= ring.completion().count();
+    }
+}
+
+fn completion_result(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+impl IOManager for UringIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(self.read_batch(&mut [(buf, offset)])?[0])
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let remain = &buf[written..];
+            let entry = opcode::Write::new(self.fd(), remain.as_ptr(), remain.len() as u32)
+                .offset(self.write_offset + written as u64)
+                .build();
+            let n = unsafe { self.submit(&[entry]) }
+                .and_then(|res| completion_result(res[0]))
+                .map_err(|e| {
+                    error!("write data file failed: {:?}", e);
+                    Errors::FailToWriteToDataFile(e.into())
+                })?;
+            if n == 0 {
+                break;
+            }
+            written += n;
+        }
+        self.write_offset += written as u64;
+        Ok(written)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let entry = opcode::Fsync::new(self.fd()).build();
+        unsafe { self.submit(&[entry]) }
+            .and_then(|res| completion_result(res[0]))
+            .map(|_| ())
+            .map_err(|e| {
+                error!("sync data file failed: {:?}", e);
+                Errors::FailToSyncDataFile(e.into())
+            })
+    }
+
+    fn size(&self) -> Result<u64> {
+        file_size(&self.fd)
+    }
+
+    fn set_write_offset(&mut self, offset: u64) -> Result<()> {
+        self.write_offset = offset;
+        Ok(())
+    }
+
+    fn preallocate(&self, size: u64) -> Result<()> {
+        preallocate_file(&self.fd, size)
+    }
+
+    fn read_batch(&self, reads: &mut [(&mut [u8], u64)]) -> Result<Vec<usize>> {
+        let entries = reads
+            .iter_mut()
+            .map(|(buf, offset)| {
+                opcode::Read::new(self.fd(), buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        unsafe { self.submit(&entries) }
+            .and_then(|res| res.into_iter().map(completion_result).collect())
+            .map_err(|e| {
+                error!("read data file failed: {:?}", e);
+                Errors::FailToReadFromDataFile(e.into())
+            })
+    }
+
+    fn write_and_sync(&mut self, buf: &[u8]) -> Result<usize> {
+        let write = opcode::Write::new(self.fd(), buf.as_ptr(), buf.len() as u32)
+            .offset(self.write_offset)
+            .build()
+            .flags(squeue::Flags::IO_LINK);
+        let fsync = opcode::Fsync::new(self.fd()).build();
+        // written data is unknown when the ring breaks, it fails as a sync
+        let res = unsafe { self.submit(&[write, fsync]) }.map_err(|e| {
+            error!("write and sync data file failed: {:?}", e);
+            Errors::FailToSyncDataFile(e.into())
+        })?;
+
+        let n_bytes = completion_result(res[0]).map_err(|e| {
+            error!("write data file failed: {:?}", e);
+            Errors::FailToWriteToDataFile(e.into())
+        })?;
+        self.write_offset += n_bytes as u64;
+        if n_bytes < buf.len() {
+            // a short write breaks the link and cancels fsync, finish it step by step
+            let n_rest = self.write(&buf[n_bytes..])?;
+            self.sync()?;
+            return Ok(n_bytes + n_rest);
+        }
+
+        completion_result(res[1]).map_err(|e| {
+            error!("sync data file failed: {:?}", e);
+            Errors::FailToSyncDataFile(e.into())
+        })?;
+        Ok(n_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn temp_file_path() -> PathBuf {
+        temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn test_uring_io_write_read() {
+        let path = temp_file_path();
+        let mut file = UringIO::new(&path).expect("failed to open file");
+
+        assert_eq!(file.write(&[1, 2, 3]), Ok(3));
+        assert_eq!(file.write("sadads".as_bytes()), Ok(6));
+        assert_eq!(file.write(&[]), Ok(0));
+        assert_eq!(file.write_and_sync(&[4, 5]), Ok(2));
+
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read(&mut buf, 0), Ok(4));
+        assert_eq!(buf, [1, 2, 3, b's']);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read(&mut buf, 9), Ok(2));
+        assert_eq!(buf, [4, 5, 0, 0]);
+
+        assert_eq!(file.sync(), Ok(()));
+
+        drop(file);
+        let mut file = UringIO::new(&path).expect("failed to reopen file");
+        assert_eq!(file.write(&[6]), Ok(1));
+        let mut buf = [0u8; 3];
+        assert_eq!(file.read(&mut buf, 9), Ok(3));
+        assert_eq!(buf, [4, 5, 6]);
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+
+    #[test]
+    fn test_uring_io_failed_entry() {
+        let path = temp_file_path();
+        let mut file = UringIO::new(&path).expect("failed to open file");
+        assert_eq!(file.write(&[1, 2, 3, 4]), Ok(4));
+
+        // failed entries of a batch are reaped with the others, so nothing is
+        // left in the ring for the next submission
+        let mut bufs = (0..URING_ENTRIES + 10)
+            .map(|_| vec![0u8; 4])
+            .collect::<Vec<_>>();
+        let entries = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| {
+                let fd = match i % 3 {
+                    0 => types::Fd(-1),
+                    _ => file.fd(),
+                };
+                opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(0)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let res = unsafe { file.submit(&entries) }.expect("failed to submit entries");
+        for (i, res) in res.into_iter().enumerate() {
+            match i % 3 {
+                0 => assert_eq!(res, -libc::EBADF),
+                _ => assert_eq!(res, 4),
+            }
+        }
+
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read(&mut buf, 0), Ok(4));
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+
+    #[test]
+    fn test_uring_io_broken_ring() {
+        let path = temp_file_path();
+        let mut file = UringIO::new(&path).expect("failed to open file");
+        assert_eq!(file.write(&[1, 2, 3, 4]), Ok(4));
+
+        // a broken ring fails every operation instead of entering it again
+        file.broken.store(true, Ordering::Release);
+        let mut buf = [0u8; 4];
+        assert!(matches!(
+            file.read(&mut buf, 0),
+            Err(Errors::FailToReadFromDataFile(_))
+        ));
+        assert!(matches!(
+            file.write(&[5]),
+            Err(Errors::FailToWriteToDataFile(_))
+        ));
+        assert!(matches!(file.sync(), Err(Errors::FailToSyncDataFile(_))));
+        assert!(matches!(
+            file.write_and_sync(&[5]),
+            Err(Errors::FailToSyncDataFile(_))
+        ));
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+
+    #[test]
+    fn test_uring_io_read_batch() {
+        let path = temp_file_path();
+        let mut file = UringIO::new(&path).expect("failed to open file");
+        let content = (0..=255u8).cycle().take(100 * 1024).collect::<Vec<_>>();
+        assert_eq!(file.write(&content), Ok(content.len()));
+
+        let offsets = (0..200u64).map(|i| i * 500).collect::<Vec<_>>();
+        let mut bufs = offsets.iter().map(|_| vec![0u8; 16]).collect::<Vec<_>>();
+        let mut reads = bufs
+            .iter_mut()
+            .zip(offsets.iter())
+            .map(|(buf, offset)| (buf.as_mut_slice(), *offset))
+            .collect::<Vec<_>>();
+        assert_eq!(file.read_batch(&mut reads), Ok(vec![16; offsets.len()]));
+        for (buf, offset) in bufs.iter().zip(offsets) {
+            let offset = offset as usize;
+            assert_eq!(buf.as_slice(), &content[offset..offset + 16]);
+        }
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+}
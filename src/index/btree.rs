@@ -211,7 +211,7 @@ mod tests {
             },
         );
 
-        assert_eq!(res, true);
+        assert!(res);
         assert_eq!(
             bt.get(vec![]),
             Some(LogRecordPos {
@@ -377,6 +377,7 @@ mod tests {
         let options = IndexIteratorOptions {
             prefix: Default::default(),
             reverse: true,
+            ..Default::default()
         };
 
         // no record
@@ -500,6 +501,7 @@ mod tests {
         let options = IndexIteratorOptions {
             prefix: "prefix_".into(),
             reverse: false,
+            ..Default::default()
         };
 
         let indexer = BTreeIndexer::new();
@@ -608,6 +610,7 @@ mod tests {
         let options = IndexIteratorOptions {
             prefix: "prefix_".into(),
             reverse: true,
+            ..Default::default()
         };
 
         let indexer = BTreeIndexer::new();
@@ -1,50 +1,125 @@
-use std::sync::Arc;
+use std::{collections::VecDeque, ops::Deref, sync::Arc};
 
 use bytes::Bytes;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use crate::{
-    db::Engine, error::Result, index::indexer::IndexIterator, options::IndexIteratorOptions,
+    db::{Db, Engine},
+    error::Result,
+    index::indexer::IndexIterator,
+    options::{IndexIteratorOptions, ReadOptions},
 };
 
-pub struct Iterator<'a> {
+/// iterator borrowing an engine
+pub type Iterator<'a> = GenericIterator<&'a Engine>;
+/// iterator owning a shared engine, it is `'static` and can be moved to other threads
+pub type OwnedIterator = GenericIterator<Arc<Engine>>;
+
+pub struct GenericIterator<E: Deref<Target = Engine>> {
     index_iterator: Arc<RwLock<Box<dyn IndexIterator>>>,
-    engine: &'a Engine,
+    engine: E,
+    prefetch: usize,
+    prefetched: Arc<Mutex<VecDeque<(Bytes, Bytes)>>>, // values read ahead
+    read_options: ReadOptions,
 }
 
-impl<'a> Iterator<'a> {
-    pub(crate) fn new(index_iterator: Box<dyn IndexIterator>, engine: &'a Engine) -> Self {
+impl<E: Deref<Target = Engine>> GenericIterator<E> {
+    /// @engine must have pinned blob files by `pin_blobs` before @index_iterator is
+    /// created, the iterator unpins them when dropped
+    pub(crate) fn new(
+        index_iterator: Box<dyn IndexIterator>,
+        engine: E,
+        prefetch: usize,
+        read_options: ReadOptions,
+    ) -> Self {
         Self {
             index_iterator: Arc::new(RwLock::new(index_iterator)),
             engine,
+            prefetch,
+            prefetched: Default::default(),
+            read_options,
         }
     }
 
     pub fn rewind(&self) {
+        self.prefetched.lock().clear();
         self.index_iterator.write().rewind();
     }
 
     pub fn seek(&self, key: Bytes) {
+        self.prefetched.lock().clear();
         self.index_iterator.write().seek(key.as_ref())
     }
 
+    /// next key and its value, a key whose record is a tombstone is skipped,
+    /// as it is by replay of datafiles
     pub fn next(&self) -> Result<Option<(Bytes, Bytes)>> {
-        let (key, pos) = match self.index_iterator.write().next() {
-            Some((key, pos)) => (key.clone(), *pos),
-            None => {
-                return Ok(None);
+        if self.prefetch > 1 {
+            return self.next_prefetched();
+        }
+
+        loop {
+            let (key, pos) = match self.index_iterator.write().next() {
+                Some((key, pos)) => (key.clone(), *pos),
+                None => {
+                    return Ok(None);
+                }
+            };
+
+            if let Some(value) = self.engine.value_at_position(&pos, &self.read_options)? {
+                return Ok(Some((key.into(), value)));
             }
-        };
+        }
+    }
+
+    /// read values of next `prefetch` entries in a batch, then return them one by one,
+    /// tombstones are skipped like `next` does
+    fn next_prefetched(&self) -> Result<Option<(Bytes, Bytes)>> {
+        let mut prefetched = self.prefetched.lock();
+        while prefetched.is_empty() {
+            let mut index_iterator = self.index_iterator.write();
+            let (keys, positions): (Vec<_>, Vec<_>) = (0..self.prefetch)
+                .map_while(|_| index_iterator.next().map(|(key, pos)| (key.clone(), *pos)))
+                .unzip();
+            if keys.is_empty() {
+                break;
+            }
+            let values = self
+                .engine
+                .get_by_positions(&positions, &self.read_options)?;
+            prefetched.extend(
+                keys.into_iter()
+                    .zip(values)
+                    .filter_map(|(key, value)| value.map(|value| (key.into(), value))),
+            );
+        }
 
-        let value = self.engine.get_by_position(&pos)?;
+        Ok(prefetched.pop_front())
+    }
+}
 
-        Ok(Some((key.into(), value)))
+impl<E: Deref<Target = Engine>> Drop for GenericIterator<E> {
+    fn drop(&mut self) {
+        self.engine.unpin_blobs();
     }
 }
 
 impl Engine {
-    pub fn iterator(&self, options: IndexIteratorOptions) -> Iterator {
-        Iterator::new(self.indexer.iterator(options), self)
+    pub fn iterator(&self, options: IndexIteratorOptions) -> Iterator<'_> {
+        let prefetch = options.prefetch;
+        let read_options = options.read_options.clone();
+        self.pin_blobs();
+        Iterator::new(self.indexer.iterator(options), self, prefetch, read_options)
+    }
+}
+
+impl Db {
+    pub fn iterator(&self, options: IndexIteratorOptions) -> OwnedIterator {
+        let prefetch = options.prefetch;
+        let read_options = options.read_options.clone();
+        self.pin_blobs();
+        let index_iterator = self.indexer.iterator(options);
+        OwnedIterator::new(index_iterator, self.engine(), prefetch, read_options)
     }
 }
 
@@ -55,20 +130,25 @@ mod tests {
     use tempfile::Builder;
 
     use crate::{
+        data::log_record::LogRecordPos,
         db::Engine,
+        error::Errors,
         options::{IndexIteratorOptions, Options},
+        utils::rand_kv::{get_test_key, get_test_value},
     };
 
     #[test]
     fn test_iterator_rewind() {
-        let mut opts = Options::default();
-        opts.dir_path = Builder::new()
-            .prefix("bitcast-rs")
-            .tempdir()
-            .unwrap()
-            .path()
-            .to_path_buf();
-        opts.datafile_size = 64 * 1024 * 1024;
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
 
         let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
@@ -90,14 +170,16 @@ mod tests {
 
     #[test]
     fn test_iterator_seek_next() {
-        let mut opts = Options::default();
-        opts.dir_path = Builder::new()
-            .prefix("bitcast-rs")
-            .tempdir()
-            .unwrap()
-            .path()
-            .to_path_buf();
-        opts.datafile_size = 64 * 1024 * 1024;
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
 
         let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
@@ -131,6 +213,7 @@ mod tests {
         let iterator_options = IndexIteratorOptions {
             prefix: Default::default(),
             reverse: true,
+            ..Default::default()
         };
         let iterator = engine.iterator(iterator_options);
         assert_eq!(iterator.next(), Ok(Some(("key1".into(), "value1".into()))));
@@ -166,20 +249,23 @@ mod tests {
 
     #[test]
     fn test_iterator_seek_prefix_next() {
-        let mut opts = Options::default();
-        opts.dir_path = Builder::new()
-            .prefix("bitcast-rs")
-            .tempdir()
-            .unwrap()
-            .path()
-            .to_path_buf();
-        opts.datafile_size = 64 * 1024 * 1024;
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
 
         let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
         let iterator_options = IndexIteratorOptions {
             prefix: "prefix_".into(),
             reverse: false,
+            ..Default::default()
         };
         let iterator = engine.iterator(iterator_options.clone());
         assert_eq!(iterator.next(), Ok(None));
@@ -233,6 +319,7 @@ mod tests {
         let iterator_options = IndexIteratorOptions {
             prefix: "prefix_".into(),
             reverse: true,
+            ..Default::default()
         };
         let iterator = engine.iterator(iterator_options);
         assert_eq!(
@@ -295,4 +382,93 @@ mod tests {
         iterator.seek("prefix_kex".into());
         assert_eq!(iterator.next(), Ok(None));
     }
+
+    #[test]
+    fn test_iterator_prefetch() {
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024,
+            max_key_size: 1024,
+            ..Default::default()
+        };
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert_eq!(engine.put(get_test_key(i), get_test_value(i)), Ok(()));
+        }
+
+        let iterator = engine.iterator(IndexIteratorOptions {
+            prefetch: 64,
+            ..Default::default()
+        });
+        for i in 0..1000 {
+            assert_eq!(
+                iterator.next(),
+                Ok(Some((get_test_key(i), get_test_value(i))))
+            );
+        }
+        assert_eq!(iterator.next(), Ok(None));
+
+        iterator.seek(get_test_key(990));
+        for i in 990..1000 {
+            assert_eq!(
+                iterator.next(),
+                Ok(Some((get_test_key(i), get_test_value(i))))
+            );
+        }
+        assert_eq!(iterator.next(), Ok(None));
+
+        iterator.rewind();
+        assert_eq!(
+            iterator.next(),
+            Ok(Some((get_test_key(0), get_test_value(0))))
+        );
+    }
+
+    #[test]
+    fn test_iterator_skip_tombstone() {
+        let opts = Options {
+            dir_path: Builder::new()
+                .prefix("bitcast-rs")
+                .tempdir()
+                .unwrap()
+                .path()
+                .to_path_buf(),
+            datafile_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert_eq!(engine.put(get_test_key(i), get_test_value(i)), Ok(()));
+        }
+        // index refers a tombstone of key 50, with and without prefetch it is
+        // skipped as replay does, not returned as an error
+        let tombstone = LogRecordPos {
+            file_id: 0,
+            offset: engine.active_file.read().get_offset(),
+        };
+        assert_eq!(engine.delete(get_test_key(50)), Ok(()));
+        assert!(engine.indexer.put(get_test_key(50).to_vec(), tombstone));
+
+        for prefetch in [0, 64] {
+            let iterator = engine.iterator(IndexIteratorOptions {
+                prefetch,
+                ..Default::default()
+            });
+            for i in (0..100).filter(|i| *i != 50) {
+                assert_eq!(
+                    iterator.next(),
+                    Ok(Some((get_test_key(i), get_test_value(i))))
+                );
+            }
+            assert_eq!(iterator.next(), Ok(None));
+        }
+        assert_eq!(engine.get(get_test_key(50)), Err(Errors::KeyNotFound));
+    }
 }
@@ -4,11 +4,20 @@ pub mod error;
 pub mod options;
 
 pub mod batch;
+pub mod blob;
 pub mod iterator;
+pub mod recovery;
+pub mod scrub;
+pub mod stream;
 
 mod fio;
 mod index;
+mod manifest;
+mod options_file;
 mod utils;
 
 #[cfg(test)]
 mod db_test;
+
+#[cfg(test)]
+mod crash_test;
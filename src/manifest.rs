@@ -0,0 +1,443 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::{info, warn};
+
+use crate::{
+    data::{
+        data_file::{BLOBFILE_NAME_SUFFIX, DATAFILE_NAME_SUFFIX},
+        file_header::{FileHeader, FILE_HEADER_SIZE},
+    },
+    db::{list_file_ids, parse_file_id},
+    error::{Errors, Result},
+    fio::io_manager::{new_io_manager, rename_file, sync_dir, IOManager},
+    options::IOType,
+};
+
+/// name of file in database directory which logs creation, sealing and removal of files
+pub(crate) const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILE_NAME: &str = "MANIFEST.tmp";
+
+/// directory in database directory which orphan files holding records are moved to
+pub(crate) const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// | len u32 | crc u32 |, crc covers len and edits
+const EDIT_HEADER_SIZE: usize = 4 + 4;
+
+/// | tag u8 | file id u32 |
+const EDIT_SIZE: usize = 1 + 4;
+
+/// ManifestEdit a change of live files, a file only becomes live after it is created
+/// and synced, and it is removed from disk only after it is dead in the manifest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ManifestEdit {
+    /// a datafile with a durable header is created
+    NewData(u32),
+    /// a datafile is sealed, no record is appended to it anymore
+    SealData(u32),
+    /// a blob file with a durable header is created
+    NewBlob(u32),
+    /// a blob file is sealed, no blob is appended to it anymore
+    SealBlob(u32),
+    /// a blob file is merged and it is going to be removed
+    RemoveBlob(u32),
+}
+
+impl ManifestEdit {
+    fn encode(&self, buf: &mut BytesMut) {
+        let (tag, fid) = match *self {
+            ManifestEdit::NewData(fid) => (1, fid),
+            ManifestEdit::SealData(fid) => (2, fid),
+            ManifestEdit::NewBlob(fid) => (3, fid),
+            ManifestEdit::SealBlob(fid) => (4, fid),
+            ManifestEdit::RemoveBlob(fid) => (5, fid),
+        };
+        buf.put_u8(tag);
+        buf.put_u32_le(fid);
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        let tag = buf.get_u8();
+        let fid = buf.get_u32_le();
+        match tag {
+            1 => Some(ManifestEdit::NewData(fid)),
+            2 => Some(ManifestEdit::SealData(fid)),
+            3 => Some(ManifestEdit::NewBlob(fid)),
+            4 => Some(ManifestEdit::SealBlob(fid)),
+            5 => Some(ManifestEdit::RemoveBlob(fid)),
+            _ => None,
+        }
+    }
+}
+
+/// ManifestState live files of a database, each with whether it is sealed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ManifestState {
+    pub(crate) data_files: BTreeMap<u32, bool>,
+    pub(crate) blob_files: BTreeMap<u32, bool>,
+    /// blob files which are merged, they may be left on disk by a crash
+    pub(crate) removed_blobs: BTreeSet<u32>,
+}
+
+impl ManifestState {
+    /// state of a database created before the manifest, files are found by their
+    /// names in @dir_path, all but the last datafile are sealed
+    pub(crate) fn scan(dir_path: &Path) -> Result<Self> {
+        let data_fids = list_file_ids(dir_path, DATAFILE_NAME_SUFFIX)?;
+        let last_fid = data_fids.last().copied();
+        Ok(ManifestState {
+            data_files: data_fids
+                .into_iter()
+                .map(|fid| (fid, Some(fid) != last_fid))
+                .collect(),
+            blob_files: list_file_ids(dir_path, BLOBFILE_NAME_SUFFIX)?
+                .into_iter()
+                .map(|fid| (fid, true))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn apply(&mut self, edit: ManifestEdit) {
+        match edit {
+            ManifestEdit::NewData(fid) => {
+                self.data_files.insert(fid, false);
+            }
+            ManifestEdit::SealData(fid) => {
+                if let Some(sealed) = self.data_files.get_mut(&fid) {
+                    *sealed = true;
+                }
+            }
+            ManifestEdit::NewBlob(fid) => {
+                self.blob_files.insert(fid, false);
+            }
+            ManifestEdit::SealBlob(fid) => {
+                if let Some(sealed) = self.blob_files.get_mut(&fid) {
+                    *sealed = true;
+                }
+            }
+            ManifestEdit::RemoveBlob(fid) => {
+                self.blob_files.remove(&fid);
+                self.removed_blobs.insert(fid);
+            }
+        }
+    }
+
+    /// edits which rebuild this state from an empty one
+    fn snapshot(&self) -> Vec<ManifestEdit> {
+        let mut edits = Vec::new();
+        for (&fid, &sealed) in &self.data_files {
+            edits.push(ManifestEdit::NewData(fid));
+            if sealed {
+                edits.push(ManifestEdit::SealData(fid));
+            }
+        }
+        for (&fid, &sealed) in &self.blob_files {
+            edits.push(ManifestEdit::NewBlob(fid));
+            if sealed {
+                edits.push(ManifestEdit::SealBlob(fid));
+            }
+        }
+        edits
+    }
+}
+
+/// encode @edits as one record, they are applied all or none on replay
+fn encode_edits(edits: &[ManifestEdit]) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(EDIT_HEADER_SIZE + edits.len() * EDIT_SIZE);
+    buf.put_u32_le((edits.len() * EDIT_SIZE) as u32);
+    buf.put_u32_le(0);
+    edits.iter().for_each(|edit| edit.encode(&mut buf));
+    let crc = manifest_crc(&buf);
+    buf[4..EDIT_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    buf.to_vec()
+}
+
+fn manifest_crc(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..4]);
+    hasher.update(&record[EDIT_HEADER_SIZE..]);
+    hasher.finalize()
+}
+
+/// the record at the start of @buf, `None` if it is torn or corrupted
+fn parse_record(buf: &[u8]) -> Option<&[u8]> {
+    buf.get(..EDIT_HEADER_SIZE)
+        .map(|header| (&header[..4]).get_u32_le() as usize)
+        .filter(|len| len % EDIT_SIZE == 0)
+        .and_then(|len| buf.get(..EDIT_HEADER_SIZE + len))
+        .filter(|record| manifest_crc(record) == (&record[4..EDIT_HEADER_SIZE]).get_u32_le())
+}
+
+/// replay records of a manifest, a torn record at its end is dropped, as its edits
+/// are never acknowledged, but a corrupted record followed by a valid one hides
+/// edits which are, and the first record is a snapshot written atomically,
+/// without either of them live files would be taken as orphans
+fn replay(mut buf: &[u8]) -> Result<ManifestState> {
+    let mut state = ManifestState::default();
+    let mut first = true;
+    loop {
+        let record = match parse_record(buf) {
+            Some(record) => record,
+            None if first => {
+                warn!("snapshot of manifest is corrupted");
+                return Err(Errors::DatabaseFileCorrupted);
+            }
+            // direct io pads file with zeros after the last record
+            None if buf.iter().all(|b| *b == 0) => return Ok(state),
+            None => {
+                // a record is written at the end only, nothing valid follows a torn one
+                if (1..buf.len()).any(|offset| parse_record(&buf[offset..]).is_some()) {
+                    warn!("manifest has a corrupted record before {} bytes", buf.len());
+                    return Err(Errors::DatabaseFileCorrupted);
+                }
+                warn!("manifest ends with a torn record of {} bytes", buf.len());
+                return Ok(state);
+            }
+        };
+        for edit in record[EDIT_HEADER_SIZE..].chunks(EDIT_SIZE) {
+            let edit = ManifestEdit::decode(edit).ok_or_else(|| {
+                warn!("unknown manifest edit {:?}", edit);
+                Errors::DatabaseFileCorrupted
+            })?;
+            state.apply(edit);
+        }
+        buf = &buf[record.len()..];
+        first = false;
+    }
+}
+
+/// Manifest log of live files of a database, `Engine::open` trusts it over the
+/// files found in database directory, every edit is synced before it returns
+pub(crate) struct Manifest {
+    file: Box<dyn IOManager>,
+    /// end of the last complete record, the next record is written here
+    len: u64,
+}
+
+impl Manifest {
+    /// replay MANIFEST of @dir_path, `None` if the database is new
+    /// or it is created before the manifest
+    pub(crate) fn recover(dir_path: &Path) -> Result<Option<ManifestState>> {
+        match fs::read(dir_path.join(MANIFEST_FILE_NAME)) {
+            Ok(buf) => replay(&buf).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                warn!("read manifest failed: {}", e);
+                Err(Errors::FailToReadFromDataFile(e.into()))
+            }
+        }
+    }
+
+    /// replace MANIFEST of @dir_path with a snapshot of @state atomically,
+    /// then open it to log edits after it
+    pub(crate) fn create(dir_path: &Path, state: &ManifestState, io_type: &IOType) -> Result<Self> {
+        let tmp_path = dir_path.join(MANIFEST_TMP_FILE_NAME);
+        let snapshot = encode_edits(&state.snapshot());
+        // a tmp file left by a crash is appended to by an io manager, drop it first
+        match fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("remove manifest tmp file failed: {}", e);
+                return Err(Errors::FailToRemoveDataFile(e.into()));
+            }
+            _ => {}
+        }
+        new_io_manager(tmp_path.clone(), io_type)?.write_and_sync(&snapshot)?;
+        let path = dir_path.join(MANIFEST_FILE_NAME);
+        rename_file(&tmp_path, &path, io_type)?;
+        sync_dir(dir_path, io_type)?;
+
+        let mut file = new_io_manager(path, io_type)?;
+        file.set_write_offset(snapshot.len() as u64)?;
+        Ok(Manifest {
+            file,
+            len: snapshot.len() as u64,
+        })
+    }
+
+    /// append @edits as one record and sync it, a failed record is overwritten
+    /// by the next one, so it never hides edits logged after it
+    pub(crate) fn log(&mut self, edits: &[ManifestEdit]) -> Result<()> {
+        let record = encode_edits(edits);
+        if let Err(e) = self.file.write_and_sync(&record) {
+            warn!("log manifest edits {:?} failed: {}", edits, e);
+            self.file.set_write_offset(self.len)?;
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// remove files in @dir_path which are not live in @state, they are left by
+    /// operations which never reach the manifest, e.g. a crash right after a file
+    /// is created, or by a crash after a file is dead but before it is removed,
+    /// an orphan which holds records is moved to `QUARANTINE_DIR_NAME` instead,
+    /// it is never acknowledged to be dead so its records may be the only copy
+    pub(crate) fn remove_orphan_files(
+        dir_path: &Path,
+        state: &ManifestState,
+        io_type: &IOType,
+    ) -> Result<()> {
+        let dir = dir_path.read_dir().map_err(|e| {
+            warn!("read directory {:?} failed: {}", dir_path, e);
+            Errors::FailToReadDatabaseDirectory
+        })?;
+
+        let mut removed = false;
+        for entry in dir {
+            let entry = entry.map_err(|e| {
+                warn!("read directory {:?} failed: {}", dir_path, e);
+                Errors::FailToReadDatabaseDirectory
+            })?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let (live, dead) = if name.ends_with(DATAFILE_NAME_SUFFIX) {
+                let fid = parse_file_id(name, DATAFILE_NAME_SUFFIX);
+                (
+                    fid.is_some_and(|fid| state.data_files.contains_key(&fid)),
+                    false,
+                )
+            } else if name.ends_with(BLOBFILE_NAME_SUFFIX) {
+                let fid = parse_file_id(name, BLOBFILE_NAME_SUFFIX);
+                (
+                    fid.is_some_and(|fid| state.blob_files.contains_key(&fid)),
+                    fid.is_some_and(|fid| state.removed_blobs.contains(&fid)),
+                )
+            } else {
+                (name != MANIFEST_TMP_FILE_NAME, true)
+            };
+            if live {
+                continue;
+            }
+
+            if !dead && has_records(&entry.path())? {
+                quarantine_file(dir_path, name, io_type)?;
+            } else {
+                info!("remove orphan file {}", name);
+                fs::remove_file(entry.path()).map_err(|e| {
+                    warn!("remove orphan file {} failed: {}", name, e);
+                    Errors::FailToRemoveDataFile(e.into())
+                })?;
+            }
+            removed = true;
+        }
+
+        if removed {
+            sync_dir(dir_path, io_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// whether file of @path starts with a valid header and has any nonzero byte after it,
+/// a file which is created but never written to holds a header and zeros at most
+fn has_records(path: &Path) -> Result<bool> {
+    let read_err = |e: io::Error| {
+        warn!("read orphan file {:?} failed: {}", path, e);
+        Errors::FailToReadFromDataFile(e.into())
+    };
+    let mut file = File::open(path).map_err(read_err)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut n = 0;
+    while n < FILE_HEADER_SIZE {
+        match file.read(&mut buf[n..FILE_HEADER_SIZE]).map_err(read_err)? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    let header_size = match FileHeader::decode(&buf[..n]) {
+        Ok(Some(header)) => FileHeader::size(header.version).min(n),
+        _ => return Ok(false),
+    };
+    if buf[header_size..n].iter().any(|b| *b != 0) {
+        return Ok(true);
+    }
+    loop {
+        match file.read(&mut buf).map_err(read_err)? {
+            0 => return Ok(false),
+            m if buf[..m].iter().any(|b| *b != 0) => return Ok(true),
+            _ => {}
+        }
+    }
+}
+
+/// move orphan file @name of @dir_path to `QUARANTINE_DIR_NAME`, a file already
+/// quarantined under the same name is kept as well
+fn quarantine_file(dir_path: &Path, name: &str, io_type: &IOType) -> Result<()> {
+    let quarantine_dir = dir_path.join(QUARANTINE_DIR_NAME);
+    let move_err = |e: io::Error| {
+        warn!("quarantine orphan file {} failed: {}", name, e);
+        Errors::FailToRemoveDataFile(e.into())
+    };
+    fs::create_dir_all(&quarantine_dir).map_err(move_err)?;
+    let mut target = quarantine_dir.join(name);
+    let mut n = 0;
+    while target.exists() {
+        n += 1;
+        target = quarantine_dir.join(format!("{}.{}", name, n));
+    }
+    warn!(
+        "orphan file {} holds records, move it to {:?}",
+        name, target
+    );
+    fs::rename(dir_path.join(name), &target).map_err(move_err)?;
+    sync_dir(&quarantine_dir, io_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_replay() {
+        let edits = [
+            ManifestEdit::NewData(0),
+            ManifestEdit::NewBlob(0),
+            ManifestEdit::SealData(0),
+            ManifestEdit::NewData(1),
+        ];
+        let mut buf = encode_edits(&edits[..2]);
+        buf.extend(encode_edits(&edits[2..]));
+        buf.extend(encode_edits(&[ManifestEdit::RemoveBlob(0)]));
+
+        let state = replay(&buf).unwrap();
+        assert_eq!(state.data_files, BTreeMap::from([(0, true), (1, false)]));
+        assert!(state.blob_files.is_empty());
+        assert_eq!(state.removed_blobs, BTreeSet::from([0]));
+        // removed files are gone once the snapshot is written
+        let snapshot = replay(&encode_edits(&state.snapshot())).unwrap();
+        assert_eq!(snapshot.data_files, state.data_files);
+        assert!(snapshot.removed_blobs.is_empty());
+
+        // edits of a torn or corrupted record are all dropped
+        let state = replay(&buf[..buf.len() - 1]).unwrap();
+        assert_eq!(state.blob_files, BTreeMap::from([(0, false)]));
+        let mut corrupted = buf.clone();
+        corrupted[buf.len() - 1] ^= 1;
+        assert_eq!(replay(&corrupted), replay(&buf[..buf.len() - 1]));
+
+        // a corrupted record followed by others hides acknowledged edits
+        let second = encode_edits(&edits[..2]).len();
+        let mut corrupted = buf.clone();
+        corrupted[second + EDIT_HEADER_SIZE] ^= 1;
+        assert_eq!(replay(&corrupted), Err(Errors::DatabaseFileCorrupted));
+        let mut corrupted = buf.clone();
+        corrupted[second] ^= 0x80;
+        assert_eq!(replay(&corrupted), Err(Errors::DatabaseFileCorrupted));
+        let mut corrupted = buf.clone();
+        corrupted[buf.len() - 1] ^= 1;
+
+        // a manifest without snapshot can't tell which files are live
+        corrupted[EDIT_HEADER_SIZE + 2] ^= 1;
+        assert_eq!(replay(&corrupted), Err(Errors::DatabaseFileCorrupted));
+        assert_eq!(replay(&[]), Err(Errors::DatabaseFileCorrupted));
+    }
+}
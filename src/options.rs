@@ -1,4 +1,15 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+pub use crate::data::checksum::ChecksumType;
+use crate::data::cipher::Cipher;
+use crate::data::codec::{Codec, MAX_CODEC_ID, NO_CODEC_ID, SNAPPY_CODEC, SNAPPY_CODEC_ID};
+use crate::data::file_header::FILE_HEADER_SIZE;
+use crate::data::log_record::{log_record_max_size, BLOB_POS_SIZE};
+use crate::error::{Errors, Result};
+use crate::scrub::CorruptRange;
+
+#[cfg(test)]
+use crate::fio::fault_io::FaultInjector;
 
 #[derive(Clone)]
 pub struct Options {
@@ -11,8 +22,56 @@ pub struct Options {
     pub sync_in_write: bool,
 
     pub index_type: IndexType,
+
+    /// io implementation used for datafiles
+    pub io_type: IOType,
+
+    /// write buffer size of active datafile, 0 disables buffering,
+    /// it is at most `datafile_size`
+    pub write_buffer_size: usize,
+
+    /// allocate `datafile_size` bytes on disk when a new active datafile is created,
+    /// it is off by default, as every datafile takes its full size at once
+    pub preallocate_datafile: bool,
+
+    /// values of at least this size are written to blob files, datafiles only keep
+    /// pointers to them, 0 disables value separation
+    pub min_blob_size: usize,
+
+    /// codec used to compress values of new records
+    pub compression: Compression,
+
+    /// cipher which encrypts and authenticates records, it holds the encryption key
+    pub encryption: Option<Arc<dyn Cipher>>,
+
+    /// checksum algorithm of records in new files
+    pub checksum: ChecksumType,
+
+    /// verify sealed datafiles in a background thread, `None` disables it
+    pub scrub: Option<ScrubOptions>,
+
+    /// how corrupted records found while loading datafiles are handled
+    pub recovery_mode: RecoveryMode,
+
+    /// max size of keys, writing a larger key fails with `KeyTooLarge`,
+    /// and a record with a larger key is treated as corrupted when read,
+    /// a record of such a key and a pointer to its value must fit in a datafile
+    pub max_key_size: usize,
+
+    /// max size of values, writing a larger value fails with `ValueTooLarge`,
+    /// and a record with a larger value is treated as corrupted when read,
+    /// values written by `put_stream` are split into chunks of at most this size,
+    /// the list of chunks is limited as a value, which limits length of a stream,
+    /// a value which can't fit in a datafile is written to a blob file of its own
+    pub max_value_size: usize,
 }
 
+pub(crate) const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024; // 64KB
+pub(crate) const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024; // 64MB
+
+/// a slower scrubber takes over an hour for a default datafile already
+const MIN_SCRUB_BYTES_PER_SEC: u64 = 64 * 1024; // 64KB/s
+
 impl Default for Options {
     fn default() -> Self {
         Self {
@@ -20,11 +79,265 @@ impl Default for Options {
             datafile_size: 256 * 1024 * 1024, // 256MB
             sync_in_write: false,
             index_type: IndexType::BtreeMap,
+            io_type: IOType::StandardFIO,
+            write_buffer_size: 0,
+            preallocate_datafile: false,
+            min_blob_size: 0,
+            compression: Compression::None,
+            encryption: None,
+            checksum: ChecksumType::Crc32,
+            scrub: None,
+            recovery_mode: RecoveryMode::Strict,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
         }
     }
 }
 
+impl Options {
+    /// builder which starts from default options
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    /// check every field, `Engine::open` rejects options which fail it
+    pub fn validate(&self) -> Result<()> {
+        match self.dir_path.to_str() {
+            Some(path) if !path.is_empty() => {}
+            _ => return Err(Errors::InvalidDatabasePath),
+        }
+
+        // a datafile holds its header and at least one record
+        if self.datafile_size <= FILE_HEADER_SIZE as u64 {
+            return Err(Errors::DatafileSizeTooSmall);
+        }
+
+        if let IndexType::SkipList = self.index_type {
+            return Err(Errors::InvalidOption(
+                "skiplist index is not supported yet".to_string(),
+            ));
+        }
+
+        if let Compression::Custom(codec) = &self.compression {
+            if codec.id() <= SNAPPY_CODEC_ID || codec.id() > MAX_CODEC_ID {
+                return Err(Errors::UnknownCodec(codec.id()));
+            }
+        }
+
+        if let Some(scrub) = &self.scrub {
+            if scrub.interval.is_zero() {
+                return Err(Errors::InvalidOption(
+                    "scrub interval must be greater than zero".to_string(),
+                ));
+            }
+            if scrub.bytes_per_sec != 0 && scrub.bytes_per_sec < MIN_SCRUB_BYTES_PER_SEC {
+                return Err(Errors::InvalidOption(format!(
+                    "scrub rate must be 0 or at least {} bytes per second",
+                    MIN_SCRUB_BYTES_PER_SEC
+                )));
+            }
+        }
+
+        // buffered bytes are flushed before the file is full
+        if self.write_buffer_size as u64 > self.datafile_size {
+            return Err(Errors::InvalidOption(format!(
+                "write buffer size {} exceeds datafile size {}",
+                self.write_buffer_size, self.datafile_size
+            )));
+        }
+
+        // sizes of key and value are u32 in record header
+        if self.max_key_size == 0 || self.max_key_size > u32::MAX as usize {
+            return Err(Errors::InvalidOption(format!(
+                "max key size must be in 1..={}",
+                u32::MAX
+            )));
+        }
+        if self.max_value_size == 0 || self.max_value_size > u32::MAX as usize {
+            return Err(Errors::InvalidOption(format!(
+                "max value size must be in 1..={}",
+                u32::MAX
+            )));
+        }
+        // a large value is moved to a blob file, a datafile keeps its key and pointer
+        let max_record_size = FILE_HEADER_SIZE + log_record_max_size() + BLOB_POS_SIZE;
+        if (self.max_key_size + max_record_size) as u64 > self.datafile_size {
+            return Err(Errors::InvalidOption(format!(
+                "max key size {} can't fit in a datafile of {} bytes",
+                self.max_key_size, self.datafile_size
+            )));
+        }
+
+        // a separated value is larger than its pointer, and it is not too large to write
+        if self.min_blob_size != 0
+            && (self.min_blob_size <= BLOB_POS_SIZE || self.min_blob_size > self.max_value_size)
+        {
+            return Err(Errors::InvalidOption(format!(
+                "min blob size must be 0 or in {}..={}",
+                BLOB_POS_SIZE + 1,
+                self.max_value_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// OptionsBuilder builds `Options` which are validated as a whole by `build`
+#[derive(Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn dir_path(mut self, dir_path: impl Into<PathBuf>) -> Self {
+        self.options.dir_path = dir_path.into();
+        self
+    }
+
+    pub fn datafile_size(mut self, datafile_size: u64) -> Self {
+        self.options.datafile_size = datafile_size;
+        self
+    }
+
+    pub fn sync_in_write(mut self, sync_in_write: bool) -> Self {
+        self.options.sync_in_write = sync_in_write;
+        self
+    }
+
+    pub fn index_type(mut self, index_type: IndexType) -> Self {
+        self.options.index_type = index_type;
+        self
+    }
+
+    pub fn io_type(mut self, io_type: IOType) -> Self {
+        self.options.io_type = io_type;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn preallocate_datafile(mut self, preallocate_datafile: bool) -> Self {
+        self.options.preallocate_datafile = preallocate_datafile;
+        self
+    }
+
+    pub fn min_blob_size(mut self, min_blob_size: usize) -> Self {
+        self.options.min_blob_size = min_blob_size;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn encryption(mut self, cipher: Arc<dyn Cipher>) -> Self {
+        self.options.encryption = Some(cipher);
+        self
+    }
+
+    pub fn checksum(mut self, checksum: ChecksumType) -> Self {
+        self.options.checksum = checksum;
+        self
+    }
+
+    pub fn scrub(mut self, scrub: ScrubOptions) -> Self {
+        self.options.scrub = Some(scrub);
+        self
+    }
+
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.options.recovery_mode = recovery_mode;
+        self
+    }
+
+    pub fn max_key_size(mut self, max_key_size: usize) -> Self {
+        self.options.max_key_size = max_key_size;
+        self
+    }
+
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.options.max_value_size = max_value_size;
+        self
+    }
+
+    pub fn build(self) -> Result<Options> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+#[derive(Clone)]
+pub enum Compression {
+    // values are stored as is
+    None,
+    // built-in snappy codec
+    Snappy,
+    // user defined codec
+    Custom(Arc<dyn Codec>),
+}
+
+impl Compression {
+    /// codec to compress new records
+    pub(crate) fn codec(&self) -> Option<&dyn Codec> {
+        match self {
+            Compression::None => None,
+            Compression::Snappy => Some(&SNAPPY_CODEC),
+            Compression::Custom(codec) => Some(codec.as_ref()),
+        }
+    }
+
+    /// codec to decompress a record written with codec @id,
+    /// built-in codecs are always available
+    pub(crate) fn codec_by_id(&self, id: u8) -> Option<&dyn Codec> {
+        match (id, self) {
+            (NO_CODEC_ID, _) => None,
+            (SNAPPY_CODEC_ID, _) => Some(&SNAPPY_CODEC),
+            (_, Compression::Custom(codec)) if codec.id() == id => Some(codec.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// callback of scrubber, it is called from scrub thread
+pub type CorruptionCallback = Arc<dyn Fn(&CorruptRange) + Send + Sync>;
+
 #[derive(Clone)]
+pub struct ScrubOptions {
+    /// max bytes verified per second, 0 means unlimited
+    pub bytes_per_sec: u64,
+    /// pause between two passes over sealed datafiles
+    pub interval: Duration,
+    /// called with each corrupt range when it is found
+    pub on_corruption: Option<CorruptionCallback>,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: 16 * 1024 * 1024, // 16MB/s
+            interval: Duration::from_secs(3600),
+            on_corruption: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    // fail to open on the first corrupted record
+    #[default]
+    Strict,
+    // skip corrupted records to the next valid one, see `Engine::recovery_report`,
+    // zeroed bytes may hide records behind them, scanning stops at 64KB of zeros,
+    // which is preallocated space after the last record
+    Salvage,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum IndexType {
     // BtreeMap
     BtreeMap,
@@ -32,10 +345,46 @@ pub enum IndexType {
     SkipList,
 }
 
+/// io implementations available depend on platform and features
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum IOType {
+    // standard file io
+    StandardFIO,
+    // O_DIRECT io which bypasses page cache
+    #[cfg(target_os = "linux")]
+    DirectIO,
+    // io_uring based io, reads and writes are submitted in batches
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    IoUring,
+    // file io wrapper which injects failures, only for crash tests
+    #[cfg(test)]
+    FaultInjection(Arc<FaultInjector>),
+}
+
 #[derive(Default, Clone)]
 pub struct IndexIteratorOptions {
     pub prefix: Vec<u8>,
     pub reverse: bool,
+    /// count of values read ahead in one batch, 0 or 1 disables prefetching
+    pub prefetch: usize,
+    /// options of reading values
+    pub read_options: ReadOptions,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// verify checksum of records read, it may be disabled on hot paths
+    /// when integrity is covered by background scrubbing
+    pub verify_checksum: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            verify_checksum: true,
+        }
+    }
 }
 #[derive(Clone)]
 pub struct WriteBatchOptions {
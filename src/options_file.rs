@@ -0,0 +1,250 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use log::{info, warn};
+
+use crate::{
+    data::{checksum::ChecksumType, data_file::DataFile, file_header::FORMAT_VERSION},
+    error::{Errors, Result},
+    fio::io_manager::sync_dir,
+    options::{IOType, IndexType, Options},
+};
+
+/// name of file in database directory which records options it is opened with
+pub(crate) const OPTIONS_FILE_NAME: &str = "OPTIONS";
+const OPTIONS_TMP_FILE_NAME: &str = "OPTIONS.tmp";
+
+/// PersistedOptions options which decide how files of a database are read,
+/// they are written to the OPTIONS file as `name=value` lines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PersistedOptions {
+    pub(crate) format_version: u32,
+    pub(crate) index_type: IndexType,
+    pub(crate) checksum: ChecksumType,
+    pub(crate) datafile_size: u64,
+    pub(crate) max_key_size: usize,
+    pub(crate) max_value_size: usize,
+}
+
+impl PersistedOptions {
+    pub(crate) fn new(options: &Options) -> Self {
+        PersistedOptions {
+            format_version: FORMAT_VERSION,
+            index_type: options.index_type.clone(),
+            checksum: options.checksum,
+            datafile_size: options.datafile_size,
+            max_key_size: options.max_key_size,
+            max_value_size: options.max_value_size,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let index_type = match self.index_type {
+            IndexType::BtreeMap => "btree",
+            IndexType::SkipList => "skiplist",
+        };
+        format!(
+            "# options of bitcask-rs database, do not edit\n\
+             format_version={}\n\
+             index_type={}\n\
+             checksum={}\n\
+             datafile_size={}\n\
+             max_key_size={}\n\
+             max_value_size={}\n",
+            self.format_version,
+            index_type,
+            self.checksum.id(),
+            self.datafile_size,
+            self.max_key_size,
+            self.max_value_size,
+        )
+    }
+
+    fn decode(content: &str) -> Option<Self> {
+        let mut format_version = None;
+        let mut index_type = None;
+        let mut checksum = None;
+        let mut datafile_size = None;
+        let mut max_key_size = None;
+        let mut max_value_size = None;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once('=')?;
+            match name {
+                "format_version" => format_version = value.parse().ok(),
+                "index_type" => {
+                    index_type = match value {
+                        "btree" => Some(IndexType::BtreeMap),
+                        "skiplist" => Some(IndexType::SkipList),
+                        _ => None,
+                    }
+                }
+                "checksum" => checksum = value.parse().ok().and_then(ChecksumType::from_id),
+                "datafile_size" => datafile_size = value.parse().ok(),
+                "max_key_size" => max_key_size = value.parse().ok(),
+                "max_value_size" => max_value_size = value.parse().ok(),
+                // options of newer versions are ignored
+                _ => {}
+            }
+        }
+        Some(PersistedOptions {
+            format_version: format_version?,
+            index_type: index_type?,
+            checksum: checksum?,
+            datafile_size: datafile_size?,
+            max_key_size: max_key_size?,
+            max_value_size: max_value_size?,
+        })
+    }
+}
+
+/// read OPTIONS file of @dir_path, `None` if the database is new
+/// or it is created before options are persisted
+pub(crate) fn read_options_file(dir_path: &Path) -> Result<Option<PersistedOptions>> {
+    let content = match fs::read_to_string(dir_path.join(OPTIONS_FILE_NAME)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            warn!("read options file failed: {}", e);
+            return Err(Errors::FailToReadFromDataFile(e.into()));
+        }
+    };
+    PersistedOptions::decode(&content).map(Some).ok_or_else(|| {
+        warn!("options file is corrupted: {:?}", content);
+        Errors::DatabaseFileCorrupted
+    })
+}
+
+/// check @options against @persisted ones which the database is opened with last time,
+/// returns whether OPTIONS file has to be rewritten
+///
+/// - format version: files of newer versions can't be read, older ones are migrated
+///   as new files are written in current version
+/// - index type: index is rebuilt on open, it can be changed freely
+/// - checksum: checksum algorithm is recorded in header of each file, the active
+///   file is sealed and new records go to a new file
+/// - datafile size: it only applies to new files
+/// - max key and value sizes: they can't be decreased, as existing records larger
+///   than them would be read as corrupted
+pub(crate) fn check_persisted_options(
+    persisted: &PersistedOptions,
+    options: &Options,
+) -> Result<bool> {
+    if persisted.format_version > FORMAT_VERSION {
+        warn!(
+            "database is written in format version {}, newer than {}",
+            persisted.format_version, FORMAT_VERSION
+        );
+        return Err(Errors::UnsupportedFormatVersion(persisted.format_version));
+    }
+    if options.max_key_size < persisted.max_key_size {
+        return Err(Errors::IncompatibleOptions(format!(
+            "max key size {} is less than {} of existing records",
+            options.max_key_size, persisted.max_key_size
+        )));
+    }
+    if options.max_value_size < persisted.max_value_size {
+        return Err(Errors::IncompatibleOptions(format!(
+            "max value size {} is less than {} of existing records",
+            options.max_value_size, persisted.max_value_size
+        )));
+    }
+
+    let current = PersistedOptions::new(options);
+    if current != *persisted {
+        info!("migrate options from {:?} to {:?}", persisted, current);
+    }
+    Ok(current != *persisted)
+}
+
+/// check @options against records of @files of a database without OPTIONS file, which
+/// may be written before size limits exist, max key and value sizes can't be less than
+/// sizes of existing records, as they would be read as corrupted once limits are recorded
+pub(crate) fn check_unrecorded_options<'a>(
+    files: impl IntoIterator<Item = &'a DataFile>,
+    options: &Options,
+) -> Result<()> {
+    for file in files {
+        if let Some((key_size, value_size)) = file.find_oversize_record(options)? {
+            return Err(Errors::IncompatibleOptions(format!(
+                "max key size {} or max value size {} is less than key size {} \
+                 or value size {} of a record in file {}",
+                options.max_key_size,
+                options.max_value_size,
+                key_size,
+                value_size,
+                file.file_id()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// remove OPTIONS.tmp of @dir_path left by an interrupted rewrite of OPTIONS file
+pub(crate) fn remove_options_tmp_file(dir_path: &Path) -> Result<()> {
+    match fs::remove_file(dir_path.join(OPTIONS_TMP_FILE_NAME)) {
+        Ok(()) => {
+            info!("remove options file left by an interrupted rewrite");
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            warn!("remove temporary options file failed: {}", e);
+            Err(Errors::FailToRemoveDataFile(e.into()))
+        }
+    }
+}
+
+/// replace OPTIONS file of @dir_path with @options atomically
+pub(crate) fn write_options_file(
+    dir_path: &Path,
+    options: &PersistedOptions,
+    io_type: &IOType,
+) -> Result<()> {
+    let tmp_path = dir_path.join(OPTIONS_TMP_FILE_NAME);
+    File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(options.encode().as_bytes())?;
+            file.sync_all()
+        })
+        .map_err(|e| {
+            warn!("write options file failed: {}", e);
+            Errors::FailToWriteToDataFile(e.into())
+        })?;
+    fs::rename(&tmp_path, dir_path.join(OPTIONS_FILE_NAME)).map_err(|e| {
+        warn!("rename options file failed: {}", e);
+        Errors::FailToWriteToDataFile(e.into())
+    })?;
+    sync_dir(dir_path, io_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_file_encode_decode() {
+        let options = Options {
+            checksum: ChecksumType::Xxh3,
+            datafile_size: 4096,
+            ..Default::default()
+        };
+        let persisted = PersistedOptions::new(&options);
+        assert_eq!(
+            PersistedOptions::decode(&persisted.encode()),
+            Some(persisted.clone())
+        );
+
+        // unknown options are skipped, missing ones fail
+        let content = persisted.encode() + "unknown=1\n";
+        assert_eq!(PersistedOptions::decode(&content), Some(persisted));
+        assert_eq!(PersistedOptions::decode("format_version=3\n"), None);
+        assert_eq!(PersistedOptions::decode("garbage"), None);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fs, io, path::Path};
+
+use log::warn;
+
+use crate::{
+    data::{
+        data_file::{DataFile, RecordCheck},
+        log_record::ReadLogRecord,
+    },
+    db::Engine,
+    error::{Errors, Result},
+    fio::io_manager::{new_io_manager, sync_dir},
+    options::IOType,
+};
+
+/// name of file written by a successful close, it is removed on open,
+/// so a database without it is not shut down cleanly
+pub(crate) const CLEAN_SHUTDOWN_FILE_NAME: &str = "CLEAN_SHUTDOWN";
+
+/// SkippedRange bytes of a datafile skipped by salvage recovery
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SkippedRange {
+    pub file_id: u32,
+    pub start: u64,
+    pub end: u64,
+    /// count of corrupted records found in range
+    pub records: usize,
+}
+
+/// RecoveryReport corruption skipped while opening with `RecoveryMode::Salvage`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub skipped: Vec<SkippedRange>,
+    /// torn write at the end of the last datafile, which is erased on open
+    /// after an unclean shutdown
+    pub torn_tail: Option<SkippedRange>,
+}
+
+impl RecoveryReport {
+    /// total count of skipped records
+    pub fn skipped_records(&self) -> usize {
+        self.skipped.iter().map(|range| range.records).sum()
+    }
+
+    /// no corruption is found, a torn tail is not corruption but an interrupted write
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty()
+    }
+
+    /// add a skipped record, it is merged into previous range when they are adjacent
+    fn skip(&mut self, file_id: u32, start: u64, end: u64) {
+        match self.skipped.last_mut() {
+            Some(last) if last.file_id == file_id && last.end == start => {
+                last.end = end;
+                last.records += 1;
+            }
+            _ => self.skipped.push(SkippedRange {
+                file_id,
+                start,
+                end,
+                records: 1,
+            }),
+        }
+    }
+}
+
+impl Engine {
+    /// corruption skipped when engine is opened, it is always clean in strict mode
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// whether the database is closed successfully before it is opened, otherwise
+    /// the end of the last datafile is verified and a torn write is erased
+    pub fn last_shutdown_clean(&self) -> bool {
+        self.clean_shutdown
+    }
+}
+
+/// remove marker of clean shutdown in @dir_path, returns whether it exists,
+/// removal is durable before any write, so a crash is never taken as clean
+pub(crate) fn take_clean_shutdown_marker(dir_path: &Path, io_type: &IOType) -> Result<bool> {
+    match fs::remove_file(dir_path.join(CLEAN_SHUTDOWN_FILE_NAME)) {
+        Ok(()) => {
+            sync_dir(dir_path, io_type)?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => {
+            warn!("remove clean shutdown marker failed: {}", e);
+            Err(Errors::FailToRemoveDataFile(e.into()))
+        }
+    }
+}
+
+/// write marker of clean shutdown in @dir_path, all files must be synced before
+pub(crate) fn put_clean_shutdown_marker(dir_path: &Path, io_type: &IOType) -> Result<()> {
+    new_io_manager(dir_path.join(CLEAN_SHUTDOWN_FILE_NAME), io_type)?.sync()?;
+    sync_dir(dir_path, io_type)
+}
+
+/// read the first valid record from @offset of @data_file, corrupted records are
+/// skipped to the next valid record boundary and added to @report, offset and
+/// content of record read are returned, `None` at end of file
+pub(crate) fn salvage_log_record(
+    data_file: &DataFile,
+    mut offset: u64,
+    report: &mut RecoveryReport,
+) -> Result<Option<(u64, ReadLogRecord)>> {
+    let file_size = data_file.size()?;
+    loop {
+        // verify before reading, a broken header may claim a huge record
+        let eof = match data_file.verify_record(offset, file_size) {
+            Ok(RecordCheck::Valid(_)) => match data_file.read_log_record(offset) {
+                Ok(res) => return Ok(Some((offset, res))),
+                // key envelope of v1 record is broken
+                Err(Errors::DecodingError) => false,
+                Err(e) => return Err(e),
+            },
+            Ok(RecordCheck::Corrupted(_)) => false,
+            // zeroed bytes in the middle of file look like end of records
+            Err(Errors::ReadEOF) => true,
+            Err(e) => return Err(e),
+        };
+
+        let next = data_file.find_next_record(offset + 1)?;
+        if eof && next.is_none() {
+            return Ok(None);
+        }
+        let end = match next {
+            Some(next) => next,
+            None => file_size,
+        };
+        warn!(
+            "skip corrupted record of datafile {}, range: {}..{}",
+            data_file.file_id(),
+            offset,
+            end
+        );
+        report.skip(data_file.file_id(), offset, end);
+        match next {
+            Some(next) => offset = next,
+            None => return Ok(None),
+        }
+    }
+}
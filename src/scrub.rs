@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use parking_lot::{Condvar, Mutex, RwLock};
+
+use crate::{
+    data::{data_file::RecordCheck, log_record::LogRecordPos},
+    db::{Engine, SealedFiles},
+    error::{Errors, Result},
+    options::ScrubOptions,
+};
+
+/// CorruptRange bytes of a sealed datafile whose records fail checksum verification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorruptRange {
+    pub file_id: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// ScrubStats progress of scrubbing since the engine is opened
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// finished passes over all sealed datafiles
+    pub passes: u64,
+    pub files_scrubbed: u64,
+    pub bytes_scrubbed: u64,
+    /// corrupt ranges found, each is counted once
+    pub corrupt_ranges: u64,
+}
+
+/// state shared by engine and scrub thread
+#[derive(Default)]
+pub(crate) struct ScrubState {
+    stats: Mutex<ScrubStats>,
+    /// corrupt ranges keyed by (file id, start), valued by end
+    corrupt: RwLock<BTreeMap<(u32, u64), u64>>,
+    stopped: Mutex<bool>,
+    stop_cond: Condvar,
+}
+
+impl ScrubState {
+    /// record @range, returns false when it is already known
+    fn mark(&self, range: CorruptRange) -> bool {
+        let mut corrupt = self.corrupt.write();
+        if corrupt.contains_key(&(range.file_id, range.start)) {
+            return false;
+        }
+        corrupt.insert((range.file_id, range.start), range.end);
+        self.stats.lock().corrupt_ranges += 1;
+        true
+    }
+
+    /// fails with `CorruptedRecord` when record at @pos is in a corrupt range
+    pub(crate) fn check(&self, pos: &LogRecordPos) -> Result<()> {
+        let corrupt = self.corrupt.read();
+        match corrupt.range(..=(pos.file_id, pos.offset)).next_back() {
+            Some((&(file_id, _), &end)) if file_id == pos.file_id && pos.offset < end => {
+                Err(Errors::CorruptedRecord {
+                    file_id,
+                    offset: pos.offset,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock()
+    }
+
+    /// wait for @timeout unless scrubbing is stopped, returns whether it is stopped
+    fn wait_stop(&self, timeout: Duration) -> bool {
+        let mut stopped = self.stopped.lock();
+        if !*stopped {
+            self.stop_cond.wait_for(&mut stopped, timeout);
+        }
+        *stopped
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.stop_cond.notify_all();
+    }
+}
+
+/// start a thread which scrubs sealed datafiles of @old_files every `interval`
+pub(crate) fn spawn_scrubber(
+    old_files: Arc<ArcSwap<SealedFiles>>,
+    state: Arc<ScrubState>,
+    options: ScrubOptions,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        info!("scrubber started");
+        loop {
+            if let Err(e) = scrub_files(&old_files, &state, &options, true) {
+                error!("scrub datafiles failed: {:?}", e);
+            }
+            if state.wait_stop(options.interval) {
+                break;
+            }
+        }
+        info!("scrubber stopped");
+    })
+}
+
+/// verify every record of sealed datafiles in @old_files, returns corrupt ranges
+/// newly found, reading is throttled to `bytes_per_sec` when @throttle is set
+fn scrub_files(
+    old_files: &ArcSwap<SealedFiles>,
+    state: &ScrubState,
+    options: &ScrubOptions,
+    throttle: bool,
+) -> Result<Vec<CorruptRange>> {
+    // sealed files never change, a snapshot of them is scrubbed
+    let old_files = old_files.load_full();
+    let mut fids = old_files.keys().copied().collect::<Vec<_>>();
+    fids.sort();
+
+    let start = Instant::now();
+    let mut scanned = 0;
+    let mut found = Vec::new();
+    for fid in fids {
+        let data_file = &old_files[&fid];
+        let mut offset = data_file.records_offset();
+        let file_size = data_file.size()?;
+        loop {
+            if state.is_stopped() {
+                return Ok(found);
+            }
+
+            let check = match data_file.verify_record(offset, file_size) {
+                Err(Errors::ReadEOF) => break,
+                res => res?,
+            };
+            let size = match check {
+                RecordCheck::Valid(size) => size,
+                RecordCheck::Corrupted(size) => {
+                    // a broken header takes the rest of file, records after it are
+                    // found by scanning, so only the gap before them is corrupt
+                    let end = data_file
+                        .find_next_record(offset + 1)?
+                        .map_or(offset + size, |next| next.min(offset + size));
+                    let range = CorruptRange {
+                        file_id: fid,
+                        start: offset,
+                        end,
+                    };
+                    if state.mark(range) {
+                        warn!("scrubber found corrupt range {:?}", range);
+                        if let Some(on_corruption) = &options.on_corruption {
+                            on_corruption(&range);
+                        }
+                        found.push(range);
+                    }
+                    end - offset
+                }
+            };
+            offset += size;
+            scanned += size;
+            state.stats.lock().bytes_scrubbed += size;
+
+            if throttle && options.bytes_per_sec > 0 {
+                let expected =
+                    Duration::from_secs_f64(scanned as f64 / options.bytes_per_sec as f64);
+                let elapsed = start.elapsed();
+                if expected > elapsed && state.wait_stop(expected - elapsed) {
+                    return Ok(found);
+                }
+            }
+        }
+        state.stats.lock().files_scrubbed += 1;
+    }
+    state.stats.lock().passes += 1;
+
+    Ok(found)
+}
+
+impl Engine {
+    /// verify all sealed datafiles now without throttling, returns corrupt ranges
+    /// which are not found before, records in them fail to read with `CorruptedRecord`
+    pub fn scrub(&self) -> Result<Vec<CorruptRange>> {
+        self.check_open()?;
+        let options = self.options.scrub.clone().unwrap_or_default();
+        scrub_files(&self.old_files, &self.scrub_state, &options, false)
+    }
+
+    pub fn scrub_stats(&self) -> ScrubStats {
+        self.scrub_state.stats.lock().clone()
+    }
+
+    /// all corrupt ranges found by scrubbing
+    pub fn corrupt_ranges(&self) -> Vec<CorruptRange> {
+        self.scrub_state
+            .corrupt
+            .read()
+            .iter()
+            .map(|(&(file_id, start), &end)| CorruptRange {
+                file_id,
+                start,
+                end,
+            })
+            .collect()
+    }
+
+    /// stop scrub thread and wait for it to exit
+    pub(crate) fn stop_scrubber(&self) {
+        self.scrub_state.stop();
+        if let Some(handle) = self.scrubber.lock().take() {
+            if handle.join().is_err() {
+                error!("scrub thread panicked");
+            }
+        }
+    }
+}
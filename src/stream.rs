@@ -0,0 +1,212 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use bytes::Bytes;
+use log::error;
+
+use crate::{
+    blob::BlobPin,
+    data::log_record::{
+        decode_chunks, encode_chunks, BlobChunk, LogRecord, LogRecordType, BLOB_CHUNK_SIZE,
+    },
+    db::{Engine, NON_BATCH_COMMIT_ID, NON_BATCH_ID},
+    error::{Errors, Result},
+    options::ReadOptions,
+};
+
+/// max size of each chunk of a streamed value
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+impl Engine {
+    /// store a value of @len bytes read from @reader without holding it in memory,
+    /// the value is split into chunks written to blob files, and becomes visible
+    /// atomically once a record listing all chunks is appended
+    pub fn put_stream<R: Read>(&self, key: Bytes, mut reader: R, len: u64) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::EmptyKey);
+        }
+        // value is split into chunks, each of them is limited as a value, and so
+        // is the list of chunks, which limits length of value
+        self.check_size(&key, &[])?;
+        let chunk_size = STREAM_CHUNK_SIZE.min(self.options.max_value_size);
+        let max_len = (self.options.max_value_size / BLOB_CHUNK_SIZE * chunk_size) as u64;
+        if len > max_len {
+            return Err(Errors::ValueTooLarge {
+                size: len as usize,
+                max: max_len as usize,
+            });
+        }
+
+        let mut chunks = Vec::new();
+        let mut buf = vec![0u8; len.min(chunk_size as u64) as usize];
+        let mut remain = len;
+        while remain > 0 {
+            let size = remain.min(chunk_size as u64) as usize;
+            reader.read_exact(&mut buf[..size]).map_err(|e| {
+                error!("read value stream failed: {:?}", e);
+                Errors::FailToReadStream(e.to_string())
+            })?;
+            let pos = self.append_blob(&key, &buf[..size])?;
+            chunks.push(BlobChunk {
+                pos,
+                size: size as u64,
+            });
+            remain -= size as u64;
+        }
+
+        let record = LogRecord {
+            key: key.to_vec(),
+            value: encode_chunks(&chunks),
+            record_type: LogRecordType::ChunkList,
+            batch_id: NON_BATCH_ID,
+            seq_id: NON_BATCH_COMMIT_ID,
+        };
+        let _key_locks = self.lock_keys([key.as_ref()]);
+        let _index_lock = self.index_lock.read();
+        let record_pos = self.append_log_record(&record)?;
+
+        match self.indexer.put(key.to_vec(), record_pos) {
+            true => Ok(()),
+            false => Err(Errors::FailToUpdateIndex),
+        }
+    }
+
+    /// get a reader of value of @key, a value written by `put_stream`
+    /// is loaded chunk by chunk while reading
+    pub fn get_reader(&self, key: Bytes) -> Result<ValueReader<'_>> {
+        if key.is_empty() {
+            return Err(Errors::EmptyKey);
+        }
+
+        let blob_pin = BlobPin::new(self);
+        let pos = self.indexer.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
+        let record = self.read_record_at(&pos)?;
+        let source = match record.record_type {
+            LogRecordType::ChunkList => {
+                let mut start = 0;
+                let chunks = decode_chunks(&record.value)
+                    .ok_or(Errors::DatabaseFileCorrupted)?
+                    .into_iter()
+                    .map(|chunk| {
+                        start += chunk.size;
+                        (start - chunk.size, chunk)
+                    })
+                    .collect();
+                ValueSource::Chunked(chunks)
+            }
+            _ => ValueSource::Inline(
+                self.resolve_value(record, &ReadOptions::default())?
+                    .ok_or(Errors::KeyNotFound)?,
+            ),
+        };
+
+        Ok(ValueReader {
+            engine: self,
+            len: source.len(),
+            source,
+            pos: 0,
+            loaded: None,
+            _blob_pin: blob_pin,
+        })
+    }
+}
+
+enum ValueSource {
+    /// value which is read as a whole
+    Inline(Bytes),
+    /// chunks of a streamed value with their start offset in value
+    Chunked(Vec<(u64, BlobChunk)>),
+}
+
+impl ValueSource {
+    fn len(&self) -> u64 {
+        match self {
+            ValueSource::Inline(value) => value.len() as u64,
+            ValueSource::Chunked(chunks) => {
+                chunks.last().map_or(0, |(start, chunk)| start + chunk.size)
+            }
+        }
+    }
+}
+
+/// ValueReader a `Read + Seek` view of a value, at most one chunk of it is in memory,
+/// chunks moved by a blob merge stay readable until the reader is dropped
+pub struct ValueReader<'a> {
+    engine: &'a Engine,
+    source: ValueSource,
+    len: u64,                       // total size of value
+    pos: u64,                       // current read position
+    loaded: Option<(usize, Bytes)>, // index and content of the loaded chunk
+    _blob_pin: BlobPin<'a>,         // chunks are kept on disk while the reader lives
+}
+
+impl ValueReader<'_> {
+    /// total size of value
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// content of the chunk which contains current position, with its start offset
+    fn current_chunk(&mut self) -> io::Result<(u64, &Bytes)> {
+        let chunks = match &self.source {
+            ValueSource::Inline(value) => return Ok((0, value)),
+            ValueSource::Chunked(chunks) => chunks,
+        };
+
+        let idx = chunks.partition_point(|(start, _)| *start <= self.pos) - 1;
+        if self.loaded.as_ref().map(|(i, _)| *i) != Some(idx) {
+            let value = self
+                .engine
+                .read_blob(&chunks[idx].1.pos, &ReadOptions::default())
+                .map_err(io::Error::other)?;
+            self.loaded = Some((idx, value));
+        }
+        let loaded = self.loaded.as_ref().map(|(_, value)| value).unwrap();
+        Ok((chunks[idx].0, loaded))
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        let pos = self.pos;
+        let (start, chunk) = self.current_chunk()?;
+        let skip = (pos - start) as usize;
+        if skip >= chunk.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk is shorter than its recorded size",
+            ));
+        }
+        let n_bytes = (chunk.len() - skip).min(buf.len());
+        buf[..n_bytes].copy_from_slice(&chunk[skip..skip + n_bytes]);
+        self.pos += n_bytes as u64;
+        Ok(n_bytes)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match target {
+            Some(target) => {
+                self.pos = target;
+                Ok(target)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}